@@ -0,0 +1,267 @@
+/*
+Headless batch runs of the galaxy simulation.
+
+`--headless` runs the simulation without opening a window, so long runs can go
+on a server with no display or GPU. It builds a `ParticleSystem` (from a saved
+scene with `--scene path`, or a numbered preset with `--preset N`), advances it
+a fixed number of steps with a fixed timestep, and writes to an output
+directory as it goes:
+
+* a scene snapshot every `--every` steps (see scene.rs), which can be loaded
+  into the viewer with `--scene` to look around;
+* a diagnostics log, `diagnostics.csv`, with a row per snapshot;
+* with `--png`, an image of the system per snapshot, rasterized on the CPU.
+
+The options are:
+
+    --headless                 run without a window
+    --scene PATH | --preset N  what to simulate (default: preset 1)
+    --seed S, --units U        as for the viewer
+    --potential P              add an external potential, as for the viewer
+    --steps N                  how many steps to take (default 1000)
+    --dt X                     the timestep, in the scene's time unit
+    --timestep T               how to divide it, as for the viewer
+    --boundary B               the edge of space, as for the viewer
+    --solver S                 the force solver, as for the viewer
+    --every K                  snapshot every K steps (default 100)
+    --out DIR                  where to write (default galaxy-out)
+    --snapshot-format F        `bin` (default) or `ron`
+    --png, --png-size PX       write square images PX pixels across (1024)
+    --color-by Q, --colormap C, --color-range LOW,HIGH
+                               color the images as in the viewer
+    --yaw D, --pitch D         turn the view by D degrees, as in the viewer
+    --perspective              draw the images in perspective
+
+The images use a fixed view, fitted to the system when the run starts, so a
+sequence of them can be stitched into a movie.
+*/
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+use nannou::image::{Rgb as Pixel, RgbImage};
+use nannou::prelude::Rgb;
+
+use crate::diagnostics::Telemetry;
+use crate::viewport::Viewport;
+use crate::{
+    arg_value, boundary_from_args, coloring_from_args, fit_to_size, numbered_scene,
+    potentials_from_args, scene, solver_from_args, timestep_from_args, units_from_args,
+    view_from_args, ParticleSystem,
+};
+
+/// Everything a batch run needs to know, read from the command line.
+struct BatchOptions {
+    steps: u64,
+    dt: Option<f32>,
+    every: u64,
+    out: PathBuf,
+    snapshot_extension: &'static str,
+    png_size: Option<u32>,
+}
+
+impl BatchOptions {
+    fn from_args() -> Self {
+        BatchOptions {
+            steps: number_from_args("--steps").unwrap_or(1000),
+            dt: number_from_args("--dt"),
+            every: number_from_args("--every").unwrap_or(100).max(1),
+            out: PathBuf::from(arg_value("--out").unwrap_or_else(|| "galaxy-out".into())),
+            snapshot_extension: match arg_value("--snapshot-format").as_deref() {
+                Some("ron") => "ron",
+                _ => "bin",
+            },
+            png_size: if std::env::args().any(|a| a == "--png") {
+                Some(number_from_args("--png-size").unwrap_or(1024).max(1))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+/// Read a numeric `--name N` command line option, if given. A value that
+/// doesn't parse as a `T`, or doesn't fit in one, is ignored with a warning.
+fn number_from_args<T>(name: &str) -> Option<T>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match arg_value(name).map(|s| s.parse::<T>()) {
+        Some(Ok(value)) => Some(value),
+        Some(Err(e)) => {
+            eprintln!("ignoring {}: {}", name, e);
+            None
+        }
+        None => None,
+    }
+}
+
+/// Build the system to simulate from the command line.
+fn system_from_args() -> Result<ParticleSystem, String> {
+    let mut system = match arg_value("--scene") {
+        Some(path) => scene::load(Path::new(&path)).map_err(|e| format!("{}: {}", path, e))?,
+        None => {
+            let number = arg_value("--preset")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(1);
+            let seed = arg_value("--seed")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(1);
+            numbered_scene(number, seed)
+                .ok_or_else(|| format!("there is no preset {}", number))?
+                .0
+        }
+    };
+    if let Some(units) = units_from_args() {
+        system.set_units(units);
+    }
+    system.potentials.extend(potentials_from_args());
+    if let Some(timestep) = timestep_from_args() {
+        system.timestep = timestep;
+    }
+    if let Some(boundary) = boundary_from_args() {
+        system.boundary = boundary;
+    }
+    if let Some(solver) = solver_from_args() {
+        system.solver = solver;
+    }
+    Ok(system)
+}
+
+/// Run the simulation without a window, as described at the top of this file.
+pub fn run() {
+    let options = BatchOptions::from_args();
+    let mut system = match system_from_args() {
+        Ok(system) => system,
+        Err(e) => {
+            eprintln!("couldn't build the scene: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(dt) = options.dt {
+        system.dt = dt;
+    }
+    if let Err(e) = fs::create_dir_all(&options.out) {
+        eprintln!("couldn't create {}: {}", options.out.display(), e);
+        process::exit(1);
+    }
+    let mut log = match Telemetry::create(options.out.join("diagnostics.csv")) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("couldn't create the diagnostics log: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // Fix the view for the images now, so the whole sequence lines up.
+    let viewport = options.png_size.map(|size| {
+        let mut viewport = Viewport::new(fit_to_size(&system, size as f32, size as f32));
+        viewport.center = system.diagnostics().center_of_mass;
+        view_from_args(&mut viewport);
+        viewport
+    });
+
+    println!(
+        "running {} steps of {} {} with {} particles ({}), writing to {}",
+        options.steps,
+        system.dt,
+        system.units.time.symbol(),
+        system.particles.as_slice().len(),
+        system.solver,
+        options.out.display()
+    );
+    let mut coloring = coloring_from_args();
+    let initial_energy = system.diagnostics().energy();
+    let mut escaped = 0;
+    for step in 0..=options.steps {
+        if step > 0 {
+            escaped += system.update().escapes.len();
+        }
+        if step % options.every != 0 && step != options.steps {
+            continue;
+        }
+
+        let diagnostics = system.diagnostics();
+        if let Err(e) = log.record(&diagnostics).and_then(|_| log.flush()) {
+            eprintln!("couldn't write the diagnostics log: {}", e);
+        }
+        let name = format!("snapshot-{:08}", step);
+        let path = options
+            .out
+            .join(&name)
+            .with_extension(options.snapshot_extension);
+        if let Err(e) = scene::save(&system, &path) {
+            eprintln!("couldn't save {}: {}", path.display(), e);
+        }
+        if let (Some(size), Some(viewport)) = (options.png_size, viewport) {
+            let path = options.out.join(&name).with_extension("png");
+            coloring.update(&system);
+            if let Err(e) = rasterize(&system, &viewport, size, coloring.colors()).save(&path) {
+                eprintln!("couldn't save {}: {}", path.display(), e);
+            }
+        }
+        println!(
+            "step {}/{}: t = {:.4} {}, {} particles ({} escaped), energy drift {:+.3e}",
+            step,
+            options.steps,
+            system.time,
+            system.units.time.symbol(),
+            diagnostics.particle_count,
+            escaped,
+            (diagnostics.energy() - initial_energy) / initial_energy.abs().max(f32::MIN_POSITIVE)
+        );
+    }
+}
+
+/// Draw the particles into an image, without a GPU.
+///
+/// Each particle is a filled disc in its color, shaded by its depth and added
+/// on top of whatever is already there, so dense regions glow. The order they
+/// are drawn in doesn't matter.
+///
+/// Arguments:
+///
+/// * `system` - the system to draw
+/// * `viewport` - the mapping from the simulation to the image, with the
+///   middle of the image as the origin
+/// * `size` - the width and height of the image, in pixels
+/// * `colors` - a color for each particle, in the order the store keeps them;
+///   particles without one use their own
+fn rasterize(system: &ParticleSystem, viewport: &Viewport, size: u32, colors: &[Rgb]) -> RgbImage {
+    let mut image = RgbImage::new(size, size);
+    let half = size as f32 / 2.0;
+    for (i, p) in system.particles.iter().enumerate() {
+        let at = match viewport.project(p.position) {
+            Some(at) => at,
+            None => continue,
+        };
+        let color = at.shade(colors.get(i).cloned().unwrap_or(p.color));
+        // Window coordinates have y up; image rows go down.
+        let (cx, cy) = (half + at.position.x, half - at.position.y);
+        let radius = at.radius(p.radius);
+        let color = [color.red, color.green, color.blue];
+
+        let x0 = (cx - radius).floor().max(0.0) as u32;
+        let y0 = (cy - radius).floor().max(0.0) as u32;
+        let x1 = ((cx + radius).ceil().max(0.0) as u32).min(size);
+        let y1 = ((cy + radius).ceil().max(0.0) as u32).min(size);
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let (dx, dy) = (x as f32 + 0.5 - cx, y as f32 + 0.5 - cy);
+                if dx * dx + dy * dy > radius * radius {
+                    continue;
+                }
+                let Pixel(pixel) = image.get_pixel_mut(x, y);
+                for (channel, value) in pixel.iter_mut().zip(color) {
+                    let added = *channel as f32 + 255.0 * 0.6 * value;
+                    *channel = added.min(255.0) as u8;
+                }
+            }
+        }
+    }
+    image
+}
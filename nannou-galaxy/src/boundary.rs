@@ -0,0 +1,395 @@
+/*
+Boundary conditions for the galaxy simulation.
+
+By default space goes on forever, and a body flung out of the system is
+simulated forever too, pulling and being pulled from ever farther away. A
+`Boundary` can close the simulation inside a cube of side `size` centered on
+the origin instead:
+
+* `Unbounded`: no walls, as before.
+* `Periodic`: space wraps around, so a particle leaving through one face comes
+  back through the opposite one. Gravity follows suit with the minimum-image
+  convention: each particle feels only the nearest copy of every other body
+  (or, in the Barnes-Hut tree, of every cell). That leaves out the pull of the
+  farther copies, which would take Ewald sums to add up, but keeps the forces
+  continuous as bodies cross the faces.
+* `Reflective`: walls that bounce particles back in, keeping `restitution` of
+  the speed they hit with (1 is perfectly elastic).
+* `Absorbing`: particles that cross a face escape. Each escape is reported,
+  and the particle is taken out of the simulation, so it stops costing force
+  evaluations: either culled for good, or parked, frozen where it left and
+  drawn in gray, so you can see where things got out.
+
+The walls are applied at the end of each update, so within one a particle may
+stray a little past them.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+
+use nannou::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::store::{ParticleId, ParticleStore};
+use crate::viewport::Viewport;
+use crate::Particle;
+
+/// What happens to particles that leave through an absorbing boundary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Escapes {
+    /// They're gone.
+    #[default]
+    Cull,
+    /// They're kept where they left, but no longer simulated.
+    Park,
+}
+
+/// The edge of the simulated region.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Boundary {
+    /// No edge at all.
+    #[default]
+    Unbounded,
+    /// A cube that space wraps around.
+    Periodic { size: f32 },
+    /// A cube with walls that bounce particles back in.
+    Reflective { size: f32, restitution: f32 },
+    /// A cube that particles escape from.
+    Absorbing { size: f32, escapes: Escapes },
+}
+
+/// What the boundary did to the particles at the end of an update.
+#[derive(Clone, Default)]
+pub struct Crossings {
+    /// The particles that wrapped around a periodic box.
+    pub wrapped: Vec<ParticleId>,
+    /// The particles that escaped, as they were when they crossed.
+    pub escaped: Vec<Particle>,
+}
+
+impl Boundary {
+    /// The side of the cube, if there is one.
+    pub fn size(&self) -> Option<f32> {
+        match *self {
+            Boundary::Unbounded => None,
+            Boundary::Periodic { size }
+            | Boundary::Reflective { size, .. }
+            | Boundary::Absorbing { size, .. } => Some(size),
+        }
+    }
+
+    /// The period that space repeats with, if it wraps around.
+    pub fn period(&self) -> Option<f32> {
+        match *self {
+            Boundary::Periodic { size } => Some(size),
+            _ => None,
+        }
+    }
+
+    /// The boundary after this one, for cycling through them with a key.
+    ///
+    /// Arguments:
+    ///
+    /// * `size` - the side of the cube to use when going from unbounded
+    pub fn next(&self, size: f32) -> Self {
+        let size = self.size().unwrap_or(size);
+        match self {
+            Boundary::Unbounded => Boundary::Periodic { size },
+            Boundary::Periodic { .. } => Boundary::Reflective {
+                size,
+                restitution: 1.0,
+            },
+            Boundary::Reflective { .. } => Boundary::Absorbing {
+                size,
+                escapes: Escapes::default(),
+            },
+            Boundary::Absorbing { .. } => Boundary::Unbounded,
+        }
+    }
+
+    /// Convert to different units, scaling the cube's side by `length`.
+    pub fn convert(&mut self, length: f32) {
+        match self {
+            Boundary::Unbounded => {}
+            Boundary::Periodic { size }
+            | Boundary::Reflective { size, .. }
+            | Boundary::Absorbing { size, .. } => *size *= length,
+        }
+    }
+
+    /// Wrap, bounce or remove every particle outside the cube.
+    ///
+    /// Arguments:
+    ///
+    /// * `particles` - the particles to keep in bounds
+    ///
+    /// Returns:
+    ///
+    /// * `Crossings` - which particles wrapped around or escaped
+    pub fn apply(&self, particles: &mut ParticleStore) -> Crossings {
+        let mut crossings = Crossings::default();
+        match *self {
+            Boundary::Unbounded => {}
+            Boundary::Periodic { size } => {
+                for p in particles.iter_mut() {
+                    let wrapped = wrap_position(p.position, size);
+                    if wrapped != p.position {
+                        p.position = wrapped;
+                        crossings.wrapped.push(p.id);
+                    }
+                }
+            }
+            Boundary::Reflective { size, restitution } => {
+                let half = 0.5 * size;
+                for p in particles.iter_mut() {
+                    reflect(&mut p.position.x, &mut p.velocity.x, half, restitution);
+                    reflect(&mut p.position.y, &mut p.velocity.y, half, restitution);
+                    reflect(&mut p.position.z, &mut p.velocity.z, half, restitution);
+                }
+            }
+            Boundary::Absorbing { size, .. } => {
+                let half = 0.5 * size;
+                crossings.escaped = particles.remove_where(|p| {
+                    p.position.x.abs() <= half
+                        && p.position.y.abs() <= half
+                        && p.position.z.abs() <= half
+                });
+            }
+        }
+        crossings
+    }
+
+    /// Whether the particles that escape are parked rather than culled.
+    pub fn parks(&self) -> bool {
+        matches!(
+            self,
+            Boundary::Absorbing {
+                escapes: Escapes::Park,
+                ..
+            }
+        )
+    }
+
+    /// Draw the edges of the cube, if there is one.
+    pub fn draw(&self, draw: &Draw, viewport: &Viewport) {
+        let half = match self.size() {
+            Some(size) => 0.5 * size,
+            None => return,
+        };
+        let corner = |i: usize| {
+            let sign = |bit: usize| if i & bit != 0 { half } else { -half };
+            vec3(sign(1), sign(2), sign(4))
+        };
+        let color = rgba(0.5, 0.7, 1.0, 0.5);
+        // Each edge joins two corners that differ in one coordinate.
+        for i in 0..8 {
+            for bit in [1, 2, 4] {
+                if i & bit != 0 {
+                    continue;
+                }
+                let ends = (
+                    viewport.to_screen(corner(i)),
+                    viewport.to_screen(corner(i | bit)),
+                );
+                if let (Some(start), Some(end)) = ends {
+                    draw.line().start(start).end(end).weight(1.0).color(color);
+                }
+            }
+        }
+    }
+}
+
+/// Write a boundary in the form `FromStr` reads.
+impl fmt::Display for Boundary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Boundary::Unbounded => write!(f, "unbounded"),
+            Boundary::Periodic { size } => write!(f, "periodic:size={}", size),
+            Boundary::Reflective { size, restitution } => {
+                write!(f, "reflective:size={},restitution={}", size, restitution)
+            }
+            Boundary::Absorbing { size, escapes } => write!(
+                f,
+                "absorbing:size={},park={}",
+                size,
+                (*escapes == Escapes::Park) as u8
+            ),
+        }
+    }
+}
+
+/// Read a boundary written as `unbounded` or `kind:name=value,...`, e.g.
+/// `periodic:size=20000`, `reflective:size=10,restitution=0.8` or
+/// `absorbing:size=50000,park=1`. Every bounded kind needs a size.
+impl FromStr for Boundary {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (kind, parameters) = match s.split_once(':') {
+            Some((kind, parameters)) => (kind, parameters),
+            None => (s, ""),
+        };
+        let (mut size, mut restitution, mut park) = (None, 1.0, false);
+        for parameter in parameters.split(',').filter(|p| !p.trim().is_empty()) {
+            let (name, value) = parameter
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=value, got {:?}", parameter))?;
+            let value: f32 = value
+                .trim()
+                .parse()
+                .map_err(|e| format!("bad value for {}: {}", name.trim(), e))?;
+            match name.trim() {
+                "size" if value > 0.0 => size = Some(value),
+                "size" => return Err(format!("size must be positive, got {}", value)),
+                "restitution" => restitution = value.clamp(0.0, 1.0),
+                "park" => park = value != 0.0,
+                other => return Err(format!("unknown parameter {:?}", other)),
+            }
+        }
+        let kind = kind.trim().to_lowercase();
+        if kind == "unbounded" {
+            return Ok(Boundary::Unbounded);
+        }
+        let size = size.ok_or_else(|| format!("a {} boundary needs a size", kind))?;
+        match kind.as_str() {
+            "periodic" => Ok(Boundary::Periodic { size }),
+            "reflective" => Ok(Boundary::Reflective { size, restitution }),
+            "absorbing" => Ok(Boundary::Absorbing {
+                size,
+                escapes: if park { Escapes::Park } else { Escapes::Cull },
+            }),
+            other => Err(format!(
+                "unknown boundary {:?} (expected unbounded, periodic, reflective or absorbing)",
+                other
+            )),
+        }
+    }
+}
+
+/// The shortest of the separations between copies of two points `d` apart,
+/// in space that repeats every `period` (or `d` itself if it doesn't).
+pub fn minimum_image(d: Vector3, period: Option<f32>) -> Vector3 {
+    match period {
+        Some(size) => vec3(
+            d.x - size * (d.x / size).round(),
+            d.y - size * (d.y / size).round(),
+            d.z - size * (d.z / size).round(),
+        ),
+        None => d,
+    }
+}
+
+/// Wrap a point into the periodic box of side `size` centered on the origin.
+pub fn wrap_position(position: Vector3, size: f32) -> Vector3 {
+    let half = 0.5 * size;
+    vec3(
+        wrap(position.x, half, size),
+        wrap(position.y, half, size),
+        wrap(position.z, half, size),
+    )
+}
+
+/// Wrap a coordinate into [-half, half).
+fn wrap(x: f32, half: f32, size: f32) -> f32 {
+    if (-half..half).contains(&x) {
+        x
+    } else {
+        x - size * ((x + half) / size).floor()
+    }
+}
+
+/// Bounce a coordinate off the walls at -half and half, if it's past one,
+/// turning the velocity back in and keeping `restitution` of it.
+fn reflect(x: &mut f32, v: &mut f32, half: f32, restitution: f32) {
+    if *x > half {
+        *x = (2.0 * half - *x).max(-half);
+        *v = -v.abs() * restitution;
+    } else if *x < -half {
+        *x = (-2.0 * half - *x).min(half);
+        *v = v.abs() * restitution;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coordinates_wrap_into_the_box() {
+        assert_eq!(wrap(3.0, 5.0, 10.0), 3.0);
+        assert_eq!(wrap(-5.0, 5.0, 10.0), -5.0);
+        assert_eq!(wrap(5.0, 5.0, 10.0), -5.0);
+        assert_eq!(wrap(6.5, 5.0, 10.0), -3.5);
+        assert_eq!(wrap(-7.0, 5.0, 10.0), 3.0);
+        // Even from several boxes away.
+        assert_eq!(wrap(27.0, 5.0, 10.0), -3.0);
+        assert_eq!(
+            wrap_position(vec3(6.0, -2.0, -16.0), 10.0),
+            vec3(-4.0, -2.0, 4.0)
+        );
+    }
+
+    #[test]
+    fn walls_turn_particles_back() {
+        let (mut x, mut v) = (6.0, 2.0);
+        reflect(&mut x, &mut v, 5.0, 0.5);
+        assert_eq!((x, v), (4.0, -1.0));
+
+        let (mut x, mut v) = (-5.5, -4.0);
+        reflect(&mut x, &mut v, 5.0, 1.0);
+        assert_eq!((x, v), (-4.5, 4.0));
+
+        // Inside, nothing happens, even when heading for a wall.
+        let (mut x, mut v) = (4.9, 3.0);
+        reflect(&mut x, &mut v, 5.0, 1.0);
+        assert_eq!((x, v), (4.9, 3.0));
+
+        // Far enough out to overshoot the other wall, it stops there.
+        let (mut x, mut v) = (16.0, 1.0);
+        reflect(&mut x, &mut v, 5.0, 1.0);
+        assert_eq!((x, v), (-5.0, -1.0));
+    }
+
+    #[test]
+    fn separations_go_to_the_nearest_image() {
+        let d = vec3(9.0, -6.0, 2.0);
+        assert_eq!(minimum_image(d, None), d);
+        assert_eq!(minimum_image(d, Some(10.0)), vec3(-1.0, 4.0, 2.0));
+        assert_eq!(
+            minimum_image(vec3(-23.0, 0.0, 0.0), Some(10.0)),
+            vec3(-3.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn boundaries_read_back_what_they_write() {
+        for boundary in [
+            Boundary::Unbounded,
+            Boundary::Periodic { size: 20000.0 },
+            Boundary::Reflective {
+                size: 10.0,
+                restitution: 0.8,
+            },
+            Boundary::Absorbing {
+                size: 50000.0,
+                escapes: Escapes::Park,
+            },
+            Boundary::Absorbing {
+                size: 0.5,
+                escapes: Escapes::Cull,
+            },
+        ] {
+            assert_eq!(boundary.to_string().parse(), Ok(boundary));
+        }
+        assert_eq!(
+            "Reflective: size = 4".parse(),
+            Ok(Boundary::Reflective {
+                size: 4.0,
+                restitution: 1.0
+            })
+        );
+        assert!("periodic".parse::<Boundary>().is_err());
+        assert!("periodic:size=-1".parse::<Boundary>().is_err());
+        assert!("toroidal:size=1".parse::<Boundary>().is_err());
+    }
+}
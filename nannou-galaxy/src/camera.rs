@@ -0,0 +1,283 @@
+/*
+The camera for the galaxy simulation.
+
+The camera owns the `Viewport` (see viewport.rs) and moves it around in
+response to the mouse:
+
+* the scroll wheel zooms in and out around the cursor, so the point under the
+  cursor stays put;
+* dragging with the right mouse button pans;
+* dragging with the middle mouse button, or the right one with shift held,
+  orbits the view around the point in the middle of the window, and so do the
+  arrow keys.
+
+Bodies are drawn at their physical size times the zoom, so they grow as you
+zoom in (down to a minimum size on screen, so small ones don't vanish). In
+perspective (see viewport.rs), nearer bodies are also drawn bigger and
+brighter than farther ones.
+
+The camera can also follow the simulation, so bodies that drift don't wander
+off the window. `Follow::CenterOfMass` keeps the system's center of mass in the
+middle of the window, and `Follow::Heaviest` keeps the heaviest body there. The
+heaviest body is chosen once and then tracked by id, through any merges, so the
+camera doesn't jump around when two bodies of similar mass trade places.
+Panning by hand stops following.
+
+Finally, the camera can draw a grid with a scale bar that says how far apart
+the grid lines are. The spacing is always 1, 2 or 5 times a power of ten, and
+adapts to the zoom. The grid lies in the plane through the middle of the
+window, facing the camera, with a line through the origin's projection onto
+it; face on, that's just the simulation's x-y grid.
+*/
+
+use nannou::prelude::*;
+
+use crate::store::{ParticleId, ParticleStore};
+use crate::viewport::Viewport;
+
+/// How much one notch of the scroll wheel zooms.
+const ZOOM_PER_NOTCH: f32 = 1.1;
+
+/// Roughly how many pixels apart grid lines should be.
+const GRID_PIXELS: f32 = 100.0;
+
+/// How far dragging across one pixel orbits the view, in radians.
+const ORBIT_PER_PIXEL: f32 = 0.01;
+
+/// What dragging the mouse does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Drag {
+    Pan,
+    Orbit,
+}
+
+/// What, if anything, the camera keeps in the middle of the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Follow {
+    /// Stay where the user put it.
+    Free,
+    /// Follow the center of mass of the whole system.
+    CenterOfMass,
+    /// Follow a body, chosen as the heaviest when following starts.
+    Heaviest(Option<ParticleId>),
+}
+
+impl Follow {
+    /// The follow mode after this one, for cycling through them with a key.
+    pub fn next(&self) -> Self {
+        match self {
+            Follow::Free => Follow::CenterOfMass,
+            Follow::CenterOfMass => Follow::Heaviest(None),
+            Follow::Heaviest(_) => Follow::Free,
+        }
+    }
+}
+
+/// The camera: where we're looking, how closely, and what we're following.
+#[derive(Clone, Debug)]
+pub struct Camera {
+    pub viewport: Viewport,
+    pub follow: Follow,
+    /// Whether to draw the grid and scale bar.
+    pub show_grid: bool,
+    /// What a drag in progress is doing, and where the cursor was, in window
+    /// coordinates.
+    drag: Option<(Drag, Vector2)>,
+}
+
+impl Camera {
+    /// Create a camera that looks at a viewport and doesn't follow anything.
+    pub fn new(viewport: Viewport) -> Self {
+        Camera {
+            viewport,
+            follow: Follow::Free,
+            show_grid: false,
+            drag: None,
+        }
+    }
+
+    /// Zoom in (factor > 1) or out (factor < 1), keeping the point under the
+    /// cursor where it is.
+    ///
+    /// Arguments:
+    ///
+    /// * `cursor` - the cursor, in window coordinates
+    /// * `factor` - how much to multiply the scale by
+    pub fn zoom_at(&mut self, cursor: Vector2, factor: f32) {
+        let anchor = self.viewport.to_world(cursor);
+        self.viewport.pixels_per_unit *= factor;
+        self.viewport.center = anchor;
+        self.viewport.pan(cursor);
+    }
+
+    /// Zoom by a scroll of the mouse wheel.
+    pub fn scroll(&mut self, cursor: Vector2, delta: MouseScrollDelta) {
+        let notches = match delta {
+            MouseScrollDelta::LineDelta(_, y) => y,
+            MouseScrollDelta::PixelDelta(position) => position.y as f32 / 50.0,
+        };
+        self.zoom_at(cursor, ZOOM_PER_NOTCH.powf(notches));
+    }
+
+    /// Start panning from the cursor, and stop following anything.
+    pub fn start_drag(&mut self, cursor: Vector2) {
+        self.drag = Some((Drag::Pan, cursor));
+        self.follow = Follow::Free;
+    }
+
+    /// Start orbiting the view with the cursor. Unlike panning, this keeps
+    /// following whatever the camera follows.
+    pub fn start_orbit(&mut self, cursor: Vector2) {
+        self.drag = Some((Drag::Orbit, cursor));
+    }
+
+    /// Move or turn the view with the cursor, if dragging: across the window
+    /// turns about the z axis, and up and down tilts.
+    pub fn drag_to(&mut self, cursor: Vector2) {
+        if let Some((drag, last)) = self.drag {
+            let moved = cursor - last;
+            match drag {
+                Drag::Pan => self.viewport.pan(moved),
+                Drag::Orbit => self
+                    .viewport
+                    .orbit(-moved.x * ORBIT_PER_PIXEL, moved.y * ORBIT_PER_PIXEL),
+            }
+            self.drag = Some((drag, cursor));
+        }
+    }
+
+    /// Stop dragging.
+    pub fn end_drag(&mut self) {
+        self.drag = None;
+    }
+
+    /// Move the view to whatever it is following.
+    pub fn update(&mut self, particles: &ParticleStore) {
+        match self.follow {
+            Follow::Free => {}
+            Follow::CenterOfMass => {
+                if let Some(center) = center_of_mass(particles) {
+                    self.viewport.center = center;
+                }
+            }
+            Follow::Heaviest(target) => {
+                // Keep following the same body, or whatever it merged into,
+                // and only pick a new one if it's gone.
+                let target = target
+                    .and_then(|id| particles.resolve(id))
+                    .or_else(|| heaviest(particles));
+                if let Some(p) = target.and_then(|id| particles.get(id)) {
+                    self.viewport.center = p.position;
+                }
+                self.follow = Follow::Heaviest(target);
+            }
+        }
+    }
+
+    /// Draw the grid and scale bar, if they're turned on.
+    ///
+    /// Arguments:
+    ///
+    /// * `draw` - the draw context
+    /// * `window` - the window's rectangle
+    /// * `unit` - the symbol of the simulation's length unit, for the label
+    pub fn draw_grid(&self, draw: &Draw, window: Rect, unit: &str) {
+        if !self.show_grid {
+            return;
+        }
+        let scale = self.viewport.pixels_per_unit;
+        let spacing = grid_spacing(GRID_PIXELS / scale);
+        // The grid is in the plane through `center` facing the camera, and
+        // lines up with the origin there.
+        let origin = self.viewport.to_camera(vec3(0.0, 0.0, 0.0));
+        let color = rgba(1.0, 1.0, 1.0, 0.08);
+
+        let mut x = ((window.left() / scale - origin.x) / spacing).floor() * spacing + origin.x;
+        while x * scale <= window.right() {
+            let sx = x * scale;
+            draw.line()
+                .start(vec2(sx, window.bottom()))
+                .end(vec2(sx, window.top()))
+                .weight(1.0)
+                .color(color);
+            x += spacing;
+        }
+        let mut y = ((window.bottom() / scale - origin.y) / spacing).floor() * spacing + origin.y;
+        while y * scale <= window.top() {
+            let sy = y * scale;
+            draw.line()
+                .start(vec2(window.left(), sy))
+                .end(vec2(window.right(), sy))
+                .weight(1.0)
+                .color(color);
+            y += spacing;
+        }
+
+        // The scale bar, in the bottom-left corner, one grid spacing long.
+        let length = spacing * self.viewport.pixels_per_unit;
+        let start = vec2(window.left() + 20.0, window.bottom() + 20.0);
+        let end = start + vec2(length, 0.0);
+        draw.line().start(start).end(end).weight(2.0).color(WHITE);
+        for tick in [start, end] {
+            draw.line()
+                .start(tick - vec2(0.0, 4.0))
+                .end(tick + vec2(0.0, 4.0))
+                .weight(2.0)
+                .color(WHITE);
+        }
+        draw.text(&format!("{} {}", format_length(spacing), unit))
+            .x_y(start.x + length / 2.0, start.y + 12.0)
+            .w_h(200.0, 14.0)
+            .font_size(12)
+            .color(WHITE);
+    }
+}
+
+/// The mass-weighted mean position of the particles, if they have any mass.
+fn center_of_mass(particles: &ParticleStore) -> Option<Vector3> {
+    let (mut mass, mut x, mut y, mut z) = (0.0f64, 0.0f64, 0.0f64, 0.0f64);
+    for p in particles.iter() {
+        mass += p.mass as f64;
+        x += (p.mass * p.position.x) as f64;
+        y += (p.mass * p.position.y) as f64;
+        z += (p.mass * p.position.z) as f64;
+    }
+    if mass > 0.0 {
+        Some(vec3(
+            (x / mass) as f32,
+            (y / mass) as f32,
+            (z / mass) as f32,
+        ))
+    } else {
+        None
+    }
+}
+
+/// The id of the heaviest particle, if there are any.
+fn heaviest(particles: &ParticleStore) -> Option<ParticleId> {
+    particles
+        .iter()
+        .max_by(|a, b| a.mass.total_cmp(&b.mass))
+        .map(|p| p.id)
+}
+
+/// The smallest "round" length (1, 2 or 5 times a power of ten) that is at
+/// least `at_least`.
+fn grid_spacing(at_least: f32) -> f32 {
+    let decade = 10f32.powf(at_least.log10().floor());
+    [1.0, 2.0, 5.0, 10.0]
+        .iter()
+        .map(|m| m * decade)
+        .find(|&s| s >= at_least)
+        .unwrap_or(10.0 * decade)
+}
+
+/// Write a round length without float noise, e.g. "0.02" or "5e7".
+fn format_length(length: f32) -> String {
+    let exponent = length.log10().floor() as i32;
+    if (-3..=4).contains(&exponent) {
+        format!("{:.*}", (-exponent).max(0) as usize, length)
+    } else {
+        format!("{:.0e}", length)
+    }
+}
@@ -0,0 +1,224 @@
+/*
+Pacing the galaxy simulation against the wall clock.
+
+Calling `ParticleSystem::update` once per frame would tie the speed of the
+simulation to the refresh rate of the monitor and to how busy the machine is.
+Instead the viewer runs a fixed-step loop with an accumulator: each frame adds
+the real time since the last one, and runs as many updates as that time has
+paid for. At normal speed that's `substeps` updates for each sixtieth of a
+second, whatever the frame rate; `time_scale` speeds that up or slows it down.
+
+If the machine can't keep up, the loop would owe more and more updates every
+frame, and each frame would get slower still. So no frame runs more than
+`max_steps` updates (times the time scale, when fast forwarding), and whatever
+is owed beyond that is forgiven: the simulation slows down rather than
+grinding to a halt.
+
+What's left in the accumulator is a fraction of an update. The particles are
+drawn that far between where they were before the frame's last update and
+where they are now, so motion stays smooth in slow motion, or when the frame
+rate and update rate don't divide evenly. That puts the picture up to one
+update behind the simulation. A particle that jumped during the update, by
+wrapping around a periodic boundary, is drawn where it landed instead.
+*/
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use nannou::prelude::*;
+
+use crate::store::{ParticleId, ParticleStore};
+
+/// The frame rate that normal speed is measured against: at time scale 1, the
+/// simulation runs `substeps` updates this many times a second.
+const FRAME_RATE: f64 = 60.0;
+
+/// How many frames' worth of updates a frame may catch up on, by default.
+const CATCH_UP_FRAMES: u32 = 4;
+
+/// The fixed-step loop's settings and state.
+pub struct Clock {
+    /// How many updates run for each frame at normal speed.
+    pub substeps: u32,
+    /// How much faster than normal the simulation runs; below 1 is slow
+    /// motion.
+    pub time_scale: f32,
+    /// The most updates run in one frame at normal speed, however far behind
+    /// the simulation is. Fast forward raises it in proportion.
+    pub max_steps: u32,
+    /// The updates owed but not yet run, carried from frame to frame.
+    accumulator: f64,
+    /// Each particle's position before the frame's last update, in the order
+    /// the store keeps them.
+    previous: Vec<(ParticleId, Vector3)>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::new(1)
+    }
+}
+
+impl Clock {
+    /// Create a clock at normal speed.
+    ///
+    /// Arguments:
+    ///
+    /// * `substeps` - how many updates to run for each frame at normal speed
+    pub fn new(substeps: u32) -> Self {
+        let substeps = substeps.max(1);
+        Clock {
+            substeps,
+            time_scale: 1.0,
+            max_steps: substeps * CATCH_UP_FRAMES,
+            accumulator: 0.0,
+            previous: Vec::new(),
+        }
+    }
+
+    /// Pay in the real time since the last frame.
+    ///
+    /// Arguments:
+    ///
+    /// * `since_last` - the wall-clock time since the last frame
+    ///
+    /// Returns:
+    ///
+    /// * `u32` - how many updates to run this frame
+    pub fn tick(&mut self, since_last: Duration) -> u32 {
+        let rate = FRAME_RATE * self.substeps as f64 * self.time_scale as f64;
+        self.accumulator += since_last.as_secs_f64() * rate;
+        let owed = self.accumulator.floor();
+        self.accumulator -= owed;
+        // Anything beyond the cap is dropped rather than carried over.
+        let cap = (self.max_steps as f32 * self.time_scale.max(1.0)).ceil();
+        owed.min(cap as f64) as u32
+    }
+
+    /// Remember where the particles are before the frame's last update, to
+    /// draw them between there and where the update takes them.
+    pub fn remember(&mut self, particles: &ParticleStore) {
+        self.previous.clear();
+        self.previous
+            .extend(particles.iter().map(|p| (p.id, p.position)));
+    }
+
+    /// Forget the remembered positions, so the particles are drawn exactly
+    /// where they are, e.g. after jumping to a different state.
+    pub fn forget(&mut self) {
+        self.previous.clear();
+    }
+
+    /// Forget where some particles were before the last update, because they
+    /// jumped rather than moved there, e.g. by wrapping around a periodic
+    /// boundary. They're drawn where they are.
+    pub fn jumped(&mut self, ids: &[ParticleId]) {
+        if ids.is_empty() {
+            return;
+        }
+        let ids: HashSet<ParticleId> = ids.iter().copied().collect();
+        for (id, _) in self.previous.iter_mut() {
+            if ids.contains(id) {
+                *id = ParticleId::UNASSIGNED;
+            }
+        }
+    }
+
+    /// Double or halve the time scale, within a range that stays usable.
+    ///
+    /// Arguments:
+    ///
+    /// * `faster` - whether to double it, rather than halve it
+    pub fn change_speed(&mut self, faster: bool) {
+        let factor = if faster { 2.0 } else { 0.5 };
+        self.time_scale = (self.time_scale * factor).clamp(1.0 / 64.0, 64.0);
+    }
+
+    /// Where to draw each particle, in the order the store keeps them.
+    ///
+    /// A particle is drawn between its remembered position and its current
+    /// one, by the fraction of an update left in the accumulator. A particle
+    /// that wasn't there before the last update, or has moved in the store,
+    /// is drawn where it is.
+    pub fn positions(&self, particles: &ParticleStore) -> Vec<Vector3> {
+        let alpha = self.accumulator as f32;
+        particles
+            .iter()
+            .enumerate()
+            .map(|(i, p)| match self.previous.get(i) {
+                Some((id, before)) if *id == p.id => *before + (p.position - *before) * alpha,
+                _ => p.position,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Particle;
+
+    /// A frame lasting `frames` sixtieths of a second.
+    fn frames(frames: f64) -> Duration {
+        Duration::from_secs_f64(frames / FRAME_RATE)
+    }
+
+    #[test]
+    fn each_frame_runs_the_updates_it_has_paid_for() {
+        let mut clock = Clock::new(2);
+        assert_eq!(clock.tick(frames(1.0)), 2);
+        // Three quarters of a frame pays for one and a half updates; the half
+        // is carried over to the next frame.
+        assert_eq!(clock.tick(frames(0.75)), 1);
+        assert!((clock.accumulator - 0.5).abs() < 1e-6);
+        assert_eq!(clock.tick(frames(0.75)), 2);
+        assert!(clock.accumulator.abs() < 1e-6);
+
+        clock.time_scale = 0.25;
+        assert_eq!(clock.tick(frames(1.0)), 0);
+        assert_eq!(clock.tick(frames(1.0)), 1);
+    }
+
+    #[test]
+    fn slow_frames_are_forgiven_past_the_cap() {
+        let mut clock = Clock::new(2);
+        assert_eq!(clock.max_steps, 2 * CATCH_UP_FRAMES);
+        // A whole second behind: only the cap is run, and the rest is
+        // dropped, not owed to the next frame.
+        assert_eq!(clock.tick(frames(60.0)), clock.max_steps);
+        assert_eq!(clock.tick(frames(0.0)), 0);
+        assert_eq!(clock.tick(frames(1.0)), 2);
+
+        // Fast forward raises the cap with the time scale.
+        clock.time_scale = 4.0;
+        assert_eq!(clock.tick(frames(60.0)), 4 * clock.max_steps);
+    }
+
+    #[test]
+    fn particles_are_drawn_between_updates() {
+        let mut store = ParticleStore::new();
+        let still = vec3(0.0, 0.0, 0.0);
+        let moving = store.insert(Particle::new(still, still, 1.0, 0.1));
+        let wrapping = store.insert(Particle::new(still, still, 1.0, 0.1));
+        let mut clock = Clock::new(1);
+        clock.remember(&store);
+
+        // The update moves both along x, and one of them wraps.
+        store.get_mut(moving).unwrap().position = vec3(4.0, 0.0, 0.0);
+        store.get_mut(wrapping).unwrap().position = vec3(-8.0, 0.0, 0.0);
+        clock.jumped(&[wrapping]);
+        let newcomer = store.insert(Particle::new(vec3(1.0, 1.0, 1.0), still, 1.0, 0.1));
+
+        // A quarter of an update is left over.
+        clock.tick(frames(1.25));
+        assert!((clock.accumulator - 0.25).abs() < 1e-6);
+        let positions = clock.positions(&store);
+        assert!((positions[0] - vec3(1.0, 0.0, 0.0)).magnitude() < 1e-5);
+        assert_eq!(positions[1], store.get(wrapping).unwrap().position);
+        assert_eq!(positions[2], store.get(newcomer).unwrap().position);
+
+        // Forgetting draws everything where it is.
+        clock.forget();
+        assert_eq!(clock.positions(&store)[0], vec3(4.0, 0.0, 0.0));
+    }
+}
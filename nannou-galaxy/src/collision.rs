@@ -0,0 +1,993 @@
+/*
+Collisions for the galaxy simulation.
+
+Two particles touch when the distance between their centers is less than the
+sum of their radii. Candidate pairs are found with a sweep along the x axis:
+sort the particles by their left edge, and only compare a particle with those
+whose left edge starts before its right edge ends. This is O(N log N) plus the
+number of overlapping intervals, instead of comparing every pair.
+
+In a periodic box (see boundary.rs) particles touch across its faces too.
+Separations are taken to the nearest copy of each particle, the sweep carries
+on round from the end of the order to its start, and the bodies in each
+collision are brought side by side to resolve it, then put back in the box.
+
+What happens when two bodies touch depends on how hard they hit. We compare
+the kinetic energy of the impact, in the pair's center-of-mass frame,
+
+    E_impact = 1/2 * mu * v^2,    mu = m1 * m2 / (m1 + m2)
+
+with the energy binding the two together when they touch,
+
+    E_binding = G * m1 * m2 / (r1 + r2)
+
+and their ratio picks the outcome:
+
+* below `accrete_above`, the bodies can't escape each other and merge;
+* up to `bounce_above`, the bigger body sweeps up part of the smaller one
+  (partial accretion) and the rest bounces off;
+* up to `fragment_above`, they bounce off each other, losing energy according
+  to the restitution coefficient (1 is perfectly elastic);
+* above that, both are smashed into several fragments flying apart.
+
+Every outcome conserves mass and linear momentum, and merging, accretion and
+fragmentation keep the center of mass where it was. A `CollisionModel` holds
+the thresholds and restitution, and which outcomes a scenario allows: when
+the natural outcome isn't allowed, the nearest allowed one is used instead, and
+when none are, bodies pass through each other. The default allows only
+merging, which is what the galaxy scenes want.
+
+Merging is transitive: if A merges with B and B merges with C, all three merge
+into one body in the same step, even if A and C are far apart. We find these
+groups with a union-find (disjoint set) over every merging pair, then merge each
+group exactly once. Each other kind of impact involves only one pair, and a body
+takes part in at most one impact per step.
+
+Merging and fragmentation also conserve the bodies' total volume (or disk
+area, depending on the `RadiusScaling`; see material.rs), and in accretion each
+body keeps its density, so bodies grow and shrink believably. Colors are
+blended by mass, in a perceptual color space (see coloring.rs).
+
+Merged and fragmented bodies are new particles with new ids; the store
+remembers which body each parent went into (see store.rs).
+
+Massless tracers (see `Particle::is_tracer`) take no part in any of this. A
+tracer that touches a massive body is captured: it disappears into the body,
+which carries on unchanged, with the same id, since the tracer brings no mass
+or momentum. Tracers pass through each other. `capture_tracers` turns
+capturing off, so tracers pass through everything.
+*/
+
+use nannou::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::boundary::{minimum_image, wrap_position};
+use crate::coloring;
+use crate::material::RadiusScaling;
+use crate::rng::Rng;
+use crate::store::{ParticleId, ParticleStore};
+use crate::Particle;
+
+/// What can happen when two bodies collide, from the gentlest impact to the
+/// most violent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Outcome {
+    Merge,
+    Accrete,
+    Bounce,
+    Fragment,
+}
+
+impl Outcome {
+    /// Every outcome, from the gentlest to the most violent.
+    const ALL: [Outcome; 4] = [
+        Outcome::Merge,
+        Outcome::Accrete,
+        Outcome::Bounce,
+        Outcome::Fragment,
+    ];
+}
+
+/// Which collision outcomes a scenario allows.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AllowedOutcomes {
+    pub merge: bool,
+    pub accrete: bool,
+    pub bounce: bool,
+    pub fragment: bool,
+}
+
+impl AllowedOutcomes {
+    /// Only perfect merges.
+    pub const MERGE_ONLY: AllowedOutcomes = AllowedOutcomes {
+        merge: true,
+        accrete: false,
+        bounce: false,
+        fragment: false,
+    };
+
+    /// Every outcome.
+    pub const ALL: AllowedOutcomes = AllowedOutcomes {
+        merge: true,
+        accrete: true,
+        bounce: true,
+        fragment: true,
+    };
+
+    /// Whether an outcome is allowed.
+    pub fn allows(&self, outcome: Outcome) -> bool {
+        match outcome {
+            Outcome::Merge => self.merge,
+            Outcome::Accrete => self.accrete,
+            Outcome::Bounce => self.bounce,
+            Outcome::Fragment => self.fragment,
+        }
+    }
+
+    /// The allowed outcome nearest to `wanted`, preferring the gentler one
+    /// on a tie, or None if nothing is allowed.
+    fn nearest(&self, wanted: Outcome) -> Option<Outcome> {
+        let rank = |o: Outcome| Outcome::ALL.iter().position(|&a| a == o).unwrap() as i32;
+        Outcome::ALL
+            .iter()
+            .copied()
+            .filter(|&o| self.allows(o))
+            .min_by_key(|&o| ((rank(o) - rank(wanted)).abs(), rank(o)))
+    }
+}
+
+/// How collisions are resolved.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CollisionModel {
+    /// Which outcomes may happen.
+    pub allowed: AllowedOutcomes,
+    /// The fraction of the impact speed kept after a bounce, from 0 (the
+    /// bodies stop dead against each other) to 1 (perfectly elastic). Also
+    /// sets how fast fragments fly apart.
+    pub restitution: f32,
+    /// Impacts above this many times the binding energy don't merge outright.
+    pub accrete_above: f32,
+    /// Impacts above this many times the binding energy bounce.
+    pub bounce_above: f32,
+    /// Impacts above this many times the binding energy fragment.
+    pub fragment_above: f32,
+    /// How many fragments a fragmenting collision makes.
+    pub fragments: usize,
+    /// How bodies grow when they merge or accrete, and shrink when they
+    /// break up (see material.rs). Missing from older scenes, which get the
+    /// default.
+    #[serde(default)]
+    pub radius_scaling: RadiusScaling,
+    /// Whether massless tracers touching a massive body are captured by it.
+    /// Missing from older scenes, which had no tracers and get the default.
+    #[serde(default = "captures_by_default")]
+    pub capture_tracers: bool,
+}
+
+/// Tracers are captured unless a scene says otherwise.
+fn captures_by_default() -> bool {
+    true
+}
+
+impl Default for CollisionModel {
+    fn default() -> Self {
+        CollisionModel {
+            allowed: AllowedOutcomes::MERGE_ONLY,
+            restitution: 0.5,
+            accrete_above: 1.0,
+            bounce_above: 3.0,
+            fragment_above: 20.0,
+            fragments: 6,
+            radius_scaling: RadiusScaling::default(),
+            capture_tracers: captures_by_default(),
+        }
+    }
+}
+
+impl CollisionModel {
+    /// The outcome of an impact with the given ratio of impact to binding
+    /// energy, if one is allowed.
+    fn outcome(&self, energy_ratio: f32) -> Option<Outcome> {
+        let wanted = if energy_ratio < self.accrete_above {
+            Outcome::Merge
+        } else if energy_ratio < self.bounce_above {
+            Outcome::Accrete
+        } else if energy_ratio < self.fragment_above {
+            Outcome::Bounce
+        } else {
+            Outcome::Fragment
+        };
+        self.allowed.nearest(wanted)
+    }
+}
+
+/// A record of one group of particles merging into a single body.
+#[derive(Clone, Debug)]
+pub struct MergeEvent {
+    /// The ids of the merged particles, which are gone from the store.
+    pub parents: Vec<ParticleId>,
+    /// The id of the heaviest parent.
+    pub heaviest: ParticleId,
+    /// The id of the new body.
+    pub id: ParticleId,
+    /// The mass of the new body.
+    pub mass: f32,
+    /// The position of the new body (the group's center of mass).
+    pub position: Vector3,
+}
+
+/// A record of a tracer being captured by a massive body.
+#[derive(Clone, Copy, Debug)]
+pub struct CaptureEvent {
+    /// The tracer, which is gone from the store.
+    pub tracer: ParticleId,
+    /// The body that captured it.
+    pub body: ParticleId,
+}
+
+/// A record of a collision between two bodies that didn't simply merge.
+#[derive(Clone, Debug)]
+pub struct ImpactEvent {
+    /// What happened.
+    pub outcome: Outcome,
+    /// The two bodies, heaviest first. After a fragmentation, they are gone.
+    pub bodies: [ParticleId; 2],
+    /// The ids of the fragments, if the bodies fragmented.
+    pub fragments: Vec<ParticleId>,
+    /// The impact energy over the binding energy.
+    pub energy_ratio: f32,
+}
+
+/// A disjoint-set forest over particle indices.
+struct UnionFind {
+    parent: Vec<usize>,
+    rank: Vec<u8>,
+}
+
+impl UnionFind {
+    fn new(n: usize) -> Self {
+        UnionFind {
+            parent: (0..n).collect(),
+            rank: vec![0; n],
+        }
+    }
+
+    /// Find the representative of `i`'s set, compressing the path as we go.
+    fn find(&mut self, i: usize) -> usize {
+        let mut root = i;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        let mut i = i;
+        while self.parent[i] != root {
+            let next = self.parent[i];
+            self.parent[i] = root;
+            i = next;
+        }
+        root
+    }
+
+    /// Merge the sets containing `a` and `b`.
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return;
+        }
+        if self.rank[a] < self.rank[b] {
+            self.parent[a] = b;
+        } else if self.rank[a] > self.rank[b] {
+            self.parent[b] = a;
+        } else {
+            self.parent[b] = a;
+            self.rank[a] += 1;
+        }
+    }
+}
+
+/// Find every pair of touching particles, except pairs of tracers.
+///
+/// Arguments:
+///
+/// * `particles` - the particles
+/// * `period` - the side of the periodic box, if space wraps around
+///
+/// Returns:
+///
+/// * `Vec<(usize, usize)>` - the indices of each pair, lower first, sorted
+pub fn touching_pairs(particles: &[Particle], period: Option<f32>) -> Vec<(usize, usize)> {
+    let mut order: Vec<usize> = (0..particles.len()).collect();
+    order.sort_by(|&a, &b| {
+        let left_a = particles[a].position.x - particles[a].radius;
+        let left_b = particles[b].position.x - particles[b].radius;
+        left_a.total_cmp(&left_b)
+    });
+
+    let mut pairs = Vec::new();
+    let mut check = |i: usize, j: usize| {
+        let (p1, p2) = (&particles[i], &particles[j]);
+        // Nothing touches itself, and tracers never touch each other.
+        if i == j || (p1.is_tracer() && p2.is_tracer()) {
+            return;
+        }
+        let r = minimum_image(p1.position - p2.position, period);
+        let reach = p1.radius + p2.radius;
+        if r.magnitude2() < reach * reach {
+            pairs.push((i.min(j), i.max(j)));
+        }
+    };
+    for (k, &i) in order.iter().enumerate() {
+        let p1 = &particles[i];
+        let right = p1.position.x + p1.radius;
+        for &j in order[k + 1..].iter() {
+            // Everything after this starts to the right of p1, so stop.
+            if particles[j].position.x - particles[j].radius > right {
+                break;
+            }
+            check(i, j);
+        }
+        // In a periodic box, the particles at the start of the order come
+        // round again a box's width to the right, where they may reach back
+        // to p1 across the face.
+        if let Some(size) = period {
+            for &j in order.iter() {
+                if particles[j].position.x - particles[j].radius + size > right {
+                    break;
+                }
+                check(i, j);
+            }
+        }
+    }
+    // A pair close enough in a small box can be found both ways.
+    pairs.sort_unstable();
+    pairs.dedup();
+    pairs
+}
+
+/// Group particles joined by a chain of pairs.
+///
+/// Returns:
+///
+/// * `Vec<Vec<usize>>` - the indices of each group of two or more, sorted
+///   ascending, and the groups sorted by their first index
+fn connected_groups(n: usize, pairs: &[(usize, usize)]) -> Vec<Vec<usize>> {
+    let mut sets = UnionFind::new(n);
+    for &(i, j) in pairs {
+        sets.union(i, j);
+    }
+
+    // Gather the members of each set; visiting in index order keeps every
+    // group sorted and the groups ordered by their first member.
+    let mut slot = vec![usize::MAX; n];
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for i in 0..n {
+        let root = sets.find(i);
+        if slot[root] == usize::MAX {
+            slot[root] = groups.len();
+            groups.push(Vec::new());
+        }
+        groups[slot[root]].push(i);
+    }
+    groups.retain(|g| g.len() > 1);
+    groups
+}
+
+/// The ratio of a pair's impact energy to the energy binding them together.
+fn energy_ratio(p1: &Particle, p2: &Particle, g: f32) -> f32 {
+    let total = p1.mass + p2.mass;
+    if p1.mass <= 0.0 || p2.mass <= 0.0 || total <= 0.0 {
+        // Nothing binds a massless body, but nor can it do any damage.
+        return 0.0;
+    }
+    let mu = p1.mass * p2.mass / total;
+    let impact = 0.5 * mu * (p1.velocity - p2.velocity).magnitude2();
+    let binding = g * p1.mass * p2.mass / (p1.radius + p2.radius).max(f32::MIN_POSITIVE);
+    impact / binding.max(f32::MIN_POSITIVE)
+}
+
+/// Resolve every collision between touching particles.
+///
+/// Arguments:
+///
+/// * `store` - the particles
+/// * `model` - how collisions are resolved
+/// * `g` - the gravitational constant, for the binding energy
+/// * `period` - the side of the periodic box, if space wraps around
+/// * `rng` - the random numbers used to scatter fragments
+///
+/// Returns:
+///
+/// * `(Vec<MergeEvent>, Vec<ImpactEvent>, Vec<CaptureEvent>)` - one event
+///   per merged group, one per other impact, and one per captured tracer
+pub fn resolve(
+    store: &mut ParticleStore,
+    model: &CollisionModel,
+    g: f32,
+    period: Option<f32>,
+    rng: &mut Rng,
+) -> (Vec<MergeEvent>, Vec<ImpactEvent>, Vec<CaptureEvent>) {
+    let particles = store.as_slice();
+    let pairs = touching_pairs(particles, period);
+    if pairs.is_empty() {
+        return (Vec::new(), Vec::new(), Vec::new());
+    }
+
+    // Decide what each pair does. Indices shift as particles leave the
+    // store, so impacts are kept by id.
+    let mut merging = Vec::new();
+    let mut impacts = Vec::new();
+    let mut captured = vec![false; particles.len()];
+    let mut captures = Vec::new();
+    for &(i, j) in pairs.iter() {
+        let (p1, p2) = (&particles[i], &particles[j]);
+        if p1.is_tracer() || p2.is_tracer() {
+            // A tracer goes into the first massive body it touches.
+            let (tracer, body) = if p1.is_tracer() { (i, j) } else { (j, i) };
+            if model.capture_tracers && !particles[body].is_tracer() && !captured[tracer] {
+                captured[tracer] = true;
+                captures.push(CaptureEvent {
+                    tracer: particles[tracer].id,
+                    body: particles[body].id,
+                });
+            }
+            continue;
+        }
+        let ratio = energy_ratio(p1, p2, g);
+        match model.outcome(ratio) {
+            Some(Outcome::Merge) => merging.push((i, j)),
+            Some(outcome) => {
+                // Bodies already moving apart (say, after a bounce last step)
+                // are left to finish separating.
+                let offset = minimum_image(p2.position - p1.position, period);
+                let closing = (p2.velocity - p1.velocity).dot(offset) < 0.0;
+                if closing {
+                    let (heavy, light) = if p1.mass >= p2.mass { (i, j) } else { (j, i) };
+                    impacts.push((outcome, heavy, light, ratio));
+                }
+            }
+            None => {}
+        }
+    }
+
+    let groups = connected_groups(particles.len(), &merging);
+    let mut busy = vec![false; particles.len()];
+    for &i in groups.iter().flatten() {
+        busy[i] = true;
+    }
+    let mut chosen = Vec::new();
+    for (outcome, heavy, light, ratio) in impacts {
+        if busy[heavy] || busy[light] {
+            continue;
+        }
+        busy[heavy] = true;
+        busy[light] = true;
+        chosen.push((outcome, particles[heavy].id, particles[light].id, ratio));
+    }
+    let groups: Vec<Vec<ParticleId>> = groups
+        .into_iter()
+        .map(|group| group.iter().map(|&i| particles[i].id).collect())
+        .collect();
+
+    let impacts = chosen
+        .into_iter()
+        .map(|(outcome, heavy, light, ratio)| {
+            gather(store, &[heavy, light], period);
+            let fragments = match outcome {
+                Outcome::Accrete => {
+                    accrete(store, heavy, light, model, ratio);
+                    Vec::new()
+                }
+                Outcome::Bounce => {
+                    bounce(store, heavy, light, model.restitution);
+                    Vec::new()
+                }
+                Outcome::Fragment => fragment(store, heavy, light, model, rng),
+                Outcome::Merge => unreachable!("merges are resolved in groups"),
+            };
+            ImpactEvent {
+                outcome,
+                bodies: [heavy, light],
+                fragments,
+                energy_ratio: ratio,
+            }
+        })
+        .collect();
+
+    // Tracers leave before the bodies that took them merge, so the store
+    // follows them on to wherever those bodies go.
+    for capture in captures.iter() {
+        store.remove(capture.tracer);
+        store.record_merge(&[capture.tracer], capture.body);
+    }
+
+    let mut merges: Vec<MergeEvent> = groups
+        .into_iter()
+        .map(|parents| {
+            gather(store, &parents, period);
+            merge_group(store, parents, model.radius_scaling)
+        })
+        .collect();
+
+    // Put back in the box whatever came out of a collision across its faces.
+    if let Some(size) = period {
+        for p in store.iter_mut() {
+            p.position = wrap_position(p.position, size);
+        }
+        for merge in merges.iter_mut() {
+            merge.position = wrap_position(merge.position, size);
+        }
+    }
+    (merges, impacts, captures)
+}
+
+/// Move bodies to the copies of them nearest the first one, in a periodic
+/// box, so that bodies touching across a face are side by side.
+///
+/// Arguments:
+///
+/// * `store` - the particles
+/// * `ids` - the bodies to gather
+/// * `period` - the side of the periodic box, if space wraps around
+fn gather(store: &mut ParticleStore, ids: &[ParticleId], period: Option<f32>) {
+    if period.is_none() {
+        return;
+    }
+    let first = match ids.first().and_then(|&id| store.get(id)) {
+        Some(p) => p.position,
+        None => return,
+    };
+    for &id in ids[1..].iter() {
+        if let Some(p) = store.get_mut(id) {
+            p.position = first + minimum_image(p.position - first, period);
+        }
+    }
+}
+
+/// Merge a group of particles into one new body.
+fn merge_group(
+    store: &mut ParticleStore,
+    parents: Vec<ParticleId>,
+    scaling: RadiusScaling,
+) -> MergeEvent {
+    let members: Vec<Particle> = parents.iter().filter_map(|&id| store.remove(id)).collect();
+    let heaviest = members
+        .iter()
+        .max_by(|a, b| a.mass.total_cmp(&b.mass))
+        .map_or(parents[0], |p| p.id);
+    let merged = members[1..]
+        .iter()
+        .fold(members[0].clone(), |acc, p| acc.merge(p, scaling));
+    let (mass, position) = (merged.mass, merged.position);
+    let id = store.insert(merged);
+    store.record_merge(&parents, id);
+    MergeEvent {
+        parents,
+        heaviest,
+        id,
+        mass,
+        position,
+    }
+}
+
+/// Bounce two touching bodies off each other.
+///
+/// The velocity along the line between their centers is reversed and scaled
+/// by the restitution; the velocity across it is untouched. The bodies are
+/// then pushed apart until they just touch, about their center of mass.
+fn bounce(store: &mut ParticleStore, a: ParticleId, b: ParticleId, restitution: f32) {
+    let (p1, p2) = match store.get_pair_mut(a, b) {
+        Some(pair) => pair,
+        None => return,
+    };
+    let total = p1.mass + p2.mass;
+    let offset = p2.position - p1.position;
+    let distance = offset.magnitude();
+    if total <= 0.0 || distance == 0.0 {
+        return;
+    }
+    let normal = offset / distance;
+
+    // The impulse that reverses the approach speed along the normal.
+    let closing = (p2.velocity - p1.velocity).dot(normal);
+    let impulse = -(1.0 + restitution) * closing * p1.mass * p2.mass / total;
+    p1.velocity -= normal * (impulse / p1.mass);
+    p2.velocity += normal * (impulse / p2.mass);
+
+    let overlap = (p1.radius + p2.radius - distance).max(0.0);
+    p1.position -= normal * (overlap * p2.mass / total);
+    p2.position += normal * (overlap * p1.mass / total);
+}
+
+/// The heavier body sweeps up part of the lighter one, and the rest bounces.
+///
+/// The closer the impact is to a merge, the more is swept up: most of it at
+/// `accrete_above` times the binding energy, none of it at `bounce_above`.
+/// Radii change as if each body kept its density, and the small body's
+/// color is blended into the big one's by the fraction of mass it gains.
+fn accrete(
+    store: &mut ParticleStore,
+    heavy: ParticleId,
+    light: ParticleId,
+    model: &CollisionModel,
+    ratio: f32,
+) {
+    if let Some((big, small)) = store.get_pair_mut(heavy, light) {
+        let span = (model.bounce_above - model.accrete_above).max(f32::MIN_POSITIVE);
+        // Always leave something to bounce; sweeping up everything is a merge.
+        let fraction = ((model.bounce_above - ratio) / span).clamp(0.0, 0.9);
+        let taken = small.mass * fraction;
+        if taken > 0.0 {
+            let mass = big.mass + taken;
+            // The swept-up mass brings the small body's position and momentum.
+            big.position = (big.position * big.mass + small.position * taken) / mass;
+            big.velocity = (big.velocity * big.mass + small.velocity * taken) / mass;
+            let scaling = model.radius_scaling;
+            big.radius = scaling.scale(big.radius, mass / big.mass);
+            small.radius = scaling.scale(small.radius, (small.mass - taken) / small.mass);
+            big.color = coloring::mix(big.color, small.color, taken / mass);
+            big.mass = mass;
+            small.mass -= taken;
+        }
+    }
+    bounce(store, heavy, light, model.restitution);
+}
+
+/// Smash two bodies into fragments.
+///
+/// The combined mass is shared out at random between `model.fragments` new
+/// bodies, spread evenly around a ring about the pair's center of mass and
+/// flying outwards from it. The ring lies in the plane of the impact, spanned
+/// by the line between the bodies and their relative velocity, so a flat
+/// system stays flat. Their kinetic energy relative to the center of mass is the
+/// impact energy times the restitution squared; their total momentum and
+/// center of mass are those of the pair. Between them the fragments fill the
+/// same volume as the pair, and they are all made of what the pair would make
+/// if merged.
+///
+/// The heavier body carries on as the heaviest fragment, and the lighter one
+/// as whichever of the others lands nearest where it was, so anything
+/// following either body (see store.rs) is handed a piece that continues it,
+/// rather than both being sent to the same arbitrary one.
+///
+/// Returns:
+///
+/// * `Vec<ParticleId>` - the fragments, heaviest first
+fn fragment(
+    store: &mut ParticleStore,
+    heavy: ParticleId,
+    light: ParticleId,
+    model: &CollisionModel,
+    rng: &mut Rng,
+) -> Vec<ParticleId> {
+    let (big, small) = match (store.remove(heavy), store.remove(light)) {
+        (Some(big), Some(small)) => (big, small),
+        (big, small) => {
+            // Only one of them was there after all: put it back unharmed.
+            for p in [big, small].into_iter().flatten() {
+                store.insert(p);
+            }
+            return Vec::new();
+        }
+    };
+    let whole = big.merge(&small, model.radius_scaling);
+    let count = model.fragments.max(2);
+
+    // Share out the mass, heaviest fragment first.
+    let mut shares: Vec<f32> = (0..count).map(|_| rng.range(0.5, 1.5)).collect();
+    shares.sort_by(|a, b| b.total_cmp(a));
+    let sum: f32 = shares.iter().sum();
+    let masses: Vec<f32> = shares.iter().map(|s| whole.mass * s / sum).collect();
+    let radii: Vec<f32> = masses
+        .iter()
+        .map(|m| model.radius_scaling.scale(whole.radius, m / whole.mass))
+        .collect();
+
+    // Space the fragments around a ring wide enough that they don't touch.
+    let widest = radii.iter().cloned().fold(0.0, f32::max);
+    let ring = (big.radius + small.radius).max(1.1 * widest / (PI / count as f32).sin());
+    let (u, v) = impact_plane(small.position - big.position, small.velocity - big.velocity);
+    let turn = rng.range(0.0, TAU);
+    let directions: Vec<Vector3> = (0..count)
+        .map(|k| {
+            let angle = turn + TAU * k as f32 / count as f32;
+            u * angle.cos() + v * angle.sin()
+        })
+        .collect();
+
+    // Give each fragment an outward speed, then take out any net momentum and
+    // offset so that the pair's are conserved exactly.
+    let mu = big.mass * small.mass / whole.mass;
+    let impact = 0.5 * mu * (big.velocity - small.velocity).magnitude2();
+    let mut kicks: Vec<Vector3> = directions
+        .iter()
+        .map(|&d| d * rng.range(0.5, 1.5))
+        .collect();
+    let drift = kicks
+        .iter()
+        .zip(masses.iter())
+        .fold(vec3(0.0, 0.0, 0.0), |acc, (&k, &m)| acc + k * m)
+        / whole.mass;
+    for kick in kicks.iter_mut() {
+        *kick -= drift;
+    }
+    let kinetic: f32 = kicks
+        .iter()
+        .zip(masses.iter())
+        .map(|(k, m)| 0.5 * m * k.magnitude2())
+        .sum();
+    let speed = if kinetic > 0.0 {
+        (model.restitution * model.restitution * impact / kinetic).sqrt()
+    } else {
+        0.0
+    };
+    let shift = directions
+        .iter()
+        .zip(masses.iter())
+        .fold(vec3(0.0, 0.0, 0.0), |acc, (&d, &m)| acc + d * m)
+        * ring
+        / whole.mass;
+
+    let positions: Vec<Vector3> = directions
+        .iter()
+        .map(|&d| whole.position + d * ring - shift)
+        .collect();
+    let ids: Vec<ParticleId> = (0..count)
+        .map(|k| {
+            let mut piece = Particle::new(
+                positions[k],
+                whole.velocity + kicks[k] * speed,
+                masses[k],
+                radii[k],
+            );
+            piece.color = whole.color;
+            piece.material = whole.material;
+            store.insert(piece)
+        })
+        .collect();
+    let nearest_light = (1..count)
+        .min_by(|&a, &b| {
+            let distance = |k: usize| (positions[k] - small.position).magnitude2();
+            distance(a).total_cmp(&distance(b))
+        })
+        .unwrap_or(0);
+    store.record_merge(&[heavy], ids[0]);
+    store.record_merge(&[light], ids[nearest_light]);
+    ids
+}
+
+/// Two perpendicular unit vectors spanning the plane of an impact.
+///
+/// Arguments:
+///
+/// * `offset` - from one body to the other
+/// * `velocity` - the velocity of one body relative to the other
+///
+/// Returns:
+///
+/// * `(Vector3, Vector3)` - the direction of `offset`, and the part of
+///   `velocity` across it; if either is missing, directions in the x-y plane
+///   stand in for them where possible
+fn impact_plane(offset: Vector3, velocity: Vector3) -> (Vector3, Vector3) {
+    let unit = |v: Vector3| {
+        let length = v.magnitude();
+        if length > 0.0 && length.is_finite() {
+            Some(v / length)
+        } else {
+            None
+        }
+    };
+    let u = unit(offset).unwrap_or(vec3(1.0, 0.0, 0.0));
+    let across = |v: Vector3| unit(v - u * v.dot(u));
+    let v = across(velocity)
+        .or_else(|| unit(vec3(0.0, 0.0, 1.0).cross(u)))
+        .unwrap_or(vec3(0.0, 1.0, 0.0));
+    (u, v)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The total mass and momentum of everything in the store.
+    fn totals(store: &ParticleStore) -> (f32, Vector3) {
+        store.iter().fold((0.0, vec3(0.0, 0.0, 0.0)), |(m, p), q| {
+            (m + q.mass, p + q.velocity * q.mass)
+        })
+    }
+
+    /// Two touching bodies, of mass 2 and 1 and radius 0.5, closing at
+    /// `speed` on top of a shared drift. With G = 1 the ratio of impact to
+    /// binding energy is speed^2 / 6.
+    fn pair(speed: f32) -> ParticleStore {
+        let drift = vec3(0.3, -0.2, 0.1);
+        let mut store = ParticleStore::new();
+        store.insert(Particle::new(
+            vec3(0.0, 0.0, 0.0),
+            drift + vec3(speed / 3.0, 0.0, 0.0),
+            2.0,
+            0.5,
+        ));
+        store.insert(Particle::new(
+            vec3(0.9, 0.1, 0.0),
+            drift - vec3(2.0 * speed / 3.0, 0.0, 0.0),
+            1.0,
+            0.5,
+        ));
+        store
+    }
+
+    /// Collide a pair at the speed for `ratio` times the binding energy,
+    /// check that mass and momentum are conserved, and return what happened.
+    fn collide(ratio: f32) -> (Vec<MergeEvent>, Vec<ImpactEvent>, ParticleStore) {
+        let mut store = pair((6.0 * ratio).sqrt());
+        let model = CollisionModel {
+            allowed: AllowedOutcomes::ALL,
+            ..CollisionModel::default()
+        };
+        let before = totals(&store);
+        let (merges, impacts, _) = resolve(&mut store, &model, 1.0, None, &mut Rng::new(1));
+        let after = totals(&store);
+        assert!(
+            (after.0 - before.0).abs() < 1e-5,
+            "mass {:?} -> {:?}",
+            before.0,
+            after.0
+        );
+        assert!(
+            (after.1 - before.1).magnitude() < 1e-5,
+            "momentum {:?} -> {:?}",
+            before.1,
+            after.1
+        );
+        (merges, impacts, store)
+    }
+
+    #[test]
+    fn groups_are_joined_through_chains_of_pairs() {
+        let groups = connected_groups(7, &[(0, 1), (5, 6), (1, 3), (2, 5)]);
+        assert_eq!(groups, vec![vec![0, 1, 3], vec![2, 5, 6]]);
+    }
+
+    #[test]
+    fn three_overlapping_bodies_merge_as_one() {
+        // The outer two don't touch, but each touches the middle one.
+        let mut store = ParticleStore::new();
+        for x in [0.0, 0.8, 1.6] {
+            store.insert(Particle::new(
+                vec3(x, 0.0, 0.0),
+                vec3(0.0, x, 0.0),
+                1.0,
+                0.5,
+            ));
+        }
+        let before = totals(&store);
+        let model = CollisionModel::default();
+        let (merges, impacts, _) = resolve(&mut store, &model, 1.0, None, &mut Rng::new(1));
+        assert_eq!(merges.len(), 1);
+        assert_eq!(merges[0].parents.len(), 3);
+        assert!(impacts.is_empty());
+        assert_eq!(store.as_slice().len(), 1);
+        let merged = &store.as_slice()[0];
+        assert_eq!(merged.id, merges[0].id);
+        assert!((merged.position - vec3(0.8, 0.0, 0.0)).magnitude() < 1e-6);
+        let after = totals(&store);
+        assert_eq!(after.0, before.0);
+        assert!((after.1 - before.1).magnitude() < 1e-6);
+    }
+
+    #[test]
+    fn pairs_touch_across_the_faces_of_a_periodic_box() {
+        let particles: Vec<Particle> = [
+            // Either side of the x faces, so at both ends of the sweep.
+            vec3(4.9, 0.0, 0.0),
+            vec3(-4.95, 0.05, 0.0),
+            vec3(0.0, 0.0, 0.0),
+            // Either side of the y faces.
+            vec3(1.0, 4.9, 0.0),
+            vec3(1.1, -4.9, 0.0),
+            vec3(-4.8, 3.0, 0.0),
+        ]
+        .into_iter()
+        .map(|x| Particle::new(x, vec3(0.0, 0.0, 0.0), 1.0, 0.2))
+        .collect();
+        assert!(touching_pairs(&particles, None).is_empty());
+        assert_eq!(touching_pairs(&particles, Some(10.0)), vec![(0, 1), (3, 4)]);
+        assert!(touching_pairs(&particles, Some(10.5)).is_empty());
+    }
+
+    #[test]
+    fn bodies_merge_across_the_faces_of_a_periodic_box() {
+        let mut store = ParticleStore::new();
+        for x in [4.8, -4.7] {
+            store.insert(Particle::new(
+                vec3(x, 1.0, 0.0),
+                vec3(0.0, 0.0, 0.0),
+                1.0,
+                0.3,
+            ));
+        }
+        let model = CollisionModel::default();
+        let (merges, _, _) = resolve(&mut store, &model, 1.0, Some(10.0), &mut Rng::new(1));
+        assert_eq!(merges.len(), 1);
+        assert_eq!(merges[0].position, store.as_slice()[0].position);
+        // Halfway between them the short way round, just over the face, and
+        // so wrapped back in on the other side.
+        let merged = &store.as_slice()[0];
+        assert!((merged.position - vec3(-4.95, 1.0, 0.0)).magnitude() < 1e-5);
+        assert_eq!(merged.mass, 2.0);
+    }
+
+    #[test]
+    fn gentle_impacts_merge() {
+        let (merges, impacts, store) = collide(0.5);
+        assert_eq!(merges.len(), 1);
+        assert!(impacts.is_empty());
+        assert_eq!(store.as_slice().len(), 1);
+    }
+
+    #[test]
+    fn harder_impacts_accrete() {
+        let (merges, impacts, store) = collide(2.0);
+        assert!(merges.is_empty());
+        assert_eq!(impacts.len(), 1);
+        assert_eq!(impacts[0].outcome, Outcome::Accrete);
+        let big = store.get(impacts[0].bodies[0]).unwrap();
+        let small = store.get(impacts[0].bodies[1]).unwrap();
+        assert!(big.mass > 2.0 && small.mass < 1.0);
+    }
+
+    #[test]
+    fn hard_impacts_bounce() {
+        let (merges, impacts, store) = collide(10.0);
+        assert!(merges.is_empty());
+        assert_eq!(impacts.len(), 1);
+        assert_eq!(impacts[0].outcome, Outcome::Bounce);
+        // They're moving apart now.
+        let big = store.get(impacts[0].bodies[0]).unwrap();
+        let small = store.get(impacts[0].bodies[1]).unwrap();
+        assert!((small.velocity - big.velocity).dot(small.position - big.position) > 0.0);
+        assert_eq!((big.mass, small.mass), (2.0, 1.0));
+    }
+
+    #[test]
+    fn violent_impacts_fragment() {
+        let (merges, impacts, store) = collide(50.0);
+        assert!(merges.is_empty());
+        assert_eq!(impacts.len(), 1);
+        assert_eq!(impacts[0].outcome, Outcome::Fragment);
+        let fragments = &impacts[0].fragments;
+        assert_eq!(fragments.len(), CollisionModel::default().fragments);
+        assert_eq!(store.as_slice().len(), fragments.len());
+
+        // Each body carries on as a different fragment, the heavy one as the
+        // heaviest.
+        let [heavy, light] = impacts[0].bodies;
+        assert_eq!(store.resolve(heavy), Some(fragments[0]));
+        let continued = store.resolve(light).unwrap();
+        assert!(fragments[1..].contains(&continued));
+    }
+
+    #[test]
+    fn outcomes_follow_the_thresholds() {
+        let model = CollisionModel {
+            allowed: AllowedOutcomes::ALL,
+            ..CollisionModel::default()
+        };
+        assert_eq!(model.outcome(0.5), Some(Outcome::Merge));
+        assert_eq!(model.outcome(2.0), Some(Outcome::Accrete));
+        assert_eq!(model.outcome(10.0), Some(Outcome::Bounce));
+        assert_eq!(model.outcome(50.0), Some(Outcome::Fragment));
+        // Only merging is allowed by default, whatever the impact.
+        assert_eq!(
+            CollisionModel::default().outcome(50.0),
+            Some(Outcome::Merge)
+        );
+    }
+
+    #[test]
+    fn energy_ratio_compares_impact_to_binding() {
+        let store = pair(3.0);
+        let (p1, p2) = (&store.as_slice()[0], &store.as_slice()[1]);
+        assert!((energy_ratio(p1, p2, 1.0) - 1.5).abs() < 1e-5);
+        assert!((energy_ratio(p1, p2, 2.0) - 0.75).abs() < 1e-5);
+    }
+}
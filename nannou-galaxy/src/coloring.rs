@@ -0,0 +1,620 @@
+/*
+Coloring particles by what they're doing, for the galaxy simulation.
+
+By default each particle is drawn in its own color, which comes from its
+material (see material.rs). Instead, the particles can be colored by a
+physical quantity:
+
+* speed, relative to the center of mass of the system;
+* mass;
+* kinetic energy, again in the center-of-mass frame;
+* local density: the mass per unit area of the cell the particle is in, on a
+  grid laid over the whole system in the x-y plane (so, seen face on, the
+  surface density);
+* age: how long since the particle joined the system or was last made by a
+  merge or a collision;
+* distance from the center of mass.
+
+The quantity is mapped through a perceptual colormap: viridis, magma, inferno
+or cividis, from matplotlib. These change smoothly and evenly in lightness, so
+equal steps in the quantity look like equal steps in color, and they read well
+to people with color-vision deficiencies. Each map is kept as a few samples,
+and interpolated in the Oklab color space (Bjorn Ottosson, 2020) rather than
+in RGB: Oklab is close to perceptually uniform, so the mix of two colors looks
+halfway between them, where mixing in RGB goes muddy and dark. `mix` is also
+what blends the colors of merging bodies.
+
+Quantities that span many orders of magnitude (mass, energy and density) are
+mapped on a log scale. The range mapped onto the colormap is either fixed, or
+chosen automatically every frame: from the 2nd to the 98th percentile of the
+values, so that one outlier doesn't wash out everything else. A legend shows
+the colormap, the quantity and the range.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+
+use nannou::prelude::*;
+
+use crate::units::UnitSystem;
+use crate::{Particle, ParticleSystem};
+
+/// How many cells across the grid used to measure local density is.
+const DENSITY_CELLS: usize = 64;
+
+/// The fraction of the values left out at each end of an automatic range.
+const AUTO_PERCENTILE: f32 = 0.02;
+
+/// What particles are colored by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quantity {
+    /// Each particle's own color.
+    Material,
+    Speed,
+    Mass,
+    KineticEnergy,
+    LocalDensity,
+    Age,
+    DistanceFromCenter,
+}
+
+impl Quantity {
+    /// The quantity after this one, for cycling through them with a key.
+    pub fn next(&self) -> Self {
+        match self {
+            Quantity::Material => Quantity::Speed,
+            Quantity::Speed => Quantity::Mass,
+            Quantity::Mass => Quantity::KineticEnergy,
+            Quantity::KineticEnergy => Quantity::LocalDensity,
+            Quantity::LocalDensity => Quantity::Age,
+            Quantity::Age => Quantity::DistanceFromCenter,
+            Quantity::DistanceFromCenter => Quantity::Material,
+        }
+    }
+
+    /// The quantity's name, as written on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quantity::Material => "material",
+            Quantity::Speed => "speed",
+            Quantity::Mass => "mass",
+            Quantity::KineticEnergy => "energy",
+            Quantity::LocalDensity => "density",
+            Quantity::Age => "age",
+            Quantity::DistanceFromCenter => "distance",
+        }
+    }
+
+    /// Whether the quantity is mapped on a log scale.
+    fn is_logarithmic(&self) -> bool {
+        matches!(
+            self,
+            Quantity::Mass | Quantity::KineticEnergy | Quantity::LocalDensity
+        )
+    }
+
+    /// The unit the quantity is measured in, in a system's units.
+    fn unit(&self, units: &UnitSystem) -> String {
+        let (l, m, t) = (
+            units.length.symbol(),
+            units.mass.symbol(),
+            units.time.symbol(),
+        );
+        match self {
+            Quantity::Material => String::new(),
+            Quantity::Speed => format!("{}/{}", l, t),
+            Quantity::Mass => m.to_string(),
+            Quantity::KineticEnergy => format!("{} {}^2/{}^2", m, l, t),
+            Quantity::LocalDensity => format!("{}/{}^2", m, l),
+            Quantity::Age => t.to_string(),
+            Quantity::DistanceFromCenter => l.to_string(),
+        }
+    }
+}
+
+impl FromStr for Quantity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut quantity = Quantity::Material;
+        loop {
+            if quantity.name() == s.trim().to_lowercase() {
+                return Ok(quantity);
+            }
+            quantity = quantity.next();
+            if quantity == Quantity::Material {
+                return Err(format!("unknown quantity {:?}", s));
+            }
+        }
+    }
+}
+
+/// A perceptual colormap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Colormap {
+    Viridis,
+    Magma,
+    Inferno,
+    Cividis,
+}
+
+impl Colormap {
+    /// The colormap after this one, for cycling through them with a key.
+    pub fn next(&self) -> Self {
+        match self {
+            Colormap::Viridis => Colormap::Magma,
+            Colormap::Magma => Colormap::Inferno,
+            Colormap::Inferno => Colormap::Cividis,
+            Colormap::Cividis => Colormap::Viridis,
+        }
+    }
+
+    /// The colormap's name, as written on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Colormap::Viridis => "viridis",
+            Colormap::Magma => "magma",
+            Colormap::Inferno => "inferno",
+            Colormap::Cividis => "cividis",
+        }
+    }
+
+    /// Evenly spaced samples of the colormap, from 0 to 1, as sRGB hex.
+    fn samples(&self) -> &'static [u32; 9] {
+        match self {
+            Colormap::Viridis => &[
+                0x440154, 0x472d7b, 0x3b528b, 0x2c728e, 0x21918c, 0x28ae80, 0x5ec962, 0xaddc30,
+                0xfde725,
+            ],
+            Colormap::Magma => &[
+                0x000004, 0x1c1044, 0x4f127b, 0x812581, 0xb5367a, 0xe55064, 0xfb8761, 0xfec287,
+                0xfcfdbf,
+            ],
+            Colormap::Inferno => &[
+                0x000004, 0x1f0c48, 0x550f6d, 0x88226a, 0xba3655, 0xe35933, 0xf98e09, 0xf9cb35,
+                0xfcffa4,
+            ],
+            Colormap::Cividis => &[
+                0x00224e, 0x123570, 0x3b496c, 0x575d6d, 0x707173, 0x8a8779, 0xa69d75, 0xc4b56c,
+                0xfee838,
+            ],
+        }
+    }
+
+    /// The color at a point along the colormap.
+    ///
+    /// Arguments:
+    ///
+    /// * `t` - how far along, from 0 to 1; clamped to that range
+    pub fn sample(&self, t: f32) -> Rgb {
+        let samples = self.samples();
+        let hex = |c: u32| {
+            rgb(
+                ((c >> 16) & 0xff) as f32 / 255.0,
+                ((c >> 8) & 0xff) as f32 / 255.0,
+                (c & 0xff) as f32 / 255.0,
+            )
+        };
+        let t = if t.is_finite() {
+            t.clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let x = t * (samples.len() - 1) as f32;
+        let i = (x.floor() as usize).min(samples.len() - 2);
+        mix(hex(samples[i]), hex(samples[i + 1]), x - i as f32)
+    }
+}
+
+impl FromStr for Colormap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut colormap = Colormap::Viridis;
+        loop {
+            if colormap.name() == s.trim().to_lowercase() {
+                return Ok(colormap);
+            }
+            colormap = colormap.next();
+            if colormap == Colormap::Viridis {
+                return Err(format!("unknown colormap {:?}", s));
+            }
+        }
+    }
+}
+
+/// The range of values mapped onto a colormap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Range {
+    /// Fit the range to the values every frame.
+    Auto,
+    /// Always map `low` to the start of the colormap and `high` to the end.
+    Fixed { low: f32, high: f32 },
+}
+
+/// The coloring settings, and the colors they give the particles right now.
+#[derive(Clone, Debug)]
+pub struct Coloring {
+    pub quantity: Quantity,
+    pub colormap: Colormap,
+    pub range: Range,
+    /// The range used for the current colors, for the legend.
+    shown: (f32, f32),
+    /// The units of the current values, for the legend.
+    unit: String,
+    /// One color per particle, in the order the store keeps them.
+    colors: Vec<Rgb>,
+}
+
+impl Default for Coloring {
+    fn default() -> Self {
+        Coloring {
+            quantity: Quantity::Material,
+            colormap: Colormap::Viridis,
+            range: Range::Auto,
+            shown: (0.0, 1.0),
+            unit: String::new(),
+            colors: Vec::new(),
+        }
+    }
+}
+
+impl Coloring {
+    /// Recompute every particle's color.
+    pub fn update(&mut self, system: &ParticleSystem) {
+        let particles = system.particles.as_slice();
+        self.colors.clear();
+        if self.quantity == Quantity::Material {
+            self.colors.extend(particles.iter().map(|p| p.color));
+            return;
+        }
+
+        let log = self.quantity.is_logarithmic();
+        let values: Vec<f32> = measure(self.quantity, system)
+            .into_iter()
+            .map(|v| if log { v.log10() } else { v })
+            .collect();
+        let (low, high) = match self.range {
+            Range::Auto => percentiles(&values),
+            Range::Fixed { low, high } if log => (low.log10(), high.log10()),
+            Range::Fixed { low, high } => (low, high),
+        };
+        let span = if high > low { high - low } else { 1.0 };
+        self.colors.extend(
+            values
+                .iter()
+                .map(|v| self.colormap.sample((v - low) / span)),
+        );
+        self.shown = if log {
+            (10f32.powf(low), 10f32.powf(high))
+        } else {
+            (low, high)
+        };
+        self.unit = self.quantity.unit(&system.units);
+    }
+
+    /// The current colors, one per particle in the order the store keeps
+    /// them. Only valid until the particles change.
+    pub fn colors(&self) -> &[Rgb] {
+        &self.colors
+    }
+
+    /// Switch between an automatic range and a range fixed where it is now.
+    pub fn toggle_range(&mut self) {
+        self.range = match self.range {
+            Range::Auto => Range::Fixed {
+                low: self.shown.0,
+                high: self.shown.1,
+            },
+            Range::Fixed { .. } => Range::Auto,
+        };
+    }
+
+    /// Draw the colormap, the quantity and the range in the bottom-right
+    /// corner of the window, unless particles are in their own colors.
+    pub fn draw_legend(&self, draw: &Draw, window: Rect) {
+        if self.quantity == Quantity::Material {
+            return;
+        }
+        let (width, height, steps) = (16.0, 160.0, 32);
+        let x = window.right() - 90.0;
+        let bottom = window.bottom() + 30.0;
+        for i in 0..steps {
+            let t = (i as f32 + 0.5) / steps as f32;
+            draw.rect()
+                .x_y(x, bottom + t * height)
+                .w_h(width, height / steps as f32 + 0.5)
+                .color(self.colormap.sample(t));
+        }
+
+        let label = |text: String, y: f32| {
+            draw.text(&text)
+                .x_y(x + 50.0, y)
+                .w_h(80.0, 14.0)
+                .left_justify()
+                .font_size(11)
+                .color(WHITE);
+        };
+        label(format_value(self.shown.1), bottom + height);
+        label(format_value(self.shown.0), bottom);
+        let scale = if self.quantity.is_logarithmic() {
+            ", log"
+        } else {
+            ""
+        };
+        let fixed = match self.range {
+            Range::Auto => "",
+            Range::Fixed { .. } => ", fixed",
+        };
+        draw.text(&format!(
+            "{} ({}{}{})",
+            self.quantity.name(),
+            self.unit,
+            scale,
+            fixed
+        ))
+        .x_y(x, bottom + height + 20.0)
+        .w_h(160.0, 14.0)
+        .font_size(11)
+        .color(WHITE);
+    }
+}
+
+impl fmt::Display for Coloring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.quantity {
+            Quantity::Material => write!(f, "material"),
+            quantity => write!(f, "{} through {}", quantity.name(), self.colormap.name()),
+        }
+    }
+}
+
+/// Measure a quantity for every particle.
+///
+/// Arguments:
+///
+/// * `quantity` - what to measure; not `Quantity::Material`
+/// * `system` - the system the particles are in, for its time and center of
+///   mass
+///
+/// Returns:
+///
+/// * `Vec<f32>` - one value per particle, in the order the store keeps them
+fn measure(quantity: Quantity, system: &ParticleSystem) -> Vec<f32> {
+    let particles = system.particles.as_slice();
+    let (center, drift) = center_of_mass_frame(particles);
+    match quantity {
+        Quantity::Material => vec![0.0; particles.len()],
+        Quantity::Speed => particles
+            .iter()
+            .map(|p| (p.velocity - drift).magnitude())
+            .collect(),
+        Quantity::Mass => particles.iter().map(|p| p.mass).collect(),
+        Quantity::KineticEnergy => particles
+            .iter()
+            .map(|p| 0.5 * p.mass * (p.velocity - drift).magnitude2())
+            .collect(),
+        Quantity::LocalDensity => local_density(particles),
+        Quantity::Age => particles
+            .iter()
+            .map(|p| (system.time - p.born) as f32)
+            .collect(),
+        Quantity::DistanceFromCenter => particles
+            .iter()
+            .map(|p| (p.position - center).magnitude())
+            .collect(),
+    }
+}
+
+/// The position and velocity of the center of mass of the particles.
+fn center_of_mass_frame(particles: &[Particle]) -> (Vector3, Vector3) {
+    let (mut mass, mut position, mut velocity) = (0.0f64, [0.0f64; 3], [0.0f64; 3]);
+    for p in particles {
+        let m = p.mass as f64;
+        mass += m;
+        position[0] += m * p.position.x as f64;
+        position[1] += m * p.position.y as f64;
+        position[2] += m * p.position.z as f64;
+        velocity[0] += m * p.velocity.x as f64;
+        velocity[1] += m * p.velocity.y as f64;
+        velocity[2] += m * p.velocity.z as f64;
+    }
+    if mass <= 0.0 {
+        return (vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0));
+    }
+    let mean = |v: [f64; 3]| {
+        vec3(
+            (v[0] / mass) as f32,
+            (v[1] / mass) as f32,
+            (v[2] / mass) as f32,
+        )
+    };
+    (mean(position), mean(velocity))
+}
+
+/// The mass per unit area around each particle, measured on a grid of
+/// `DENSITY_CELLS` square cells across the bounding box of the particles.
+fn local_density(particles: &[Particle]) -> Vec<f32> {
+    let (mut lower, mut upper) = (vec2(f32::MAX, f32::MAX), vec2(f32::MIN, f32::MIN));
+    for p in particles {
+        lower = vec2(lower.x.min(p.position.x), lower.y.min(p.position.y));
+        upper = vec2(upper.x.max(p.position.x), upper.y.max(p.position.y));
+    }
+    let size = (upper.x - lower.x).max(upper.y - lower.y);
+    if particles.is_empty() || size <= 0.0 {
+        return vec![0.0; particles.len()];
+    }
+    let cell = size / DENSITY_CELLS as f32 * 1.0001;
+    let index = |p: &Particle| {
+        let x = ((p.position.x - lower.x) / cell) as usize;
+        let y = ((p.position.y - lower.y) / cell) as usize;
+        y.min(DENSITY_CELLS - 1) * DENSITY_CELLS + x.min(DENSITY_CELLS - 1)
+    };
+    let mut grid = vec![0.0; DENSITY_CELLS * DENSITY_CELLS];
+    for p in particles {
+        grid[index(p)] += p.mass;
+    }
+    let area = cell * cell;
+    particles.iter().map(|p| grid[index(p)] / area).collect()
+}
+
+/// The values `AUTO_PERCENTILE` of the way in from each end of the finite
+/// values, or (0, 1) if there are none.
+fn percentiles(values: &[f32]) -> (f32, f32) {
+    let mut sorted: Vec<f32> = values.iter().cloned().filter(|v| v.is_finite()).collect();
+    if sorted.is_empty() {
+        return (0.0, 1.0);
+    }
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let at = |q: f32| sorted[((sorted.len() - 1) as f32 * q).round() as usize];
+    (at(AUTO_PERCENTILE), at(1.0 - AUTO_PERCENTILE))
+}
+
+/// Write a value for the legend, in a few characters.
+fn format_value(value: f32) -> String {
+    if value != 0.0 && !(0.01..10000.0).contains(&value.abs()) {
+        format!("{:.2e}", value)
+    } else {
+        format!("{:.3}", value)
+    }
+}
+
+/// An sRGB channel, from 0 to 1, in linear light.
+fn to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// A channel in linear light, from 0 to 1, encoded as sRGB.
+fn from_linear(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0);
+    if c <= 0.003_130_8 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Convert an sRGB color to Oklab lightness and a and b.
+fn to_oklab(color: Rgb) -> [f32; 3] {
+    let (r, g, b) = (
+        to_linear(color.red),
+        to_linear(color.green),
+        to_linear(color.blue),
+    );
+    let l = (0.412_221_46 * r + 0.536_332_55 * g + 0.051_445_995 * b).cbrt();
+    let m = (0.211_903_5 * r + 0.680_699_5 * g + 0.107_396_96 * b).cbrt();
+    let s = (0.088_302_46 * r + 0.281_718_85 * g + 0.629_978_7 * b).cbrt();
+    [
+        0.210_454_26 * l + 0.793_617_8 * m - 0.004_072_047 * s,
+        1.977_998_5 * l - 2.428_592_2 * m + 0.450_593_7 * s,
+        0.025_904_037 * l + 0.782_771_77 * m - 0.808_675_77 * s,
+    ]
+}
+
+/// Convert an Oklab color back to sRGB, clipping it into gamut.
+fn from_oklab([lightness, a, b]: [f32; 3]) -> Rgb {
+    let l = lightness + 0.396_337_78 * a + 0.215_803_76 * b;
+    let m = lightness - 0.105_561_346 * a - 0.063_854_17 * b;
+    let s = lightness - 0.089_484_18 * a - 1.291_485_5 * b;
+    let (l, m, s) = (l * l * l, m * m * m, s * s * s);
+    rgb(
+        from_linear(4.076_741_7 * l - 3.307_711_6 * m + 0.230_969_94 * s),
+        from_linear(-1.268_438 * l + 2.609_757_4 * m - 0.341_319_38 * s),
+        from_linear(-0.004_196_086_3 * l - 0.703_418_6 * m + 1.707_614_7 * s),
+    )
+}
+
+/// Mix two colors in Oklab, so that the result looks in between them.
+///
+/// Arguments:
+///
+/// * `a` - the color at `t = 0`
+/// * `b` - the color at `t = 1`
+/// * `t` - how far from `a` towards `b`
+pub fn mix(a: Rgb, b: Rgb, t: f32) -> Rgb {
+    let (a, b) = (to_oklab(a), to_oklab(b));
+    from_oklab([
+        a[0] + (b[0] - a[0]) * t,
+        a[1] + (b[1] - a[1]) * t,
+        a[2] + (b[2] - a[2]) * t,
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(c: u32) -> Rgb {
+        let channel = |shift: u32| ((c >> shift) & 0xff) as f32 / 255.0;
+        rgb(channel(16), channel(8), channel(0))
+    }
+
+    fn assert_close(a: Rgb, b: Rgb, tolerance: f32) {
+        let error = (a.red - b.red)
+            .abs()
+            .max((a.green - b.green).abs())
+            .max((a.blue - b.blue).abs());
+        assert!(error < tolerance, "{:?} vs {:?}", a, b);
+    }
+
+    #[test]
+    fn colormaps_pass_through_their_samples() {
+        let viridis = Colormap::Viridis;
+        assert_close(viridis.sample(0.0), hex(0x440154), 1e-4);
+        assert_close(viridis.sample(0.5), hex(0x21918c), 1e-4);
+        assert_close(viridis.sample(1.0), hex(0xfde725), 1e-4);
+        // Out of range and missing values go to the ends.
+        assert_close(viridis.sample(-3.0), viridis.sample(0.0), 1e-6);
+        assert_close(viridis.sample(7.0), viridis.sample(1.0), 1e-6);
+        assert_close(viridis.sample(f32::NAN), viridis.sample(0.0), 1e-6);
+
+        // Every map gets steadily lighter from end to end.
+        let mut colormap = Colormap::Viridis;
+        for _ in 0..4 {
+            let lightness: Vec<f32> = (0..=32)
+                .map(|i| to_oklab(colormap.sample(i as f32 / 32.0))[0])
+                .collect();
+            assert!(
+                lightness.windows(2).all(|w| w[1] > w[0]),
+                "{} isn't monotonic",
+                colormap.name()
+            );
+            colormap = colormap.next();
+        }
+    }
+
+    #[test]
+    fn colors_survive_a_trip_through_oklab() {
+        for i in 0..=4 {
+            for j in 0..=4 {
+                for k in 0..=4 {
+                    let color = rgb(i as f32 / 4.0, j as f32 / 4.0, k as f32 / 4.0);
+                    assert_close(from_oklab(to_oklab(color)), color, 1e-3);
+                }
+            }
+        }
+        // White has full lightness and no hue.
+        let [lightness, a, b] = to_oklab(rgb(1.0, 1.0, 1.0));
+        assert!((lightness - 1.0).abs() < 1e-3 && a.abs() < 1e-3 && b.abs() < 1e-3);
+        // And mixing goes from one color to the other.
+        let (red, blue) = (rgb(1.0, 0.0, 0.0), rgb(0.0, 0.0, 1.0));
+        assert_close(mix(red, blue, 0.0), red, 1e-3);
+        assert_close(mix(red, blue, 1.0), blue, 1e-3);
+    }
+
+    #[test]
+    fn the_auto_range_skips_outliers() {
+        let mut values: Vec<f32> = (0..=100).map(|i| i as f32).collect();
+        values.extend([1e9, -1e9, f32::NAN, f32::INFINITY]);
+        let (low, high) = percentiles(&values);
+        let expected = (100.0 * AUTO_PERCENTILE).round();
+        assert!((low - expected).abs() <= 1.0, "{}", low);
+        assert!((high - (100.0 - expected)).abs() <= 1.0, "{}", high);
+        assert_eq!(percentiles(&[f32::NAN]), (0.0, 1.0));
+        assert_eq!(percentiles(&[]), (0.0, 1.0));
+    }
+}
@@ -0,0 +1,408 @@
+/*
+Conservation diagnostics for the galaxy simulation.
+
+An isolated gravitating system conserves its total energy, linear momentum and
+angular momentum, and its center of mass moves in a straight line. Watching
+how far those quantities wander is the easiest way to tell whether a run is
+physically sane, and to compare integrators and timesteps: a symplectic
+integrator keeps the energy oscillating around its starting value, while
+explicit Euler lets it climb steadily.
+
+Merges are inelastic, so kinetic energy is lost (as "heat") whenever particles
+merge; energy is only expected to be conserved between merges. External
+potentials (see potential.rs) count towards the potential energy, but they
+exert a net force and torque on the system, so momentum and angular momentum
+are no longer conserved with them on, and a rotating bar changes the energy
+too.
+
+`Diagnostics::measure` takes a snapshot of these quantities. They are summed in
+f64 so that large runs don't lose precision. `Telemetry` streams a snapshot per
+step to a CSV or JSON-lines file for plotting later.
+*/
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use nannou::prelude::*;
+
+use crate::gravity::{ForceSolver, Gravity};
+use crate::potential::{self, ExternalPotential};
+use crate::Particle;
+
+/// A snapshot of the conserved quantities of a system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Diagnostics {
+    /// The simulation time of the snapshot.
+    pub time: f64,
+    pub particle_count: usize,
+    /// How many of the particles are massless tracers.
+    pub tracer_count: usize,
+    pub total_mass: f32,
+    /// The total kinetic energy, sum of m * v^2 / 2.
+    pub kinetic: f32,
+    /// The total gravitational potential energy, counting each pair once,
+    /// plus each particle's energy in the external potentials.
+    pub potential: f32,
+    /// The total linear momentum, sum of m * v.
+    pub momentum: Vector3,
+    /// The total angular momentum about the origin, sum of m * (x cross v).
+    /// Flat systems only have a z component.
+    pub angular_momentum: Vector3,
+    pub center_of_mass: Vector3,
+}
+
+impl Diagnostics {
+    /// Measure the conserved quantities of a set of particles.
+    ///
+    /// The potential energy uses the same solver as the forces, so with the
+    /// Barnes-Hut or mesh solvers it is approximate too.
+    ///
+    /// Arguments:
+    ///
+    /// * `particles` - a snapshot of the particles in the system
+    /// * `solver` - the solver to compute the potential with
+    /// * `gravity` - the gravitational constant and softening
+    /// * `external` - the external potentials the particles are in
+    /// * `time` - the simulation time of the snapshot
+    pub fn measure(
+        particles: &[Particle],
+        solver: ForceSolver,
+        gravity: Gravity,
+        external: &[ExternalPotential],
+        time: f64,
+    ) -> Self {
+        let potentials = solver.potentials(particles, gravity);
+
+        let (mut mass, mut kinetic, mut potential) = (0.0f64, 0.0f64, 0.0f64);
+        let (mut momentum, mut angular, mut weighted) = ([0.0f64; 3], [0.0f64; 3], [0.0f64; 3]);
+        for (p, phi) in particles.iter().zip(potentials) {
+            let m = p.mass as f64;
+            let x = [
+                p.position.x as f64,
+                p.position.y as f64,
+                p.position.z as f64,
+            ];
+            let v = [
+                p.velocity.x as f64,
+                p.velocity.y as f64,
+                p.velocity.z as f64,
+            ];
+            mass += m;
+            kinetic += 0.5 * m * (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]);
+            // Each pair appears once from each side, hence the half.
+            potential += 0.5 * m * phi as f64;
+            potential += m * potential::energy_at(external, p.position, time, gravity) as f64;
+            for k in 0..3 {
+                // The kth component of x cross v uses the other two axes,
+                // in cyclic order.
+                let (a, b) = ((k + 1) % 3, (k + 2) % 3);
+                momentum[k] += m * v[k];
+                angular[k] += m * (x[a] * v[b] - x[b] * v[a]);
+                weighted[k] += m * x[k];
+            }
+        }
+        let vector = |v: [f64; 3]| vec3(v[0] as f32, v[1] as f32, v[2] as f32);
+        let center_of_mass = if mass > 0.0 {
+            vector(weighted.map(|c| c / mass))
+        } else {
+            vec3(0.0, 0.0, 0.0)
+        };
+
+        Diagnostics {
+            time,
+            particle_count: particles.len(),
+            tracer_count: particles.iter().filter(|p| p.is_tracer()).count(),
+            total_mass: mass as f32,
+            kinetic: kinetic as f32,
+            potential: potential as f32,
+            momentum: vector(momentum),
+            angular_momentum: vector(angular),
+            center_of_mass,
+        }
+    }
+
+    /// The total energy, kinetic plus potential.
+    pub fn energy(&self) -> f32 {
+        self.kinetic + self.potential
+    }
+
+    /// The lines of the on-screen readout.
+    ///
+    /// Arguments:
+    ///
+    /// * `initial_energy` - the energy at the start of the run, to show the
+    ///   drift against
+    pub fn hud_lines(&self, initial_energy: f32) -> Vec<String> {
+        let drift = if initial_energy != 0.0 {
+            (self.energy() - initial_energy) / initial_energy.abs()
+        } else {
+            0.0
+        };
+        vec![
+            format!("t        {:.4}", self.time),
+            format!(
+                "N        {}  ({} tracers)",
+                self.particle_count, self.tracer_count
+            ),
+            format!("mass     {:.4e}", self.total_mass),
+            format!("E kin    {:+.4e}", self.kinetic),
+            format!("E pot    {:+.4e}", self.potential),
+            format!("E total  {:+.4e}  (drift {:+.2e})", self.energy(), drift),
+            format!("p        {}", format_vector(self.momentum)),
+            format!("L        {}", format_vector(self.angular_momentum)),
+            format!("com      {}", format_vector(self.center_of_mass)),
+        ]
+    }
+}
+
+/// Write a vector for the readout.
+fn format_vector(v: Vector3) -> String {
+    format!("({:+.3e}, {:+.3e}, {:+.3e})", v.x, v.y, v.z)
+}
+
+/// Write a number for a JSON record: `null` if it's infinite or NaN, which
+/// JSON has no way to write.
+fn json_number<T: Copy + Into<f64> + fmt::LowerExp>(x: T) -> String {
+    if x.into().is_finite() {
+        format!("{:e}", x)
+    } else {
+        "null".to_string()
+    }
+}
+
+/// The file formats that telemetry can be written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TelemetryFormat {
+    /// Comma-separated values with a header row.
+    Csv,
+    /// One JSON object per line, with `null` for any quantity that has gone
+    /// infinite or NaN.
+    JsonLines,
+}
+
+/// A stream of diagnostics written to a file, one record per step.
+pub struct Telemetry {
+    writer: BufWriter<File>,
+    format: TelemetryFormat,
+}
+
+impl Telemetry {
+    /// Create a telemetry file.
+    ///
+    /// The format is picked from the extension: `.csv` for CSV, anything
+    /// else for JSON lines.
+    ///
+    /// Arguments:
+    ///
+    /// * `path` - where to write the file; it is overwritten if it exists
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some("csv") => TelemetryFormat::Csv,
+            _ => TelemetryFormat::JsonLines,
+        };
+        let mut writer = BufWriter::new(File::create(path)?);
+        if format == TelemetryFormat::Csv {
+            writeln!(
+                writer,
+                "time,particle_count,total_mass,kinetic,potential,energy,\
+                 momentum_x,momentum_y,momentum_z,angular_momentum_x,angular_momentum_y,\
+                 angular_momentum_z,com_x,com_y,com_z"
+            )?;
+        }
+        Ok(Telemetry { writer, format })
+    }
+
+    /// Append one snapshot to the file.
+    pub fn record(&mut self, d: &Diagnostics) -> io::Result<()> {
+        match self.format {
+            TelemetryFormat::Csv => writeln!(
+                self.writer,
+                "{},{},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e},{:e}",
+                d.time,
+                d.particle_count,
+                d.total_mass,
+                d.kinetic,
+                d.potential,
+                d.energy(),
+                d.momentum.x,
+                d.momentum.y,
+                d.momentum.z,
+                d.angular_momentum.x,
+                d.angular_momentum.y,
+                d.angular_momentum.z,
+                d.center_of_mass.x,
+                d.center_of_mass.y,
+                d.center_of_mass.z
+            ),
+            TelemetryFormat::JsonLines => writeln!(
+                self.writer,
+                "{{\"time\":{},\"particle_count\":{},\"total_mass\":{},\"kinetic\":{},\
+                 \"potential\":{},\"energy\":{},\"momentum\":[{},{},{}],\
+                 \"angular_momentum\":[{},{},{}],\"center_of_mass\":[{},{},{}]}}",
+                json_number(d.time),
+                d.particle_count,
+                json_number(d.total_mass),
+                json_number(d.kinetic),
+                json_number(d.potential),
+                json_number(d.energy()),
+                json_number(d.momentum.x),
+                json_number(d.momentum.y),
+                json_number(d.momentum.z),
+                json_number(d.angular_momentum.x),
+                json_number(d.angular_momentum.y),
+                json_number(d.angular_momentum.z),
+                json_number(d.center_of_mass.x),
+                json_number(d.center_of_mass.y),
+                json_number(d.center_of_mass.z)
+            ),
+        }
+    }
+
+    /// Make sure everything recorded so far is on disk.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::store::ParticleStore;
+
+    /// A scratch file in the temporary directory, deleted when dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let file = format!("nannou-galaxy-{}-{}", std::process::id(), name);
+            Scratch(std::env::temp_dir().join(file))
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    /// Two bodies of mass 1 and 3, 4 apart on the x axis, crossing it in
+    /// opposite directions. They're stored so they get ids of their own.
+    fn two_bodies() -> ParticleStore {
+        let mut bodies = ParticleStore::new();
+        bodies.insert(Particle::new(
+            vec3(-3.0, 0.0, 0.0),
+            vec3(0.0, 3.0, 0.0),
+            1.0,
+            0.1,
+        ));
+        bodies.insert(Particle::new(
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, -1.0, 0.5),
+            3.0,
+            0.1,
+        ));
+        bodies
+    }
+
+    fn gravity() -> Gravity {
+        Gravity {
+            g: 2.0,
+            softening: 0.0,
+            period: None,
+        }
+    }
+
+    #[test]
+    fn two_bodies_add_up() {
+        let d = Diagnostics::measure(
+            two_bodies().as_slice(),
+            ForceSolver::Direct,
+            gravity(),
+            &[],
+            1.5,
+        );
+        assert_eq!(d.time, 1.5);
+        assert_eq!((d.particle_count, d.tracer_count), (2, 0));
+        assert_eq!(d.total_mass, 4.0);
+        // (1 * 9 + 3 * 1.25) / 2, and -G * 1 * 3 / 4 counted once.
+        assert!((d.kinetic - 6.375).abs() < 1e-5, "{}", d.kinetic);
+        assert!((d.potential + 1.5).abs() < 1e-5, "{}", d.potential);
+        assert!((d.energy() - 4.875).abs() < 1e-5);
+        assert_eq!(d.momentum, vec3(0.0, 0.0, 1.5));
+        // -3 x (0, 3, 0) + 3 * (1 x (0, -1, 0.5)).
+        assert_eq!(d.angular_momentum, vec3(0.0, -1.5, -12.0));
+        assert_eq!(d.center_of_mass, vec3(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn csv_telemetry_has_a_column_per_quantity() {
+        let scratch = Scratch::new("telemetry.csv");
+        let d = Diagnostics::measure(
+            two_bodies().as_slice(),
+            ForceSolver::Direct,
+            gravity(),
+            &[],
+            1.5,
+        );
+        let mut telemetry = Telemetry::create(&scratch.0).unwrap();
+        telemetry.record(&d).unwrap();
+        telemetry.record(&d).unwrap();
+        telemetry.flush().unwrap();
+
+        let text = std::fs::read_to_string(&scratch.0).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        let header: Vec<&str> = lines[0].split(',').collect();
+        let row: Vec<f64> = lines[1]
+            .split(',')
+            .map(|field| field.parse().unwrap())
+            .collect();
+        assert_eq!(header.len(), row.len());
+        let column = |name: &str| row[header.iter().position(|&h| h == name).unwrap()];
+        assert_eq!(column("time"), 1.5);
+        assert_eq!(column("particle_count"), 2.0);
+        assert_eq!(column("energy"), d.energy() as f64);
+        assert_eq!(column("momentum_z"), 1.5);
+        assert_eq!(column("angular_momentum_z"), -12.0);
+        assert_eq!(lines[2], lines[1]);
+    }
+
+    #[test]
+    fn json_telemetry_writes_non_finite_values_as_null() {
+        let scratch = Scratch::new("telemetry.jsonl");
+        let d = Diagnostics::measure(
+            two_bodies().as_slice(),
+            ForceSolver::Direct,
+            gravity(),
+            &[],
+            1.5,
+        );
+        let blown_up = Diagnostics {
+            kinetic: f32::INFINITY,
+            momentum: vec3(f32::NAN, 0.0, 0.0),
+            ..d
+        };
+        let mut telemetry = Telemetry::create(&scratch.0).unwrap();
+        telemetry.record(&d).unwrap();
+        telemetry.record(&blown_up).unwrap();
+        telemetry.flush().unwrap();
+
+        let text = std::fs::read_to_string(&scratch.0).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("{\"time\":1.5e0,\"particle_count\":2,"));
+        assert!(lines[0].contains("\"momentum\":[0e0,0e0,1.5e0]"));
+        assert!(lines[0].ends_with('}'));
+        assert!(lines[1].contains("\"kinetic\":null,"));
+        assert!(lines[1].contains("\"energy\":null,"));
+        assert!(lines[1].contains("\"momentum\":[null,0e0,0e0]"));
+        for line in lines {
+            assert!(!line.contains("NaN") && !line.contains("inf"), "{}", line);
+        }
+    }
+}
@@ -0,0 +1,460 @@
+/*
+Force solvers for the galaxy simulation.
+
+A solver takes a snapshot of every particle and returns the gravitational
+acceleration on each one. We keep four:
+
+* `Direct` sums over every pair of particles. It is exact and O(N^2), so it is
+  the reference the other solvers are measured against.
+* `BarnesHut` builds an octree (see octree.rs) and approximates far-away
+  groups of particles by their center of mass. It is O(N log N).
+* `ParticleMesh` spreads the mass over a grid and solves for its potential
+  with FFTs (see mesh.rs). It costs O(N + M log M) for M cells, so it is the
+  one for a million particles, but blurs gravity over a cell or two.
+* `P3m` adds the pull of near neighbours directly on top of a smoothed mesh,
+  so close pairs are right again, at the cost of a sum over each particle's
+  neighbourhood.
+
+All of them use Plummer softening: the pull between two particles at distance r is
+
+    a = G * m * r / (r^2 + epsilon^2)^(3/2)
+
+which is ordinary gravity far away, but stays finite as r goes to zero, so
+very close passes no longer fling particles across the screen. (The mesh can't
+resolve anything smaller than a cell, whatever the softening.) A particle never
+pulls on itself: the direct sum skips it by its id, and the tree removes it
+from the leaf it lives in.
+
+Only massive bodies pull. Massless tracers (see `Particle::is_tracer`) feel
+the field but are left out of every sum and out of the tree, so with N_massive
+bodies among N particles the direct sum costs O(N_massive * N) rather than
+O(N^2), and a disk of a hundred thousand tracer stars around a few dozen
+heavy bodies is cheap.
+
+In a periodic box (see boundary.rs) each particle feels the nearest image of
+every other body, and of every cell in the tree: the minimum-image convention.
+
+Every solver evaluates particles in parallel with rayon. Each particle only
+reads the shared snapshot and writes its own result, and its sum runs over the
+others in a fixed order, so runs reproduce exactly for any thread count.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+
+use nannou::prelude::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::boundary::minimum_image;
+use crate::mesh::{self, Mesh, DEFAULT_GRID, MAX_GRID, MIN_GRID};
+use crate::octree::Octree;
+use crate::Particle;
+
+/// The constants that set the strength and shape of gravity.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gravity {
+    /// The gravitational constant.
+    pub g: f32,
+    /// The Plummer softening length epsilon; 0 is unsoftened gravity.
+    pub softening: f32,
+    /// The side of the periodic box, if space wraps around; each body then
+    /// pulls from its nearest image.
+    pub period: Option<f32>,
+}
+
+/// Which algorithm to use when computing gravity.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ForceSolver {
+    /// The exact sum over every massive body.
+    Direct,
+    /// The Barnes-Hut tree approximation with opening angle `theta`.
+    BarnesHut { theta: f32 },
+    /// A particle-mesh solver on a grid `grid` cells across, a power of two.
+    ParticleMesh { grid: u32 },
+    /// The particle-mesh solver for the long range, with near neighbours'
+    /// pulls added directly.
+    P3m { grid: u32 },
+}
+
+impl Default for ForceSolver {
+    fn default() -> Self {
+        ForceSolver::BarnesHut { theta: 0.5 }
+    }
+}
+
+impl ForceSolver {
+    /// The solver after this one, for cycling through them with a key. The
+    /// mesh solvers keep their grid between them.
+    pub fn next(&self) -> Self {
+        match *self {
+            ForceSolver::Direct => ForceSolver::default(),
+            ForceSolver::BarnesHut { .. } => ForceSolver::ParticleMesh { grid: DEFAULT_GRID },
+            ForceSolver::ParticleMesh { grid } => ForceSolver::P3m { grid },
+            ForceSolver::P3m { .. } => ForceSolver::Direct,
+        }
+    }
+
+    /// Compute the gravitational acceleration on every particle.
+    ///
+    /// The particles are a read-only snapshot for the whole evaluation, and
+    /// each acceleration is written to its own slot of `out`, so the work is
+    /// split across rayon's threads without any locking. Every particle's sum
+    /// is still taken over the others in the same fixed order, so the result
+    /// is bit-for-bit the same however many threads there are.
+    ///
+    /// Arguments:
+    ///
+    /// * `particles` - a snapshot of the particles in the system
+    /// * `gravity` - the gravitational constant and softening
+    /// * `out` - receives the acceleration on each particle, in the same
+    ///   order; its allocation is reused from call to call
+    pub fn accelerations_into(
+        &self,
+        particles: &[Particle],
+        gravity: Gravity,
+        out: &mut Vec<Vector3>,
+    ) {
+        self.accelerations_on(
+            particles,
+            (0..particles.len()).into_par_iter(),
+            gravity,
+            out,
+        );
+    }
+
+    /// Compute the gravitational acceleration on some of the particles, due
+    /// to all of them, e.g. on the ones whose block timestep ends now (see
+    /// timestep.rs). Each acceleration is exactly what `accelerations_into`
+    /// would give for that particle.
+    ///
+    /// Arguments:
+    ///
+    /// * `particles` - a snapshot of the particles in the system
+    /// * `targets` - the indices of the particles to compute the acceleration
+    ///   on
+    /// * `gravity` - the gravitational constant and softening
+    /// * `out` - receives the acceleration on each target, in the order of
+    ///   `targets`
+    pub fn accelerations_of(
+        &self,
+        particles: &[Particle],
+        targets: &[usize],
+        gravity: Gravity,
+        out: &mut Vec<Vector3>,
+    ) {
+        self.accelerations_on(particles, targets.par_iter().copied(), gravity, out);
+    }
+
+    /// Compute the acceleration on each particle whose index comes out of
+    /// `targets`, into `out` in the same order.
+    fn accelerations_on<I>(
+        &self,
+        particles: &[Particle],
+        targets: I,
+        gravity: Gravity,
+        out: &mut Vec<Vector3>,
+    ) where
+        I: IndexedParallelIterator<Item = usize>,
+    {
+        match *self {
+            ForceSolver::Direct => {
+                let sources = massive(particles);
+                targets
+                    .map(|i| direct_acceleration(&sources, &particles[i], gravity))
+                    .collect_into_vec(out);
+            }
+            ForceSolver::BarnesHut { theta } => {
+                let (tree, slots) = massive_tree(particles, gravity.period);
+                let softening = gravity.softening;
+                targets
+                    .map(|i| {
+                        let a = match slots[i] {
+                            Some(body) => tree.acceleration(body, theta, softening),
+                            None => tree.acceleration_at(particles[i].position, theta, softening),
+                        };
+                        a * gravity.g
+                    })
+                    .collect_into_vec(out);
+            }
+            ForceSolver::ParticleMesh { grid } | ForceSolver::P3m { grid } => {
+                let mesh = particle_mesh(particles, grid, gravity, self.short_range());
+                targets
+                    .map(|i| mesh.acceleration(i) * gravity.g)
+                    .collect_into_vec(out);
+            }
+        }
+    }
+
+    /// Compute the gravitational potential at every particle due to all the
+    /// others, with the same approximation used for the accelerations.
+    ///
+    /// Arguments:
+    ///
+    /// * `particles` - a snapshot of the particles in the system
+    /// * `gravity` - the gravitational constant and softening
+    ///
+    /// Returns:
+    ///
+    /// * `Vec<f32>` - the potential at each particle, in the same order
+    pub fn potentials(&self, particles: &[Particle], gravity: Gravity) -> Vec<f32> {
+        match *self {
+            ForceSolver::Direct => {
+                let epsilon2 = gravity.softening * gravity.softening;
+                let sources = massive(particles);
+                particles
+                    .par_iter()
+                    .map(|p| {
+                        let mut potential = 0.0;
+                        for other in sources.iter() {
+                            let r = minimum_image(other.position - p.position, gravity.period);
+                            let r2 = r.magnitude2() + epsilon2;
+                            if other.id != p.id && r2 > 0.0 {
+                                potential -= gravity.g * other.mass / r2.sqrt();
+                            }
+                        }
+                        potential
+                    })
+                    .collect()
+            }
+            ForceSolver::BarnesHut { theta } => {
+                let (tree, slots) = massive_tree(particles, gravity.period);
+                let softening = gravity.softening;
+                (0..particles.len())
+                    .into_par_iter()
+                    .map(|i| {
+                        let phi = match slots[i] {
+                            Some(body) => tree.potential(body, theta, softening),
+                            None => tree.potential_at(particles[i].position, theta, softening),
+                        };
+                        phi * gravity.g
+                    })
+                    .collect()
+            }
+            ForceSolver::ParticleMesh { grid } | ForceSolver::P3m { grid } => {
+                let mesh = particle_mesh(particles, grid, gravity, self.short_range());
+                (0..particles.len())
+                    .into_par_iter()
+                    .map(|i| mesh.potential(i) * gravity.g)
+                    .collect()
+            }
+        }
+    }
+
+    /// Whether this solver adds up the short-range pulls directly.
+    fn short_range(&self) -> bool {
+        matches!(self, ForceSolver::P3m { .. })
+    }
+}
+
+/// Write a solver in the form `FromStr` reads.
+impl fmt::Display for ForceSolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ForceSolver::Direct => write!(f, "direct"),
+            ForceSolver::BarnesHut { theta } => write!(f, "barnes-hut:theta={}", theta),
+            ForceSolver::ParticleMesh { grid } => write!(f, "pm:grid={}", grid),
+            ForceSolver::P3m { grid } => write!(f, "p3m:grid={}", grid),
+        }
+    }
+}
+
+/// Read a solver written as `direct`, `barnes-hut:theta=0.5`, `pm:grid=128`
+/// or `p3m:grid=64`. Anything left out takes its default; the grid has to be
+/// a power of two, from 16 to 128.
+impl FromStr for ForceSolver {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (kind, parameters) = match s.split_once(':') {
+            Some((kind, parameters)) => (kind, parameters),
+            None => (s, ""),
+        };
+        let (mut theta, mut grid) = (0.5, DEFAULT_GRID);
+        for parameter in parameters.split(',').filter(|p| !p.trim().is_empty()) {
+            let (name, value) = parameter
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=value, got {:?}", parameter))?;
+            let value = value.trim();
+            match name.trim() {
+                "theta" => {
+                    theta = value
+                        .parse()
+                        .map_err(|e| format!("bad value for theta: {}", e))?
+                }
+                "grid" => {
+                    grid = value
+                        .parse()
+                        .map_err(|e| format!("bad value for grid: {}", e))?;
+                    if !(MIN_GRID..=MAX_GRID).contains(&grid) || !grid.is_power_of_two() {
+                        return Err(format!(
+                            "grid must be a power of two from {} to {}, got {} \
+                             (a mesh of {} cells already needs {} MB while it solves, \
+                             and each doubling needs eight times as much)",
+                            MIN_GRID,
+                            MAX_GRID,
+                            grid,
+                            MAX_GRID,
+                            mesh::scratch_bytes(MAX_GRID) >> 20
+                        ));
+                    }
+                }
+                other => return Err(format!("unknown parameter {:?}", other)),
+            }
+        }
+        match kind.trim().to_lowercase().as_str() {
+            "direct" => Ok(ForceSolver::Direct),
+            "barnes-hut" | "tree" => Ok(ForceSolver::BarnesHut { theta }),
+            "pm" => Ok(ForceSolver::ParticleMesh { grid }),
+            "p3m" => Ok(ForceSolver::P3m { grid }),
+            other => Err(format!(
+                "unknown solver {:?} (expected direct, barnes-hut, pm or p3m)",
+                other
+            )),
+        }
+    }
+}
+
+/// The particles that pull on others: every one but the tracers.
+fn massive(particles: &[Particle]) -> Vec<&Particle> {
+    particles.iter().filter(|p| !p.is_tracer()).collect()
+}
+
+/// Build a tree over the massive particles.
+///
+/// Arguments:
+///
+/// * `particles` - a snapshot of the particles in the system
+/// * `period` - the side of the periodic box, if space wraps around
+///
+/// Returns:
+///
+/// * `(Octree, Vec<Option<usize>>)` - the tree, and for each particle its
+///   index in the tree, or None for a tracer
+fn massive_tree(particles: &[Particle], period: Option<f32>) -> (Octree, Vec<Option<usize>>) {
+    let mut slots = vec![None; particles.len()];
+    let (mut positions, mut masses) = (Vec::new(), Vec::new());
+    for (i, p) in particles.iter().enumerate().filter(|(_, p)| !p.is_tracer()) {
+        slots[i] = Some(positions.len());
+        positions.push(p.position);
+        masses.push(p.mass);
+    }
+    (Octree::new(positions, masses, period), slots)
+}
+
+/// Solve for the potential of the massive particles on a mesh.
+///
+/// Arguments:
+///
+/// * `particles` - a snapshot of the particles in the system
+/// * `grid` - how many cells across the mesh is
+/// * `gravity` - the softening and period
+/// * `short_range` - whether to leave the short range to a direct sum (P3M)
+fn particle_mesh(particles: &[Particle], grid: u32, gravity: Gravity, short_range: bool) -> Mesh {
+    let positions = particles.iter().map(|p| p.position).collect();
+    let masses = particles.iter().map(|p| p.mass).collect();
+    Mesh::new(
+        positions,
+        masses,
+        grid,
+        gravity.softening,
+        gravity.period,
+        short_range,
+    )
+}
+
+/// The exact sum of the pulls on one particle from every massive body.
+fn direct_acceleration(sources: &[&Particle], p: &Particle, gravity: Gravity) -> Vector3 {
+    let epsilon2 = gravity.softening * gravity.softening;
+    let mut acceleration = vec3(0.0, 0.0, 0.0);
+    for other in sources.iter() {
+        if other.id == p.id {
+            continue;
+        }
+        // a = G * m / r^2, pointing towards the other particle.
+        let r = minimum_image(other.position - p.position, gravity.period);
+        let r2 = r.magnitude2() + epsilon2;
+        if r2 == 0.0 {
+            continue;
+        }
+        acceleration += r * (gravity.g * other.mass / (r2 * r2.sqrt()));
+    }
+    acceleration
+}
+
+/// The gravitational field of a snapshot of the massive particles, for
+/// probing at many points in space (e.g. along the path a new body would
+/// take).
+///
+/// The tree is built once, in O(N log N), and then each probe costs O(log N),
+/// where summing over every body would cost O(N).
+pub struct Field {
+    tree: Octree,
+    gravity: Gravity,
+    theta: f32,
+}
+
+impl Field {
+    /// Build the field of the massive particles.
+    ///
+    /// Arguments:
+    ///
+    /// * `particles` - a snapshot of the particles in the system
+    /// * `gravity` - the gravitational constant, softening and period
+    /// * `theta` - the Barnes-Hut opening angle the probes use
+    pub fn new(particles: &[Particle], gravity: Gravity, theta: f32) -> Self {
+        Field {
+            tree: massive_tree(particles, gravity.period).0,
+            gravity,
+            theta,
+        }
+    }
+
+    /// The gravitational acceleration at a point in space.
+    pub fn at(&self, point: Vector3) -> Vector3 {
+        self.tree
+            .acceleration_at(point, self.theta, self.gravity.softening)
+            * self.gravity.g
+    }
+}
+
+/// How far a solver's accelerations are from the direct sum.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SolverError {
+    /// The mean relative error over all particles.
+    pub mean: f32,
+    /// The worst relative error of any particle.
+    pub max: f32,
+}
+
+/// Measure the accuracy of a solver against the direct O(N^2) sum.
+///
+/// This is as slow as the direct sum, so call it occasionally (e.g. on a key
+/// press) rather than every frame.
+///
+/// Arguments:
+///
+/// * `solver` - the solver to test
+/// * `particles` - a snapshot of the particles in the system
+/// * `gravity` - the gravitational constant and softening
+pub fn solver_error(solver: ForceSolver, particles: &[Particle], gravity: Gravity) -> SolverError {
+    let mut exact = Vec::new();
+    let mut approx = Vec::new();
+    ForceSolver::Direct.accelerations_into(particles, gravity, &mut exact);
+    solver.accelerations_into(particles, gravity, &mut approx);
+
+    let mut error = SolverError::default();
+    for (a, b) in exact.iter().zip(approx.iter()) {
+        let magnitude = a.magnitude();
+        if magnitude == 0.0 {
+            continue;
+        }
+        let relative = (*b - *a).magnitude() / magnitude;
+        error.mean += relative;
+        error.max = error.max.max(relative);
+    }
+    if !exact.is_empty() {
+        error.mean /= exact.len() as f32;
+    }
+    error
+}
@@ -0,0 +1,359 @@
+/*
+Snapshot history for the galaxy simulation.
+
+When something interesting happens it is gone a frame later, so the viewer
+keeps a history of the system: every `stride` updates it takes a copy of the
+whole `ParticleSystem` (particles, ids, time, random state and settings) and
+keeps the latest `depth` of them in a ring buffer, the oldest falling off the
+end. Big systems keep fewer, so the history never holds much more than
+`MEMORY_BUDGET` bytes, counting everything a snapshot copies: the particles,
+their ids and merge records, the external potentials and any parked
+particles.
+
+Playback can be paused, stepped one update at a time, rewound, and scrubbed
+with the timeline bar along the bottom of the window. Looking back doesn't
+lose where the live simulation had got to: the live state is added to the
+history first. Resuming from an earlier snapshot forks the timeline there:
+every snapshot after it is thrown away, and the simulation carries on from
+that state. The random state is part of the snapshot, so resuming without
+changing anything replays the same future; add a body or flip a setting first
+to send it somewhere new.
+*/
+
+use std::collections::VecDeque;
+
+use nannou::prelude::*;
+
+use crate::ParticleSystem;
+
+/// Roughly how many bytes of snapshots the history may hold.
+const MEMORY_BUDGET: usize = 1 << 30;
+
+/// How far from the bottom of the window the timeline bar is, in pixels.
+const BAR_HEIGHT: f32 = 8.0;
+
+/// How much history to keep.
+#[derive(Clone, Copy, Debug)]
+pub struct HistorySettings {
+    /// How many snapshots to keep at most.
+    pub depth: usize,
+    /// How many updates apart the snapshots are.
+    pub stride: usize,
+}
+
+impl Default for HistorySettings {
+    fn default() -> Self {
+        HistorySettings {
+            depth: 200,
+            stride: 5,
+        }
+    }
+}
+
+/// Whether the simulation is running, stopped, or running backwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Playback {
+    Playing,
+    Paused,
+    /// Stepping back through the history, one snapshot per frame.
+    Rewinding,
+}
+
+/// A ring buffer of snapshots of a system, and which one is being looked at.
+pub struct History {
+    settings: HistorySettings,
+    /// The snapshots, oldest first.
+    snapshots: VecDeque<ParticleSystem>,
+    /// Roughly how many bytes the snapshots take between them.
+    bytes: usize,
+    /// How many bytes they may take before the oldest are dropped:
+    /// `MEMORY_BUDGET`, except in tests.
+    budget: usize,
+    /// How many updates there have been since the last snapshot.
+    since_snapshot: usize,
+    /// The snapshot being looked at, or None when looking at the live system.
+    cursor: Option<usize>,
+}
+
+impl History {
+    /// Create an empty history.
+    pub fn new(settings: HistorySettings) -> Self {
+        History {
+            settings: HistorySettings {
+                depth: settings.depth.max(1),
+                stride: settings.stride.max(1),
+            },
+            snapshots: VecDeque::new(),
+            bytes: 0,
+            budget: MEMORY_BUDGET,
+            since_snapshot: 0,
+            cursor: None,
+        }
+    }
+
+    /// Forget everything and start again from a new system.
+    pub fn reset(&mut self, system: &ParticleSystem) {
+        self.snapshots.clear();
+        self.bytes = 0;
+        self.cursor = None;
+        self.push(system);
+    }
+
+    /// Whether the live system is being looked at, rather than a snapshot.
+    pub fn is_live(&self) -> bool {
+        self.cursor.is_none()
+    }
+
+    /// Note that the live system has been updated, taking a snapshot of it
+    /// every `stride` updates.
+    pub fn record(&mut self, system: &ParticleSystem) {
+        self.since_snapshot += 1;
+        if self.since_snapshot >= self.settings.stride {
+            self.push(system);
+        }
+    }
+
+    /// The snapshot before the one being looked at, if there is one.
+    ///
+    /// Arguments:
+    ///
+    /// * `live` - the live system, added to the history if this leaves it
+    pub fn earlier(&mut self, live: &ParticleSystem) -> Option<&ParticleSystem> {
+        let i = self.pin(live);
+        if i == 0 {
+            return None;
+        }
+        self.show(i - 1)
+    }
+
+    /// The snapshot after the one being looked at, if there is one.
+    pub fn later(&mut self) -> Option<&ParticleSystem> {
+        let i = self.cursor?;
+        if i + 1 >= self.snapshots.len() {
+            return None;
+        }
+        self.show(i + 1)
+    }
+
+    /// The snapshot a fraction of the way along the history, from 0 (the
+    /// oldest) to 1 (the newest), if it isn't the one already being looked
+    /// at.
+    ///
+    /// Arguments:
+    ///
+    /// * `fraction` - how far along the history to look
+    /// * `live` - the live system, added to the history if this leaves it
+    pub fn scrub(&mut self, fraction: f32, live: &ParticleSystem) -> Option<&ParticleSystem> {
+        let current = self.pin(live);
+        let last = self.snapshots.len() - 1;
+        let i = (fraction.clamp(0.0, 1.0) * last as f32).round() as usize;
+        if i == current {
+            return None;
+        }
+        self.show(i)
+    }
+
+    /// Carry on from the snapshot being looked at, throwing away every later
+    /// one, so the simulation starts a new timeline from there.
+    pub fn fork(&mut self) {
+        if let Some(i) = self.cursor.take() {
+            self.snapshots.truncate(i + 1);
+            self.bytes = self.snapshots.iter().map(ParticleSystem::heap_size).sum();
+            self.since_snapshot = 0;
+        }
+    }
+
+    /// Draw the timeline bar along the bottom of the window, with a marker at
+    /// the snapshot being looked at, and what playback is doing above it.
+    ///
+    /// Arguments:
+    ///
+    /// * `draw` - the draw context
+    /// * `window` - the window's rectangle
+    /// * `playback` - what playback is doing
+    /// * `shown` - the system being shown, live or from a snapshot
+    pub fn draw(&self, draw: &Draw, window: Rect, playback: Playback, shown: &ParticleSystem) {
+        let (left, right) = bar_ends(window);
+        let y = window.bottom() + BAR_HEIGHT;
+        draw.line()
+            .start(vec2(left, y))
+            .end(vec2(right, y))
+            .weight(4.0)
+            .color(rgba(1.0, 1.0, 1.0, 0.2));
+        let last = self.snapshots.len().saturating_sub(1).max(1);
+        let at = self.cursor.unwrap_or(last) as f32 / last as f32;
+        let x = left + (right - left) * at;
+        draw.line()
+            .start(vec2(left, y))
+            .end(vec2(x, y))
+            .weight(4.0)
+            .color(rgba(1.0, 1.0, 1.0, 0.6));
+        draw.ellipse().x_y(x, y).radius(5.0).color(WHITE);
+
+        let state = match playback {
+            Playback::Playing => "playing",
+            Playback::Paused => "paused",
+            Playback::Rewinding => "rewinding",
+        };
+        let oldest = self.snapshots.front().map_or(shown.time, |s| s.time);
+        let position = match self.cursor {
+            Some(i) => format!("snapshot {} of {}", i + 1, self.snapshots.len()),
+            None => "live".to_string(),
+        };
+        draw.text(&format!(
+            "{}  t {:.4} {} (history from {:.4}), {}",
+            state,
+            shown.time,
+            shown.units.time.symbol(),
+            oldest,
+            position
+        ))
+        .x_y(0.0, window.top() - 10.0)
+        .w_h(500.0, 14.0)
+        .font_size(11)
+        .color(WHITE);
+    }
+
+    /// How far along the timeline bar a point in the window is, from 0 to 1,
+    /// or None if it isn't on the bar.
+    pub fn hit(&self, window: Rect, point: Point2) -> Option<f32> {
+        let (left, right) = bar_ends(window);
+        let y = window.bottom() + BAR_HEIGHT;
+        if (point.y - y).abs() > 8.0 || point.x < left - 8.0 || point.x > right + 8.0 {
+            return None;
+        }
+        Some(((point.x - left) / (right - left)).clamp(0.0, 1.0))
+    }
+
+    /// Make sure a snapshot is being looked at, taking one of the live system
+    /// if need be, and return its index.
+    fn pin(&mut self, live: &ParticleSystem) -> usize {
+        if let Some(i) = self.cursor {
+            return i;
+        }
+        if self.since_snapshot > 0 || self.snapshots.is_empty() {
+            self.push(live);
+        }
+        let i = self.snapshots.len() - 1;
+        self.cursor = Some(i);
+        i
+    }
+
+    /// Look at a snapshot.
+    fn show(&mut self, i: usize) -> Option<&ParticleSystem> {
+        self.cursor = Some(i);
+        self.snapshots.get(i)
+    }
+
+    /// Add a snapshot, dropping the oldest ones if there are too many, or
+    /// they take too much memory. The two newest are always kept.
+    fn push(&mut self, system: &ParticleSystem) {
+        let snapshot = system.clone();
+        self.bytes += snapshot.heap_size();
+        self.snapshots.push_back(snapshot);
+        self.since_snapshot = 0;
+        while self.snapshots.len() > 2
+            && (self.snapshots.len() > self.settings.depth || self.bytes > self.budget)
+        {
+            if let Some(oldest) = self.snapshots.pop_front() {
+                self.bytes -= oldest.heap_size();
+            }
+            if let Some(i) = self.cursor.as_mut() {
+                *i = i.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Where the timeline bar starts and ends, leaving room for the color legend.
+fn bar_ends(window: Rect) -> (f32, f32) {
+    (window.left() + 20.0, window.right() - 160.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::UnitSystem;
+    use crate::Particle;
+
+    /// A system of a few particles at a given time.
+    fn system(time: f64) -> ParticleSystem {
+        let mut system = ParticleSystem::new(UnitSystem::SOLAR);
+        for i in 0..4 {
+            let x = i as f32;
+            system.add_particle(Particle::new(
+                vec3(x, 0.0, 0.0),
+                vec3(0.0, x, 0.0),
+                1.0,
+                0.1,
+            ));
+        }
+        system.time = time;
+        system
+    }
+
+    /// The times of the snapshots, oldest first, checking on the way that
+    /// the byte count adds up.
+    fn times(history: &History) -> Vec<f64> {
+        let bytes: usize = history.snapshots.iter().map(|s| s.heap_size()).sum();
+        assert_eq!(history.bytes, bytes);
+        history.snapshots.iter().map(|s| s.time).collect()
+    }
+
+    #[test]
+    fn only_the_latest_depth_snapshots_are_kept() {
+        let mut history = History::new(HistorySettings {
+            depth: 3,
+            stride: 2,
+        });
+        history.reset(&system(0.0));
+        for i in 1..=10 {
+            history.record(&system(i as f64));
+        }
+        assert_eq!(times(&history), vec![6.0, 8.0, 10.0]);
+    }
+
+    #[test]
+    fn snapshots_are_dropped_to_stay_in_the_memory_budget() {
+        let mut history = History::new(HistorySettings {
+            depth: 100,
+            stride: 1,
+        });
+        let size = system(0.0).heap_size();
+        history.budget = 3 * size + size / 2;
+        for i in 0..10 {
+            history.record(&system(i as f64));
+        }
+        assert_eq!(times(&history), vec![7.0, 8.0, 9.0]);
+
+        // However small the budget, the two newest stay.
+        history.budget = 1;
+        history.record(&system(10.0));
+        assert_eq!(times(&history), vec![9.0, 10.0]);
+    }
+
+    #[test]
+    fn forking_drops_the_later_snapshots() {
+        let mut history = History::new(HistorySettings {
+            depth: 100,
+            stride: 1,
+        });
+        for i in 0..6 {
+            history.record(&system(i as f64));
+        }
+        // Step back from the live system, which is already the newest
+        // snapshot, to the one before it, then scrub back to the middle.
+        let live = system(5.0);
+        assert_eq!(history.earlier(&live).map(|s| s.time), Some(4.0));
+        assert_eq!(history.scrub(0.4, &live).map(|s| s.time), Some(2.0));
+        assert!(!history.is_live());
+
+        history.fork();
+        assert!(history.is_live());
+        assert_eq!(times(&history), vec![0.0, 1.0, 2.0]);
+
+        // The new timeline carries on from there.
+        history.record(&system(2.5));
+        assert_eq!(times(&history), vec![0.0, 1.0, 2.0, 2.5]);
+    }
+}
@@ -0,0 +1,656 @@
+/*
+Initial-condition generators for the galaxy simulation.
+
+Each generator adds a family of particles to a `ParticleSystem`, set up so that
+it starts close to equilibrium instead of collapsing or flying apart on the
+first step. Every generator takes a parameter struct (whose `Default` is a
+sensible starting point) and a seed, so the same seed always builds the same
+scene.
+
+* `exponential_disk`: a central mass surrounded by a rotating disk of stars
+  whose surface density falls off as exp(-R / scale_length). Each star moves at
+  the circular speed set by the central mass plus the disk mass inside its
+  orbit, and by the inward pull of any external potentials already in the
+  system (such as a dark-matter halo, see potential.rs), with a little random
+  dispersion. The disk can also carry massless tracer stars, drawn from the
+  same profile, which show its shape without adding to the cost of gravity.
+  They don't count towards the disk mass.
+* `plummer_sphere`: a star cluster with the Plummer density profile, sampled
+  with the method of Aarseth, Henon & Wielen (1974) and then rescaled so that
+  2 * kinetic + potential = 0 (virial equilibrium) in this simulation. It is
+  a real sphere, in 3D.
+* `kepler_system`: a star with planets on Keplerian orbits of given spacing and
+  eccentricity, the star recoiling so the whole system doesn't drift. The star
+  and planets are sized by their material (see material.rs): light planets
+  are rocky and heavy ones gas giants.
+* `galaxy_merger`: two disks on a collision course, with a relative velocity,
+  an impact parameter, and each disk's own inclination.
+
+Disks and planetary systems are flat. A disk is built in the x-y plane and
+then tilted by its inclination i about a line in that plane (the line of
+nodes, at its position angle from the x axis), so an inclined disk really does
+stand out of the plane. Past 90 degrees it turns over, and i = 180 degrees
+gives an exactly retrograde disk. With no inclination the whole scene stays in
+the x-y plane.
+
+The defaults for the disks and the cluster are in `UnitSystem::GALACTIC`
+(parsecs, solar masses, megayears) and for the planets in `UnitSystem::SOLAR`.
+*/
+
+use nannou::prelude::*;
+
+use crate::diagnostics::Diagnostics;
+use crate::gravity::ForceSolver;
+use crate::material::{Material, SUN_TEMPERATURE};
+use crate::potential;
+use crate::rng::Rng;
+use crate::store::ParticleStore;
+use crate::{Particle, ParticleSystem};
+
+/// The color of ordinary stars.
+fn star_color() -> Rgb {
+    rgb(0.8, 0.85, 1.0)
+}
+
+/// The color of central masses and host stars.
+fn central_color() -> Rgb {
+    rgb(1.0, 0.85, 0.55)
+}
+
+/// Rotate a vector counter-clockwise by `angle` radians about the z axis.
+fn rotate(v: Vector3, angle: f32) -> Vector3 {
+    let (sin, cos) = angle.sin_cos();
+    vec3(v.x * cos - v.y * sin, v.x * sin + v.y * cos, v.z)
+}
+
+/// Tilt a vector by `angle` radians about the x axis, lifting +y towards +z.
+fn tilt(v: Vector3, angle: f32) -> Vector3 {
+    let (sin, cos) = angle.sin_cos();
+    vec3(v.x, v.y * cos - v.z * sin, v.y * sin + v.z * cos)
+}
+
+/// Parameters for `exponential_disk`.
+#[derive(Clone, Copy, Debug)]
+pub struct DiskParams {
+    /// How many stars make up the disk.
+    pub star_count: usize,
+    /// How many massless tracer stars to add on top of them.
+    pub tracer_count: usize,
+    /// The mass of the central body (bulge or black hole).
+    pub central_mass: f32,
+    /// The collision radius of the central body.
+    pub central_radius: f32,
+    /// The total mass of the stars, shared equally between them.
+    pub disk_mass: f32,
+    /// The radius over which the surface density falls by a factor of e.
+    pub scale_length: f32,
+    /// No star is placed closer in than this, where orbits would be too fast
+    /// for the timestep.
+    pub min_radius: f32,
+    /// No star is placed further out than this.
+    pub max_radius: f32,
+    /// The collision radius of each star.
+    pub star_radius: f32,
+    /// Random velocity scatter, as a fraction of the local circular speed.
+    pub velocity_dispersion: f32,
+    /// The position of the disk's center.
+    pub center: Vector3,
+    /// The bulk velocity of the whole disk.
+    pub velocity: Vector3,
+    /// The tilt of the disk out of the x-y plane, in radians.
+    pub inclination: f32,
+    /// The direction of the line the disk is tilted about, in radians from
+    /// the x axis.
+    pub position_angle: f32,
+}
+
+impl Default for DiskParams {
+    fn default() -> Self {
+        DiskParams {
+            star_count: 5000,
+            tracer_count: 0,
+            central_mass: 3e10,
+            central_radius: 20.0,
+            disk_mass: 2e10,
+            scale_length: 2500.0,
+            min_radius: 500.0,
+            max_radius: 12000.0,
+            star_radius: 0.1,
+            velocity_dispersion: 0.15,
+            center: vec3(0.0, 0.0, 0.0),
+            velocity: vec3(0.0, 0.0, 0.0),
+            inclination: 0.0,
+            position_angle: 0.0,
+        }
+    }
+}
+
+impl DiskParams {
+    /// The total mass of the central body and the disk.
+    pub fn total_mass(&self) -> f32 {
+        self.central_mass + self.disk_mass
+    }
+}
+
+/// Add an exponential disk of stars around a central mass.
+///
+/// The radius of each star is drawn from the exponential surface density
+/// between `min_radius` and `max_radius` (see `sample_radius`).
+///
+/// Panics if `min_radius` is more than `max_radius`.
+///
+/// Arguments:
+///
+/// * `system` - the system to add the particles to
+/// * `params` - the shape of the disk
+/// * `seed` - the random seed
+pub fn exponential_disk(system: &mut ParticleSystem, params: &DiskParams, seed: u64) {
+    assert!(
+        params.min_radius <= params.max_radius,
+        "a disk's min_radius ({}) must not be more than its max_radius ({})",
+        params.min_radius,
+        params.max_radius
+    );
+    let mut rng = Rng::new(seed);
+    let gravity = system.gravity();
+    let g = gravity.g;
+    let epsilon2 = system.softening * system.softening;
+    let star_mass = params.disk_mass / params.star_count.max(1) as f32;
+
+    // The disk mass inside radius r, for an exponential profile.
+    let enclosed = |r: f32| {
+        let x = r / params.scale_length;
+        params.disk_mass * (1.0 - (1.0 + x) * (-x).exp())
+    };
+
+    // Tilting the disk about its line of nodes, then moving it into place.
+    let place = |v: Vector3| {
+        let aligned = rotate(v, -params.position_angle);
+        rotate(tilt(aligned, params.inclination), params.position_angle)
+    };
+
+    let mut center = Particle::new(
+        params.center,
+        params.velocity,
+        params.central_mass,
+        params.central_radius,
+    );
+    center.color = central_color();
+    system.add_particle(center);
+
+    for k in 0..params.star_count + params.tracer_count {
+        let r = sample_radius(&mut rng, params);
+        let angle = rng.range(0.0, TAU);
+        let offset = vec3(r * angle.cos(), r * angle.sin(), 0.0);
+
+        // Circular speed from everything inside the orbit, softened the same
+        // way as the forces: v^2 = G * M * r^2 / (r^2 + epsilon^2)^(3/2).
+        // External potentials add r times their inward pull.
+        let inside = params.central_mass + enclosed(r);
+        let position = params.center + place(offset);
+        let inward = -place(offset) / r;
+        let pull = potential::field_at(&system.potentials, position, system.time, gravity)
+            .dot(inward)
+            .max(0.0);
+        let speed = (g * inside * r * r / (r * r + epsilon2).powf(1.5) + r * pull).sqrt();
+        let tangent = vec3(-angle.sin(), angle.cos(), 0.0);
+        let scatter = vec3(rng.normal(), rng.normal(), 0.0) * (params.velocity_dispersion * speed);
+        let velocity = tangent * speed + scatter;
+
+        // The massive stars come first, then the tracers, which are points.
+        let (mass, radius) = if k < params.star_count {
+            (star_mass, params.star_radius)
+        } else {
+            (0.0, 0.0)
+        };
+        let mut star = Particle::new(position, params.velocity + place(velocity), mass, radius);
+        star.color = star_color();
+        system.add_particle(star);
+    }
+}
+
+/// Draw a radius from an exponential disk's surface density, truncated to
+/// between its `min_radius` and `max_radius`.
+///
+/// The radius in scale lengths, x, follows the Gamma(2) distribution, whose
+/// chance of lying beyond x is S(x) = (1 + x) exp(-x). We draw S uniformly
+/// between its values at the two ends and solve for x by bisection, on
+/// ln S = ln(1 + x) - x so that far-out disks don't underflow. Unlike drawing
+/// from the whole profile and rejecting what falls outside, this takes the
+/// same time however little of the profile the range holds.
+///
+/// Arguments:
+///
+/// * `rng` - the random number generator
+/// * `params` - the disk, whose `min_radius` is at most its `max_radius`
+///
+/// Returns:
+///
+/// * `f32` - the radius
+fn sample_radius(rng: &mut Rng, params: &DiskParams) -> f32 {
+    let scale = params.scale_length as f64;
+    let log_survival = |x: f64| x.ln_1p() - x;
+    let low = (params.min_radius.max(0.0) as f64) / scale;
+    let high = (params.max_radius.max(0.0) as f64) / scale;
+    let (log_low, log_high) = (log_survival(low), log_survival(high));
+
+    // S = S(low) - u (S(low) - S(high)), taken out of the logarithm.
+    let u = rng.uniform() as f64;
+    let target = log_low + (-u * (1.0 - (log_high - log_low).exp())).ln_1p();
+    let (mut inside, mut outside) = (low, high);
+    for _ in 0..64 {
+        let middle = 0.5 * (inside + outside);
+        if log_survival(middle) > target {
+            inside = middle;
+        } else {
+            outside = middle;
+        }
+    }
+    (0.5 * (inside + outside) * scale) as f32
+}
+
+/// Parameters for `plummer_sphere`.
+#[derive(Clone, Copy, Debug)]
+pub struct PlummerParams {
+    /// How many stars make up the cluster.
+    pub star_count: usize,
+    /// The total mass of the cluster, shared equally between the stars.
+    pub total_mass: f32,
+    /// The Plummer scale radius, inside which about a third of the mass lies.
+    pub scale_radius: f32,
+    /// The collision radius of each star.
+    pub star_radius: f32,
+    /// The position of the cluster's center of mass.
+    pub center: Vector3,
+    /// The bulk velocity of the whole cluster.
+    pub velocity: Vector3,
+}
+
+impl Default for PlummerParams {
+    fn default() -> Self {
+        PlummerParams {
+            star_count: 2000,
+            total_mass: 1e5,
+            scale_radius: 5.0,
+            star_radius: 1e-4,
+            center: vec3(0.0, 0.0, 0.0),
+            velocity: vec3(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// A random direction in 3D, uniformly distributed over the sphere.
+fn random_direction(rng: &mut Rng) -> Vector3 {
+    let z = rng.range(-1.0, 1.0);
+    let phi = rng.range(0.0, TAU);
+    let s = (1.0 - z * z).sqrt();
+    vec3(s * phi.cos(), s * phi.sin(), z)
+}
+
+/// Add a Plummer sphere of stars in virial equilibrium.
+///
+/// Positions and speeds are sampled from the 3D Plummer model. Since the
+/// simulation's softened gravity isn't quite the model's, the velocities are
+/// then rescaled to satisfy the virial theorem with this system's own gravity
+/// and solver.
+///
+/// Arguments:
+///
+/// * `system` - the system to add the particles to
+/// * `params` - the size and mass of the cluster
+/// * `seed` - the random seed
+pub fn plummer_sphere(system: &mut ParticleSystem, params: &PlummerParams, seed: u64) {
+    let mut rng = Rng::new(seed);
+    let g = system.g();
+    let a = params.scale_radius;
+    let star_mass = params.total_mass / params.star_count.max(1) as f32;
+
+    // The stars get ids from a scratch store, so that measuring them can tell
+    // them apart, and new ones from the system when they join it.
+    let mut stars = ParticleStore::new();
+    for _ in 0..params.star_count {
+        // Invert the cumulative mass profile M(r) / M = r^3 / (r^2 + a^2)^(3/2),
+        // skipping the far tail so no star starts absurdly far out.
+        let r = loop {
+            let u = rng.uniform().max(1e-6);
+            let r = a / (u.powf(-2.0 / 3.0) - 1.0).sqrt();
+            if r < 10.0 * a {
+                break r;
+            }
+        };
+        let direction = random_direction(&mut rng);
+
+        // Speed as a fraction q of the escape speed, with q distributed as
+        // q^2 (1 - q^2)^(7/2), sampled by rejection.
+        let q = loop {
+            let q = rng.uniform();
+            if rng.uniform() * 0.1 < q * q * (1.0 - q * q).powf(3.5) {
+                break q;
+            }
+        };
+        let escape = (2.0 * g * params.total_mass / (r * r + a * a).sqrt()).sqrt();
+
+        let mut star = Particle::new(
+            direction * r,
+            random_direction(&mut rng) * (q * escape),
+            star_mass,
+            params.star_radius,
+        );
+        star.color = star_color();
+        stars.insert(star);
+    }
+
+    // Move to the cluster's rest frame, then scale the velocities so that
+    // 2 * kinetic + potential = 0.
+    let (solver, gravity) = (ForceSolver::default(), system.gravity());
+    let before = Diagnostics::measure(stars.as_slice(), solver, gravity, &[], 0.0);
+    let drift = before.momentum / before.total_mass.max(f32::MIN_POSITIVE);
+    for star in stars.iter_mut() {
+        star.position -= before.center_of_mass;
+        star.velocity -= drift;
+    }
+    let after = Diagnostics::measure(stars.as_slice(), solver, gravity, &[], 0.0);
+    let scale = if after.kinetic > 0.0 {
+        (-0.5 * after.potential / after.kinetic).sqrt()
+    } else {
+        1.0
+    };
+
+    for mut star in stars.as_slice().iter().cloned() {
+        star.position += params.center;
+        star.velocity = star.velocity * scale + params.velocity;
+        system.add_particle(star);
+    }
+}
+
+/// Parameters for `kepler_system`.
+#[derive(Clone, Copy, Debug)]
+pub struct KeplerParams {
+    /// The mass of the host star.
+    pub star_mass: f32,
+    /// The surface temperature of the host star, in kelvin.
+    pub star_temperature: f32,
+    /// How many planets orbit the star.
+    pub planet_count: usize,
+    /// The semi-major axis of the innermost planet.
+    pub inner_axis: f32,
+    /// The ratio between the semi-major axes of neighbouring planets.
+    pub spacing: f32,
+    /// Planet masses are drawn log-uniformly between these two.
+    pub planet_mass_range: (f32, f32),
+    /// Planets heavier than this are gas giants; lighter ones are rocky.
+    pub gas_giant_above: f32,
+    /// Eccentricities are drawn uniformly below this.
+    pub max_eccentricity: f32,
+    /// The position of the host star.
+    pub center: Vector3,
+    /// The bulk velocity of the whole system.
+    pub velocity: Vector3,
+}
+
+impl Default for KeplerParams {
+    fn default() -> Self {
+        KeplerParams {
+            star_mass: 1.0,
+            star_temperature: SUN_TEMPERATURE,
+            planet_count: 6,
+            inner_axis: 0.4,
+            spacing: 1.6,
+            planet_mass_range: (1e-6, 1e-3),
+            // About ten Earth masses.
+            gas_giant_above: 3e-5,
+            max_eccentricity: 0.1,
+            center: vec3(0.0, 0.0, 0.0),
+            velocity: vec3(0.0, 0.0, 0.0),
+        }
+    }
+}
+
+/// Add a star with planets on Keplerian orbits.
+///
+/// Each planet starts at a random point of an ellipse with a random
+/// orientation. Planets are treated as test bodies when choosing their
+/// velocities, so heavy planets will perturb each other. The star recoils
+/// from the planets, so the center of mass moves at the system's `velocity`.
+///
+/// Arguments:
+///
+/// * `system` - the system to add the particles to
+/// * `params` - the star and the layout of the planets
+/// * `seed` - the random seed
+pub fn kepler_system(system: &mut ParticleSystem, params: &KeplerParams, seed: u64) {
+    let mut rng = Rng::new(seed);
+    let mu = system.g() * params.star_mass;
+
+    let mut star = Particle::of_material(
+        params.center,
+        params.velocity,
+        params.star_mass,
+        Material::Star {
+            temperature: params.star_temperature,
+        },
+        &system.units,
+    );
+
+    let (low, high) = params.planet_mass_range;
+    let mut axis = params.inner_axis;
+    let mut planets = Vec::with_capacity(params.planet_count);
+    for _ in 0..params.planet_count {
+        let e = rng.range(0.0, params.max_eccentricity);
+        let true_anomaly = rng.range(0.0, TAU);
+        let periapsis = rng.range(0.0, TAU);
+        let mass = (rng.range(low.ln(), high.ln())).exp();
+
+        // Position and velocity on the ellipse, in the orbit's own frame:
+        // r = p / (1 + e cos f), with radial and tangential speeds from the
+        // semi-latus rectum p = a (1 - e^2).
+        let p = axis * (1.0 - e * e);
+        let (sin_f, cos_f) = true_anomaly.sin_cos();
+        let r = p / (1.0 + e * cos_f);
+        let h = (mu / p).sqrt();
+        let radial = h * e * sin_f;
+        let tangential = h * (1.0 + e * cos_f);
+        let position = vec3(r * cos_f, r * sin_f, 0.0);
+        let velocity = vec3(
+            radial * cos_f - tangential * sin_f,
+            radial * sin_f + tangential * cos_f,
+            0.0,
+        );
+
+        let material = if mass > params.gas_giant_above {
+            Material::Gas
+        } else {
+            Material::Rock
+        };
+        planets.push(Particle::of_material(
+            params.center + rotate(position, periapsis),
+            params.velocity + rotate(velocity, periapsis),
+            mass,
+            material,
+            &system.units,
+        ));
+
+        axis *= params.spacing;
+    }
+
+    // Take the planets' momentum back off everything, star included, so the
+    // system as a whole moves at `velocity` rather than drifting off with
+    // them. The orbits about the star stay as they were.
+    let total_mass = params.star_mass + planets.iter().map(|p| p.mass).sum::<f32>();
+    let momentum = planets.iter().fold(vec3(0.0, 0.0, 0.0), |sum, p| {
+        sum + (p.velocity - params.velocity) * p.mass
+    });
+    let drift = momentum / total_mass.max(f32::MIN_POSITIVE);
+    star.velocity -= drift;
+    system.add_particle(star);
+    for mut planet in planets {
+        planet.velocity -= drift;
+        system.add_particle(planet);
+    }
+}
+
+/// Parameters for `galaxy_merger`.
+#[derive(Clone, Copy, Debug)]
+pub struct MergerParams {
+    /// The first disk. Its `center` and `velocity` are ignored.
+    pub first: DiskParams,
+    /// The second disk. Its `center` and `velocity` are ignored.
+    pub second: DiskParams,
+    /// How far apart the disks start along the x axis.
+    pub separation: f32,
+    /// How far apart the disks start along the y axis, so they don't hit
+    /// head-on.
+    pub impact_parameter: f32,
+    /// The speed at which the disks approach each other.
+    pub approach_speed: f32,
+}
+
+impl Default for MergerParams {
+    fn default() -> Self {
+        let small = DiskParams {
+            star_count: 3000,
+            ..DiskParams::default()
+        };
+        MergerParams {
+            first: small,
+            second: DiskParams {
+                inclination: 60.0f32.to_radians(),
+                position_angle: 30.0f32.to_radians(),
+                ..small
+            },
+            separation: 40000.0,
+            impact_parameter: 10000.0,
+            approach_speed: 100.0,
+        }
+    }
+}
+
+/// Add two disk galaxies on a collision course.
+///
+/// The pair is placed so that its center of mass is at the origin and at
+/// rest.
+///
+/// Arguments:
+///
+/// * `system` - the system to add the particles to
+/// * `params` - the two disks and their orbit
+/// * `seed` - the random seed; each disk gets its own seed derived from it
+pub fn galaxy_merger(system: &mut ParticleSystem, params: &MergerParams, seed: u64) {
+    let m1 = params.first.total_mass();
+    let m2 = params.second.total_mass();
+    let total = m1 + m2;
+
+    // Split the offset and relative velocity by mass, so the center of mass
+    // stays put.
+    let offset = vec3(params.separation, params.impact_parameter, 0.0);
+    let relative = vec3(-params.approach_speed, 0.0, 0.0);
+
+    let first = DiskParams {
+        center: offset * (-m2 / total),
+        velocity: relative * (-m2 / total),
+        ..params.first
+    };
+    let second = DiskParams {
+        center: offset * (m1 / total),
+        velocity: relative * (m1 / total),
+        ..params.second
+    };
+    exponential_disk(system, &first, seed);
+    exponential_disk(system, &second, seed.wrapping_add(0x9e3779b97f4a7c15));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::units::UnitSystem;
+
+    #[test]
+    fn disks_keep_to_their_radii() {
+        // A thin ring thirty scale lengths out, which a draw from the whole
+        // profile would almost never land in.
+        let params = DiskParams {
+            star_count: 200,
+            min_radius: 30.0 * 2500.0,
+            max_radius: 31.0 * 2500.0,
+            ..DiskParams::default()
+        };
+        let mut system = ParticleSystem::new(UnitSystem::GALACTIC);
+        exponential_disk(&mut system, &params, 7);
+        let stars = &system.particles.as_slice()[1..];
+        assert_eq!(stars.len(), 200);
+        for star in stars {
+            let r = star.position.magnitude();
+            assert!(r >= 0.999 * params.min_radius && r <= 1.001 * params.max_radius);
+        }
+        // And more of them in the inner half, where the density is higher.
+        let middle = 0.5 * (params.min_radius + params.max_radius);
+        let inner = stars
+            .iter()
+            .filter(|p| p.position.magnitude() < middle)
+            .count();
+        assert!(inner > 100, "{} of 200 in the inner half", inner);
+    }
+
+    #[test]
+    #[should_panic(expected = "min_radius")]
+    fn disks_inside_out_are_refused() {
+        let params = DiskParams {
+            min_radius: 2000.0,
+            max_radius: 1000.0,
+            ..DiskParams::default()
+        };
+        exponential_disk(&mut ParticleSystem::new(UnitSystem::GALACTIC), &params, 0);
+    }
+
+    #[test]
+    fn plummer_spheres_start_in_virial_equilibrium() {
+        let params = PlummerParams {
+            star_count: 500,
+            ..PlummerParams::default()
+        };
+        let mut system = ParticleSystem::new(UnitSystem::GALACTIC);
+        plummer_sphere(&mut system, &params, 3);
+
+        // Measured exactly, rather than with the tree the generator used.
+        let measured = Diagnostics::measure(
+            system.particles.as_slice(),
+            ForceSolver::Direct,
+            system.gravity(),
+            &[],
+            0.0,
+        );
+        let ratio = -2.0 * measured.kinetic / measured.potential;
+        assert!((ratio - 1.0).abs() < 0.02, "virial ratio {}", ratio);
+    }
+
+    #[test]
+    fn kepler_orbits_are_circular_and_the_system_stays_put() {
+        let params = KeplerParams {
+            planet_mass_range: (1e-3, 1e-2),
+            max_eccentricity: 0.0,
+            velocity: vec3(0.5, -0.25, 0.0),
+            ..KeplerParams::default()
+        };
+        let mut system = ParticleSystem::new(UnitSystem::SOLAR);
+        kepler_system(&mut system, &params, 11);
+        let particles = system.particles.as_slice();
+        let mu = system.g() * params.star_mass;
+
+        // Each planet moves at the circular speed, square to the line to
+        // the star.
+        let star = &particles[0];
+        for planet in &particles[1..] {
+            let r = planet.position - star.position;
+            let v = planet.velocity - star.velocity;
+            let circular = (mu / r.magnitude()).sqrt();
+            assert!((v.magnitude() / circular - 1.0).abs() < 1e-4);
+            assert!(r.dot(v).abs() < 1e-4 * r.magnitude() * v.magnitude());
+        }
+
+        // While the center of mass moves with the system.
+        let measured =
+            Diagnostics::measure(particles, ForceSolver::Direct, system.gravity(), &[], 0.0);
+        let drift = measured.momentum / measured.total_mass - params.velocity;
+        assert!(drift.magnitude() < 1e-5, "drifting at {:?}", drift);
+    }
+}
@@ -0,0 +1,380 @@
+/*
+Time integrators for the galaxy simulation.
+
+An integrator advances every particle's position and velocity by one timestep
+`dt`, given a way to compute the gravitational acceleration (a = F / m) at any
+set of positions. They differ in cost, accuracy, and in how well they hold on
+to energy over long runs:
+
+* `ExplicitEuler` moves by the old velocity, then kicks by the old
+  acceleration. Cheap, first-order, and gains energy every orbit: good for
+  chaos, bad for anything long-running.
+* `SemiImplicitEuler` kicks first, then moves by the new velocity. Same cost,
+  but symplectic, so orbits stay closed.
+* `Leapfrog` (kick-drift-kick) splits the kick into two halves around the
+  drift. Second-order and symplectic; the usual choice for N-body work.
+* `VelocityVerlet` is algebraically the same scheme as leapfrog, written as a
+  position update followed by an averaged velocity update.
+* `Rk4` is the classic fourth-order Runge-Kutta. Very accurate per step and
+  four force evaluations per step, but not symplectic, so energy slowly drifts.
+
+Forces are computed into a buffer that is kept from step to step (see
+`StepBuffers`), so a step doesn't copy or reallocate the particle list.
+
+Every integrator expects `Particle::acceleration` to hold the acceleration at
+the current positions when it is called, and leaves it holding the acceleration
+at the new positions when it returns. That way the single-evaluation schemes
+only compute forces once per step. Forces can change with time (a rotating
+bar, see potential.rs), so each evaluation is also told how far into the step
+its positions are.
+*/
+
+use nannou::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::timestep::BlockBuffers;
+use crate::Particle;
+
+/// Which scheme to use to advance the particles through time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Integrator {
+    ExplicitEuler,
+    SemiImplicitEuler,
+    #[default]
+    Leapfrog,
+    VelocityVerlet,
+    Rk4,
+}
+
+impl Integrator {
+    /// The integrator after this one, for cycling through them with a key.
+    pub fn next(&self) -> Self {
+        match self {
+            Integrator::ExplicitEuler => Integrator::SemiImplicitEuler,
+            Integrator::SemiImplicitEuler => Integrator::Leapfrog,
+            Integrator::Leapfrog => Integrator::VelocityVerlet,
+            Integrator::VelocityVerlet => Integrator::Rk4,
+            Integrator::Rk4 => Integrator::ExplicitEuler,
+        }
+    }
+
+    /// Advance the particles by one timestep.
+    ///
+    /// Arguments:
+    ///
+    /// * `particles` - the particles to advance, whose `acceleration` is
+    ///   up to date with their positions
+    /// * `dt` - the length of the timestep
+    /// * `buffers` - scratch space kept from step to step, so that stepping
+    ///   doesn't allocate
+    /// * `accelerations` - writes the acceleration on each of a set of
+    ///   particles, computed from their positions at the given time since the
+    ///   start of the step, into the given buffer
+    pub fn step<F>(
+        &self,
+        particles: &mut [Particle],
+        dt: f32,
+        buffers: &mut StepBuffers,
+        accelerations: F,
+    ) where
+        F: Fn(&[Particle], f32, &mut Vec<Vector3>),
+    {
+        match self {
+            Integrator::ExplicitEuler => {
+                for p in particles.iter_mut() {
+                    p.position += p.velocity * dt;
+                    p.velocity += p.acceleration * dt;
+                }
+                evaluate(particles, dt, &mut buffers.accelerations, &accelerations);
+            }
+            Integrator::SemiImplicitEuler => {
+                for p in particles.iter_mut() {
+                    p.velocity += p.acceleration * dt;
+                    p.position += p.velocity * dt;
+                }
+                evaluate(particles, dt, &mut buffers.accelerations, &accelerations);
+            }
+            Integrator::Leapfrog => {
+                // Kick by half a step, drift a whole step...
+                for p in particles.iter_mut() {
+                    p.velocity += p.acceleration * (0.5 * dt);
+                    p.position += p.velocity * dt;
+                }
+                evaluate(particles, dt, &mut buffers.accelerations, &accelerations);
+                // ...and kick by the other half with the new forces.
+                for p in particles.iter_mut() {
+                    p.velocity += p.acceleration * (0.5 * dt);
+                }
+            }
+            Integrator::VelocityVerlet => {
+                let old = &mut buffers.stages[0];
+                old.clear();
+                old.extend(particles.iter().map(|p| p.acceleration));
+                for p in particles.iter_mut() {
+                    p.position += p.velocity * dt + p.acceleration * (0.5 * dt * dt);
+                }
+                evaluate(particles, dt, &mut buffers.accelerations, &accelerations);
+                for (p, a) in particles.iter_mut().zip(buffers.stages[0].iter()) {
+                    p.velocity += (*a + p.acceleration) * (0.5 * dt);
+                }
+            }
+            Integrator::Rk4 => rk4(particles, dt, buffers, &accelerations),
+        }
+    }
+}
+
+/// Scratch space for `Integrator::step`, and for block timesteps (see
+/// timestep.rs).
+///
+/// The force solver reads the particles as a read-only snapshot and writes its
+/// results into `accelerations`, which are then copied onto the particles: two
+/// buffers, so nothing is read and written at the same time. Every buffer here
+/// keeps its allocation between steps.
+#[derive(Default)]
+pub struct StepBuffers {
+    /// Where the force solver writes the accelerations.
+    accelerations: Vec<Vector3>,
+    /// A copy of the particles moved to RK4's trial positions.
+    trial: Vec<Particle>,
+    /// Per-particle intermediate values: Verlet's old accelerations, and
+    /// RK4's stage velocity, stage acceleration and running sums.
+    stages: [Vec<Vector3>; 3],
+    /// The particles' levels and step ends, for block timesteps.
+    pub blocks: BlockBuffers,
+}
+
+impl StepBuffers {
+    /// Bring the particles' cached accelerations up to date with their
+    /// positions, e.g. after particles were added or merged. The positions are
+    /// taken to be at the start of the next step.
+    pub fn refresh<F>(&mut self, particles: &mut [Particle], accelerations: F)
+    where
+        F: Fn(&[Particle], f32, &mut Vec<Vector3>),
+    {
+        evaluate(particles, 0.0, &mut self.accelerations, &accelerations);
+    }
+}
+
+/// Compute the accelerations at the particles' current positions, `since`
+/// the start of the step, into the write buffer, and copy them onto the
+/// particles.
+fn evaluate<F>(particles: &mut [Particle], since: f32, buffer: &mut Vec<Vector3>, accelerations: &F)
+where
+    F: Fn(&[Particle], f32, &mut Vec<Vector3>),
+{
+    accelerations(particles, since, buffer);
+    for (p, a) in particles.iter_mut().zip(buffer.iter()) {
+        p.acceleration = *a;
+    }
+}
+
+/// The classic fourth-order Runge-Kutta step.
+///
+/// The state of each particle is (x, v), and its derivative is (v, a(x)). We
+/// sample the derivative at the start, twice at the midpoint, and at the end of
+/// the step, and advance by their weighted average. Rather than keep all four
+/// samples, we keep the latest one and a running weighted sum, and leave the
+/// particles at (x0, v0) until the end.
+fn rk4<F>(particles: &mut [Particle], dt: f32, buffers: &mut StepBuffers, accelerations: &F)
+where
+    F: Fn(&[Particle], f32, &mut Vec<Vector3>),
+{
+    let StepBuffers {
+        accelerations: a,
+        trial,
+        stages,
+        ..
+    } = buffers;
+    let [v, sum_v, sum_a] = stages;
+
+    // k1 is the derivative we already have.
+    v.clear();
+    v.extend(particles.iter().map(|p| p.velocity));
+    a.clear();
+    a.extend(particles.iter().map(|p| p.acceleration));
+    sum_v.clone_from(v);
+    sum_a.clone_from(a);
+
+    trial.clear();
+    trial.extend_from_slice(particles);
+    for (h, weight) in [(0.5 * dt, 2.0), (0.5 * dt, 2.0), (dt, 1.0)] {
+        for (i, (p, x0)) in trial.iter_mut().zip(particles.iter()).enumerate() {
+            p.position = x0.position + v[i] * h;
+            v[i] = x0.velocity + a[i] * h;
+        }
+        accelerations(trial, h, a);
+        for i in 0..particles.len() {
+            sum_v[i] += v[i] * weight;
+            sum_a[i] += a[i] * weight;
+        }
+    }
+
+    for (i, p) in particles.iter_mut().enumerate() {
+        p.position += sum_v[i] * (dt / 6.0);
+        p.velocity += sum_a[i] * (dt / 6.0);
+    }
+    evaluate(particles, dt, a, accelerations);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The pull of a unit point mass at the origin.
+    fn kepler(particles: &[Particle], _: f32, out: &mut Vec<Vector3>) {
+        out.clear();
+        out.extend(
+            particles
+                .iter()
+                .map(|p| -p.position / p.position.magnitude().powi(3)),
+        );
+    }
+
+    /// The spring of a unit harmonic oscillator, which swings with period
+    /// 2 pi.
+    fn spring(particles: &[Particle], _: f32, out: &mut Vec<Vector3>) {
+        out.clear();
+        out.extend(particles.iter().map(|p| -p.position));
+    }
+
+    /// Run a scheme for `steps` steps of `dt`, calling `each` after every one.
+    fn run<F>(
+        integrator: Integrator,
+        particles: &mut [Particle],
+        accelerations: F,
+        dt: f32,
+        steps: usize,
+        mut each: impl FnMut(&[Particle]),
+    ) where
+        F: Fn(&[Particle], f32, &mut Vec<Vector3>),
+    {
+        let mut buffers = StepBuffers::default();
+        buffers.refresh(particles, &accelerations);
+        for _ in 0..steps {
+            integrator.step(particles, dt, &mut buffers, &accelerations);
+            each(particles);
+        }
+    }
+
+    /// How far a scheme's oscillator is from the exact solution after a time
+    /// of 1, taking steps of `dt`.
+    fn oscillator_error(integrator: Integrator, dt: f32) -> f32 {
+        let mut particles = [Particle::new(
+            vec3(1.0, 0.0, 0.0),
+            vec3(0.0, 0.0, 0.0),
+            1.0,
+            0.0,
+        )];
+        let steps = (1.0 / dt).round() as usize;
+        run(integrator, &mut particles, spring, dt, steps, |_| {});
+        let p = &particles[0];
+        let exact = (vec3(1f32.cos(), 0.0, 0.0), vec3(-1f32.sin(), 0.0, 0.0));
+        (p.position - exact.0).magnitude() + (p.velocity - exact.1).magnitude()
+    }
+
+    #[test]
+    fn schemes_converge_at_their_order() {
+        // Steps coarse enough that f32 rounding stays well below the
+        // truncation error, even for RK4.
+        for (integrator, order, dt) in [
+            (Integrator::ExplicitEuler, 1.0, 1.0 / 64.0),
+            (Integrator::SemiImplicitEuler, 1.0, 1.0 / 64.0),
+            (Integrator::Leapfrog, 2.0, 1.0 / 32.0),
+            (Integrator::VelocityVerlet, 2.0, 1.0 / 32.0),
+            (Integrator::Rk4, 4.0, 1.0 / 4.0),
+        ] {
+            // Halving the step divides the error by 2^order.
+            let measured =
+                (oscillator_error(integrator, dt) / oscillator_error(integrator, 0.5 * dt)).log2();
+            assert!(
+                (measured - order).abs() < 0.2,
+                "{:?} converged at order {}",
+                integrator,
+                measured
+            );
+        }
+    }
+
+    #[test]
+    fn symplectic_schemes_keep_energy_bounded() {
+        // An eccentric orbit about a unit mass, with semi-major axis 1 and so
+        // period 2 pi and energy -1/2, taken a hundred times round.
+        let e = 0.5;
+        let steps_per_orbit = 500;
+        let dt = 2.0 * PI / steps_per_orbit as f32;
+        let energy = |p: &Particle| 0.5 * p.velocity.magnitude2() - 1.0 / p.position.magnitude();
+
+        for integrator in [
+            Integrator::SemiImplicitEuler,
+            Integrator::Leapfrog,
+            Integrator::VelocityVerlet,
+        ] {
+            let mut particles = [Particle::new(
+                vec3(1.0 - e, 0.0, 0.0),
+                vec3(0.0, ((1.0 + e) / (1.0 - e)).sqrt(), 0.0),
+                0.0,
+                0.0,
+            )];
+            let mut errors = Vec::new();
+            run(
+                integrator,
+                &mut particles,
+                kepler,
+                dt,
+                100 * steps_per_orbit,
+                |particles| errors.push((energy(&particles[0]) + 0.5).abs() / 0.5),
+            );
+
+            // The error oscillates over each orbit, but doesn't grow from the
+            // first ten orbits to the last ten.
+            let worst = |errors: &[f32]| errors.iter().cloned().fold(0.0, f32::max);
+            let first = worst(&errors[..10 * steps_per_orbit]);
+            let last = worst(&errors[errors.len() - 10 * steps_per_orbit..]);
+            assert!(first < 0.05, "{:?} energy error {}", integrator, first);
+            assert!(
+                last < 1.5 * first,
+                "{:?} energy error grew from {} to {}",
+                integrator,
+                first,
+                last
+            );
+        }
+    }
+
+    #[test]
+    fn leapfrog_and_velocity_verlet_agree() {
+        let start = [
+            Particle::new(vec3(1.0, 0.0, 0.0), vec3(0.0, 1.1, 0.0), 0.0, 0.0),
+            Particle::new(vec3(0.0, -2.0, 0.5), vec3(0.6, 0.1, 0.0), 0.0, 0.0),
+        ];
+        let mut leapfrog = start.clone();
+        let mut verlet = start;
+        let mut positions = Vec::new();
+        run(
+            Integrator::Leapfrog,
+            &mut leapfrog,
+            kepler,
+            0.01,
+            2000,
+            |particles| positions.push([particles[0].position, particles[1].position]),
+        );
+        let mut step = 0;
+        run(
+            Integrator::VelocityVerlet,
+            &mut verlet,
+            kepler,
+            0.01,
+            2000,
+            |particles| {
+                // The same trajectory step for step, up to rounding.
+                for (p, q) in particles.iter().zip(positions[step].iter()) {
+                    assert!((p.position - *q).magnitude() < 1e-3);
+                }
+                step += 1;
+            },
+        );
+        for (p, q) in leapfrog.iter().zip(verlet.iter()) {
+            assert!((p.velocity - q.velocity).magnitude() < 1e-3);
+        }
+    }
+}
@@ -24,20 +24,220 @@ them together subject to:
 where F is the force, G is the gravitational constant, m1 and m2 are the masses
 of the particles, and r is the distance between the particles.
 
-We also update each particle's position in parallel, using the common rust
-library rayon. This is a library that provides a thread pool, which is used to
-parallelize the work of updating the positions of the particles. This means that
-the particle positions are updated in parallel, and then we perform a final O(N)
-sweep to check for collisions.
+Computing that force naively means looking at every pair of particles, which is
+O(N^2). Instead, the force is computed by a pluggable solver (see gravity.rs):
+either the exact direct sum, or a Barnes-Hut octree (see octree.rs) that
+approximates distant clusters of particles by their center of mass. For very
+big runs there is also a particle-mesh solver (see mesh.rs), which spreads the
+mass over a grid and solves for the potential with FFTs, and P3M, which adds
+the pull of near neighbours back on top of it. Press B to cycle through them,
+and E to print how far the current solver is from the direct sum; pass e.g.
+`--solver pm:grid=128` to start with one.
+
+Not every particle has to pull. A particle of zero mass is a tracer: it feels
+gravity but is left out of the sources, so the force costs O(N_massive * N)
+instead of O(N^2), and a disk of a hundred thousand tracer stars can orbit a
+few dozen heavy bodies. A tracer that touches a massive body is captured by it
+(see collision.rs).
+
+Each particle is accelerated by a = F / m and moved through an explicit
+timestep dt by one of several integrators (see integrator.rs): explicit and
+semi-implicit Euler, leapfrog, velocity Verlet, and RK4. Press I to cycle
+through them.
+
+So that close encounters neither crawl nor explode, each update's timestep
+can be divided up (see timestep.rs): into global steps as short as the most
+demanding particle needs, or into block timesteps, where each particle
+advances at its own power-of-two fraction of dt and only the few bodies in a
+close pass pay for short steps. Either way every particle lands on the end of
+the update together, so what is drawn is always one consistent moment. Press
+A to cycle through fixed, adaptive and block steps, and pass e.g. `--timestep
+block:eta=0.02,max_level=16` to start with one.
+
+The viewer keeps a history of snapshots of the system (see history.rs), so
+nothing interesting is gone a frame later. Press space to pause and resume,
+period and comma to step one update forwards or back, backspace to rewind, and
+Home and End to jump to either end of the history; while paused, drag along
+the timeline bar at the bottom of the window to scrub. Resuming from an
+earlier point forks a new timeline there. Pass `--history-depth 200` and
+`--history-stride 5` to keep 200 snapshots, one every 5 updates.
+
+The simulation runs at the same speed however fast the screen refreshes (see
+clock.rs): a fixed-step loop runs one update (or `--substeps N`) for every
+sixtieth of a second that passes, and the particles are drawn in between
+updates so they move smoothly. Press - and = to halve or double the speed,
+for slow motion or fast forward, or pass `--time-scale 0.25`. If the machine
+falls behind, a frame runs at most `--max-steps-per-frame` updates and the
+simulation slows down rather than stalls.
+
+Space can be closed off by a boundary (see boundary.rs), a cube centered on
+the origin: periodic, where particles leaving one face come back through the
+opposite one and every body pulls from its nearest image; reflective, with
+walls that bounce particles back in; or absorbing, where particles that leave
+are reported and taken out of the simulation, either for good or parked in
+gray where they left. Press W to cycle through them, with a box that fits the
+whole system, or pass e.g. `--boundary periodic:size=20000`.
+
+The force calculation, which is where nearly all the time goes, runs in
+parallel on rayon's thread pool: the solver reads the particles as a read-only
+snapshot and writes every acceleration into a separate buffer, which the
+integrator then applies. Each particle's forces are always summed in the same
+order, so a run with a given seed reproduces exactly on any number of threads.
+Pass --threads N to set the size of the pool (by default, one per core).
+
+Collisions are resolved by collision.rs. Gentle impacts merge the bodies into
+one (chains of touching bodies, found with a union-find, merge together);
+harder ones, measured against the energy binding the pair, lead to partial
+accretion, a bounce, or fragmentation. Each scene picks which of these can
+happen: the galaxies only merge, while the planetary scenes allow them all.
+Every outcome conserves mass and momentum. Each step returns a report listing
+the merges and other impacts.
+
+Every particle is made of a material (see material.rs): rock, ice, gas, or a
+star of some temperature. The material sets the body's density, and so its
+radius for its mass, and its color. When bodies merge their volumes add up,
+so they grow believably, and their colors blend by mass.
+
+Particles live in a generational slot map (see store.rs) and are known by a
+stable `ParticleId`, so adding, removing and finding a particle are O(1), and
+two bodies at the same spot are never mistaken for each other. The store also
+remembers which body every merged particle went into.
+
+Gravity is softened (Plummer softening, see gravity.rs) so close passes stay
+finite; press [ and ] to halve or double the softening length. Each particle is
+given an id when it joins the system, and never pulls on itself. Any particle
+whose state stops being a finite number is taken out of the simulation and
+reported, rather than poisoning its neighbours and being drawn off-screen.
+
+The simulation is 3D: positions, velocities and forces all have a z
+component. The flat scenes start in the x-y plane and, with nothing to push
+them out of it, stay exactly there; the disk galaxies can be inclined, and the
+star cluster is a sphere.
+
+On top of the particles' own gravity, a scene can have fixed analytic external
+potentials (see potential.rs): an NFW or isothermal dark-matter halo, a
+Plummer bulge, a point mass, a uniform field or a rotating bar. The disk
+galaxy sits in an NFW halo, so its rotation curve stays flat far out. Press F1
+to F6 to turn the scene's potentials on and off, and pass e.g. `--potential
+bar:mass=1e10,pattern_speed=0.04` (repeatably) to add more.
+
+Everything runs in physical units (see units.rs). By default lengths are in AU,
+masses in solar masses and times in years, so G = 4 * pi^2 and the Earth takes
+one year to go around the Sun. A `Viewport` (see viewport.rs) maps the
+simulation onto the window, and a `Camera` (see camera.rs) moves it: scroll to
+zoom around the cursor, drag with the right mouse button to pan, press F to
+follow the center of mass or the heaviest body, and press G for a grid with a
+scale bar. Drag with the middle mouse button (or the right one with shift
+held), or press the arrow keys, to orbit the view; press V to switch between
+perspective, where farther bodies are drawn smaller and dimmer, and an
+orthographic view, and O to look straight down on the x-y plane again. Pass
+`--yaw 30 --pitch 60 --perspective` (in degrees) to start turned.
+
+To add a body, press the left mouse button where it should go (in a turned
+view, it goes in the plane through the middle of the window facing you), pull
+back and let go, like a slingshot (see spawn.rs). While pulling, its predicted
+path is drawn; scroll to change its mass, and hold shift or alt as you let go
+to make it ten times heavier or lighter.
+
+To run in other units, pass e.g. `--units pc,msun,myr`; the scene is converted
+and should look and move exactly the same.
+
+To check that a run is physically sane, diagnostics.rs measures the total
+kinetic and potential energy, momentum, angular momentum and center of mass
+after every step. Press H to show them on screen, along with how far the
+energy has drifted since the scene started. Pass `--telemetry run.csv` (or
+`run.jsonl`) to stream them to a file.
+
+Particles are drawn in their own colors, or colored by a physical quantity
+through a perceptual colormap (see coloring.rs). Press C to cycle through the
+quantities (speed, mass, kinetic energy, local density, age and distance from
+the center of mass), M to cycle through the colormaps (viridis, magma, inferno
+and cividis), and R to fix the color range where it is or go back to fitting
+it to every frame. A legend shows what the colors mean. Pass e.g. `--color-by
+speed --colormap magma --color-range 0,30` to start that way.
+
+Each particle leaves a trail behind it that fades and narrows with age (see
+trails.rs); press T to hide or show them, and pass `--trail-length 200` or
+`--trail-decay 0.99` to make them longer or fade more slowly.
+
+Scenes are built by the generators in initial_conditions.rs, each from a seed
+(pass `--seed 42` to change it). Press a number key to load one:
+
+1. five suns of different temperatures scattered at random, at rest
+2. the Sun and the Earth, sized by their densities
+3. an exponential disk galaxy around a central mass, in a dark-matter halo
+4. a Plummer star cluster in virial equilibrium
+5. a planetary system on Keplerian orbits
+6. two inclined disk galaxies colliding
+7. a hundred thousand tracer stars around a few dozen heavy clumps, in a halo
+
+Press S to save the running scene (see scene.rs) to `galaxy.ron`, or to the
+file given with `--save`; a `.bin` extension saves a compact binary snapshot
+instead of the readable RON. Pass `--scene galaxy.ron` to start from a saved
+scene.
+
+Pass `--headless` to run without a window, e.g. on a server (see batch.rs):
+the simulation is advanced a fixed number of steps, writing scene snapshots, a
+diagnostics log and optionally PNG images as it goes. The window is then just a
+viewer over the same simulation.
 */
 
-use rayon::iter::IntoParallelRefIterator;
-use rayon::iter::IntoParallelRefMutIterator;
-use rayon::iter::ParallelIterator;
+use std::mem;
+use std::path::Path;
+
 // use nannou::noise::*;
 use nannou::prelude::*;
 
+mod batch;
+mod boundary;
+mod camera;
+mod clock;
+mod collision;
+mod coloring;
+mod diagnostics;
+mod gravity;
+mod history;
+mod initial_conditions;
+mod integrator;
+mod material;
+mod mesh;
+mod octree;
+mod potential;
+mod rng;
+mod scene;
+mod spawn;
+mod store;
+mod timestep;
+mod trails;
+mod units;
+mod viewport;
+
+use boundary::{Boundary, Crossings};
+use camera::Camera;
+use clock::Clock;
+use collision::{AllowedOutcomes, CaptureEvent, CollisionModel, ImpactEvent, MergeEvent};
+use coloring::{Coloring, Range};
+use diagnostics::{Diagnostics, Telemetry};
+use gravity::{ForceSolver, Gravity};
+use history::{History, HistorySettings, Playback};
+use initial_conditions::{DiskParams, KeplerParams, MergerParams, PlummerParams};
+use integrator::{Integrator, StepBuffers};
+use material::{Material, RadiusScaling};
+use potential::{ExternalPotential, Potential};
+use rng::Rng;
+use spawn::Slingshot;
+use store::{ParticleId, ParticleStore};
+use timestep::{Substeps, Timestep};
+use trails::{TrailSettings, Trails};
+use units::UnitSystem;
+use viewport::{Projected, Viewport};
+
 fn main() {
+    threads_from_args();
+    if std::env::args().any(|a| a == "--headless") {
+        batch::run();
+        return;
+    }
     nannou::app(model).update(update).run();
 }
 
@@ -47,25 +247,32 @@ fn main() {
 /// It also has a method to update its position based on its velocity and
 /// gravity.
 ///
-
-// Grav const:
-const G: f32 = 6.67408e-11;
-
+/// All quantities are in the units of the owning `ParticleSystem`.
 struct Particle {
-    position: Vector2,
-    velocity: Vector2,
+    /// A stable id, handed out by the `ParticleSystem` the particle joins.
+    id: ParticleId,
+    position: Vector3,
+    velocity: Vector3,
+    /// The gravitational acceleration at the current position, kept up to
+    /// date by the `Integrator`.
+    acceleration: Vector3,
     color: Rgb,
     radius: f32,
     mass: f32,
+    /// What the particle is made of (see material.rs).
+    material: Material,
+    /// The simulation time when the particle joined its system, or was made
+    /// by a collision.
+    born: f64,
 }
 
-/// Implement PartialEq for Particle, so that we can use it in a HashSet:
-/// https://doc.rust-lang.org/std/collections/struct.HashSet.html
+/// Implement PartialEq for Particle.
 ///
-/// Two particles are the same if they have the same position and mass.
+/// Two particles are the same if they have the same id, wherever they are:
+/// coincident bodies are still different particles.
 impl PartialEq for Particle {
     fn eq(&self, other: &Particle) -> bool {
-        self.position == other.position && self.mass == other.mass
+        self.id == other.id
     }
 }
 
@@ -73,19 +280,22 @@ impl PartialEq for Particle {
 impl Clone for Particle {
     fn clone(&self) -> Self {
         Particle {
+            id: self.id,
             position: self.position,
             velocity: self.velocity,
+            acceleration: self.acceleration,
             color: self.color,
             radius: self.radius,
             mass: self.mass,
+            material: self.material,
+            born: self.born,
         }
     }
 }
 
 /// Implementation of the Particle struct.
 ///
-/// We implement the update method, which updates the position of the particle
-/// based on its velocity and gravity.
+/// Particles are moved through time by the system's `Integrator`.
 impl Particle {
     /// Create a new particle.
     ///
@@ -93,62 +303,100 @@ impl Particle {
     ///
     /// * `position` - the position of the particle
     /// * `velocity` - the velocity of the particle
-    fn new(x: f32, y: f32) -> Self {
+    /// * `mass` - the mass of the particle
+    /// * `radius` - the collision radius of the particle
+    fn new(position: Vector3, velocity: Vector3, mass: f32, radius: f32) -> Self {
         Particle {
-            position: pt2(x, y),
-            velocity: vec2(0.0, 0.0),
+            id: ParticleId::UNASSIGNED,
+            position,
+            velocity,
+            acceleration: vec3(0.0, 0.0, 0.0),
             color: rgb(1.0, 1.0, 1.0),
-            radius: 8.0,
-            // mass of sun in kg:
-            mass: 1.98892e30,
+            radius,
+            mass,
+            material: Material::default(),
+            born: 0.0,
         }
     }
 
-    /// Update the particle's position.
-    ///
-    /// This method updates the position of the particle based on its velocity
-    /// and gravity, subject to F = G * (m1 * m2) / r^2.
+    /// Create a new particle whose radius and color come from its material.
     ///
     /// Arguments:
     ///
-    fn update_in_system(&mut self, particles: &Vec<Particle>) {
-        // Update the particle's position based on its velocity and gravity.
-        self.position += self.velocity;
-
-        // Get the gravitational force on the particle.
-        let mut force = vec2(0.0, 0.0);
-
-        // For each particle in the system, calculate the gravitational force
-        // on the particle.
-        particles.iter().for_each(|p| {
-            // Calculate the distance between the particle and the current
-            // particle.
-            let distance = self.position - p.position;
+    /// * `position` - the position of the particle
+    /// * `velocity` - the velocity of the particle
+    /// * `mass` - the mass of the particle
+    /// * `material` - what the particle is made of
+    /// * `units` - the units of the system the particle will join
+    fn of_material(
+        position: Vector3,
+        velocity: Vector3,
+        mass: f32,
+        material: Material,
+        units: &UnitSystem,
+    ) -> Self {
+        let mut particle = Particle::new(position, velocity, mass, material.radius(mass, units));
+        particle.color = material.color();
+        particle.material = material;
+        particle
+    }
 
-            // Calculate the magnitude of the distance.
-            let distance_magnitude = distance.magnitude();
+    /// Merge another particle into this one.
+    ///
+    /// The result keeps this particle's id until it is added to a store and
+    /// given a new one. It conserves the combined mass, momentum, and center of mass of
+    /// the two particles. The radius grows as if the two bodies were squashed
+    /// together (see material.rs), and the color is blended by mass fraction
+    /// (see coloring.rs).
+    ///
+    /// Arguments:
+    ///
+    /// * `other` - the particle to merge in
+    /// * `scaling` - how the radius grows with mass
+    fn merge(&self, other: &Particle, scaling: RadiusScaling) -> Particle {
+        let new_mass = self.mass + other.mass;
+        let t = other.mass / new_mass;
+        let new_radius = scaling.combine(self.radius, other.radius);
 
-            // Calculate the gravitational force.
-            let force_magnitude = (G * self.mass * p.mass) / distance_magnitude.powi(2);
+        // Color is mixed in Oklab, so the blend looks halfway between:
+        let new_color = coloring::mix(self.color, other.color, t);
 
-            // Calculate the direction of the gravitational force.
-            let force_direction = distance.normalize();
+        Particle {
+            id: self.id,
+            position: (self.position * self.mass + other.position * other.mass) / new_mass,
+            velocity: (self.velocity * self.mass + other.velocity * other.mass) / new_mass,
+            acceleration: vec3(0.0, 0.0, 0.0),
+            color: new_color,
+            radius: new_radius,
+            mass: new_mass,
+            material: self.material.mix(self.mass, &other.material, other.mass),
+            born: self.born,
+        }
+    }
 
-            // Calculate the gravitational force.
-            force += force_direction * force_magnitude;
-        });
+    /// Whether this is a massless tracer, which is moved by gravity but
+    /// doesn't pull on anything.
+    fn is_tracer(&self) -> bool {
+        self.mass == 0.0
+    }
 
-        // Update the particle's velocity based on its gravity.
-        self.velocity += force;
+    /// Whether every number describing the particle is finite.
+    fn is_finite(&self) -> bool {
+        let finite = |v: Vector3| v.x.is_finite() && v.y.is_finite() && v.z.is_finite();
+        finite(self.position)
+            && finite(self.velocity)
+            && finite(self.acceleration)
+            && self.mass.is_finite()
+            && self.radius.is_finite()
     }
 
-    // Draw the particle.
-    fn draw(&self, draw: &Draw) {
-        println!("{:?}", self.position);
+    // Draw the particle where it was projected into the window, in a color
+    // chosen by the caller and shaded by its depth.
+    fn draw(&self, draw: &Draw, at: &Projected, color: Rgb) {
         draw.ellipse()
-            .xy(self.position)
-            .radius(self.radius)
-            .color(self.color);
+            .xy(at.position)
+            .radius(at.radius(self.radius))
+            .color(at.shade(color));
     }
 }
 
@@ -158,126 +406,282 @@ impl Particle {
 /// step through time, modifying the particles' positions, and adding/removing
 /// particles as appropriate.
 ///
-/// We implement the update method, which updates the positions of the particles
-/// in parallel, using the common rust library rayon. This is a library that
-/// provides a thread pool, which is used to parallelize the work of updating
-/// the positions of the particles. This means that the particle positions are
-/// updated in parallel, and then we perform a final O(N) sweep to check for
-/// collisions.
+/// We implement the update method, which computes the forces on the particles
+/// in parallel with rayon, advances them with the chosen integrator, and then
+/// performs a final sweep to merge colliding particles.
 ///
 /// We also implement the draw method, which draws each particle.
 struct ParticleSystem {
-    particles: Vec<Particle>,
+    particles: ParticleStore,
+    solver: ForceSolver,
+    integrator: Integrator,
+    /// How each timestep is divided into shorter steps, if at all (see
+    /// timestep.rs).
+    timestep: Timestep,
+    /// The units that positions, masses and times are measured in.
+    units: UnitSystem,
+    /// The length of one timestep, in `units.time`: how far each update
+    /// advances, and the longest step any particle takes.
+    dt: f32,
+    /// The simulation time elapsed since the start, in `units.time`.
+    time: f64,
+    /// The Plummer softening length, in `units.length`.
+    softening: f32,
+    /// Whether the particles' cached accelerations need recomputing before
+    /// the next step, e.g. because particles were added or merged.
+    accelerations_stale: bool,
+    /// How touching particles collide.
+    collisions: CollisionModel,
+    /// Fixed analytic potentials, such as a dark-matter halo, felt on top of
+    /// the particles' own gravity (see potential.rs).
+    potentials: Vec<ExternalPotential>,
+    /// The edge of the simulated space, if it has one (see boundary.rs).
+    boundary: Boundary,
+    /// Particles that escaped through an absorbing boundary and were kept
+    /// where they left, no longer simulated.
+    parked: Vec<Particle>,
+    /// The random numbers used by collisions, seeded so runs reproduce.
+    rng: Rng,
+    /// Scratch space for the force solver and integrator, reused every step.
+    buffers: StepBuffers,
 }
 
-// Implement cloning for ParticleSystem, so that we can use it in a HashSet:
-// https://doc.rust-lang.org/std/collections/struct.HashSet.html
-// impl Clone for ParticleSystem {
-//     fn clone(&self) -> Self {
-//         ParticleSystem {
-//             particles: self.particles.clone(),
-//         }
-//     }
-// }
+/// Implement cloning for ParticleSystem, so that its state can be kept in the
+/// history (see history.rs). The copy gets its own, empty scratch space.
+impl Clone for ParticleSystem {
+    fn clone(&self) -> Self {
+        ParticleSystem {
+            particles: self.particles.clone(),
+            solver: self.solver,
+            integrator: self.integrator,
+            timestep: self.timestep,
+            units: self.units,
+            dt: self.dt,
+            time: self.time,
+            softening: self.softening,
+            accelerations_stale: self.accelerations_stale,
+            collisions: self.collisions,
+            potentials: self.potentials.clone(),
+            boundary: self.boundary,
+            parked: self.parked.clone(),
+            rng: self.rng.clone(),
+            buffers: StepBuffers::default(),
+        }
+    }
+}
 
 fn lerp(a: f32, b: f32, t: f32) -> f32 {
     a + (b - a) * t
 }
 
+/// What happened during one call to `ParticleSystem::update`.
+#[derive(Clone, Default)]
+struct StepReport {
+    /// Every group of particles that merged this step.
+    merges: Vec<MergeEvent>,
+    /// Every other collision this step: bounces, accretion and fragmentation.
+    impacts: Vec<ImpactEvent>,
+    /// Every tracer captured by a massive body this step.
+    captures: Vec<CaptureEvent>,
+    /// How the timestep was divided (see timestep.rs).
+    substeps: Substeps,
+    /// Particles that left through an absorbing boundary this step, as they
+    /// were when they crossed it.
+    escapes: Vec<Particle>,
+    /// Particles that wrapped around a periodic boundary this step.
+    wrapped: Vec<ParticleId>,
+    /// Particles that were removed because their state stopped being finite,
+    /// as they were when they were removed.
+    non_finite: Vec<Particle>,
+}
+
 /// Implementation of the ParticleSystem struct.
 impl ParticleSystem {
     /// Create a new particle system.
-    fn new() -> Self {
+    ///
+    /// The timestep starts at one day.
+    ///
+    /// Arguments:
+    ///
+    /// * `units` - the units that the system's numbers are measured in
+    fn new(units: UnitSystem) -> Self {
         ParticleSystem {
-            particles: Vec::new(),
+            particles: ParticleStore::new(),
+            solver: ForceSolver::default(),
+            integrator: Integrator::default(),
+            timestep: Timestep::default(),
+            units,
+            dt: units.time_from_seconds(86400.0),
+            time: 0.0,
+            softening: 0.0,
+            accelerations_stale: true,
+            buffers: StepBuffers::default(),
+            collisions: CollisionModel::default(),
+            potentials: Vec::new(),
+            boundary: Boundary::default(),
+            parked: Vec::new(),
+            rng: Rng::new(0),
         }
     }
 
-    /// Add a particle to the system.
-    fn add_particle(&mut self, particle: Particle) {
-        self.particles.push(particle);
+    /// The gravitational constant in this system's units.
+    fn g(&self) -> f32 {
+        self.units.gravitational_constant()
+    }
+
+    /// The gravitational constant and softening used by the force solver.
+    fn gravity(&self) -> Gravity {
+        Gravity {
+            g: self.g(),
+            softening: self.softening,
+            period: self.boundary.period(),
+        }
+    }
+
+    /// Roughly how many bytes a copy of the system takes, e.g. in the
+    /// history: everything `clone` copies, counting the heap.
+    fn heap_size(&self) -> usize {
+        mem::size_of::<ParticleSystem>()
+            + self.particles.heap_size()
+            + mem::size_of_val(self.potentials.as_slice())
+            + mem::size_of_val(self.parked.as_slice())
+    }
+
+    /// Switch to different units, converting every particle and the timestep
+    /// so that the simulation is physically unchanged.
+    fn set_units(&mut self, units: UnitSystem) {
+        let (length, mass, time) = self.units.factors_to(&units);
+        for p in self.particles.iter_mut().chain(self.parked.iter_mut()) {
+            p.position *= length;
+            p.velocity *= length / time;
+            p.acceleration *= length / (time * time);
+            p.radius *= length;
+            p.mass *= mass;
+        }
+        self.dt *= time;
+        self.time *= time as f64;
+        self.softening *= length;
+        for potential in self.potentials.iter_mut() {
+            potential.convert(length, mass, time);
+        }
+        self.boundary.convert(length);
+        self.units = units;
     }
 
-    /// Remove a particle from the system.
-    fn remove_particle(&mut self, particle: Particle) {
-        self.particles.retain(|p| p.position != particle.position);
+    /// Add a particle to the system.
+    ///
+    /// Returns:
+    ///
+    /// * `ParticleId` - the id the particle was given
+    fn add_particle(&mut self, mut particle: Particle) -> ParticleId {
+        self.accelerations_stale = true;
+        particle.born = self.time;
+        self.particles.insert(particle)
     }
 
     /// Update the particle system.
     ///
-    /// This method updates the positions of the particles in parallel, using the
-    /// common rust library rayon. This means that the particle positions are
-    /// updated in parallel, and then we perform a final O(N) sweep to check for
-    /// collisions.
-    fn update(&mut self) {
-        // Update the particle positions in parallel.
-
-        let parts = self.particles.clone();
-        self.particles.iter_mut().for_each(|p| {
-            // print location:
-            p.update_in_system(&parts);
-        });
-
-        // We hold on to a list of particles that we want to remove:
-        let mut to_remove = Vec::new();
-
-        // We also hold on to a list of particles that we want to add:
-        let mut to_add = Vec::new();
-
-        // Check for collisions.
-        for i in 0..self.particles.len() {
-            for j in i + 1..self.particles.len() {
-                let p1 = &self.particles[i];
-                let p2 = &self.particles[j];
-                let r = p1.position - p2.position;
-                let r_mag = r.magnitude();
-                if r_mag < p1.radius + p2.radius {
-                    // Combine the masses of the particles.
-                    let new_mass = p1.mass + p2.mass;
-                    let new_radius = (p1.radius * p1.mass + p2.radius * p2.mass) / new_mass;
-
-                    // Color is lerped in RGB space:
-                    let new_color_r = lerp(p1.color.red, p2.color.red, p1.mass / new_mass);
-                    let new_color_g = lerp(p1.color.green, p2.color.green, p1.mass / new_mass);
-                    let new_color_b = lerp(p1.color.blue, p2.color.blue, p1.mass / new_mass);
-                    let new_color = rgb(new_color_r, new_color_g, new_color_b);
-
-                    // Create a new particle with the combined mass, radius, and
-                    // color.
-                    let new_particle = Particle {
-                        position: (p1.position * p1.mass + p2.position * p2.mass) / new_mass,
-                        velocity: (p1.velocity * p1.mass + p2.velocity * p2.mass) / new_mass,
-                        color: new_color,
-                        radius: new_radius,
-                        mass: new_mass,
-                    };
-
-                    // Add the new particle to the list of particles to add.
-                    to_add.push(new_particle);
-
-                    // Add the particles to the list of particles to remove.
-                    to_remove.push(p1);
-                    to_remove.push(p2);
-
-                    // Break out of the inner loop.
-                    break;
-                }
-            }
+    /// This method computes the forces on the particles in parallel (see
+    /// gravity.rs), adds the pull of any external potentials (see
+    /// potential.rs), advances them by one timestep, in shorter steps if the
+    /// timestep scheme asks for them (see timestep.rs), keeps them inside the
+    /// boundary (see boundary.rs), and then performs a final sweep to merge
+    /// any particles that collided.
+    ///
+    /// Returns:
+    ///
+    /// * `StepReport` - what happened during the step, such as merges
+    fn update(&mut self) -> StepReport {
+        let solver = self.solver;
+        let gravity = self.gravity();
+        let (potentials, time) = (&self.potentials, self.time);
+        let forces = |particles: &[Particle], since: f32, out: &mut Vec<Vector3>| {
+            solver.accelerations_into(particles, gravity, out);
+            potential::add_accelerations(potentials, particles, gravity, time + since as f64, out);
+        };
+        let forces_of =
+            |particles: &[Particle], targets: &[usize], since: f32, out: &mut Vec<Vector3>| {
+                solver.accelerations_of(particles, targets, gravity, out);
+                let time = time + since as f64;
+                potential::add_accelerations_of(potentials, particles, targets, gravity, time, out);
+            };
+        if self.accelerations_stale {
+            self.buffers.refresh(self.particles.as_mut_slice(), forces);
+            self.accelerations_stale = false;
+        }
+        let substeps = self.timestep.advance(
+            self.integrator,
+            self.particles.as_mut_slice(),
+            self.dt,
+            self.softening,
+            &mut self.buffers,
+            forces,
+            forces_of,
+        );
+        self.time += self.dt as f64;
+
+        // Take out any particle whose state is no longer finite, before it
+        // spreads to the rest through the force solver.
+        let non_finite = self.particles.remove_where(|p| p.is_finite());
+        if !non_finite.is_empty() {
+            self.accelerations_stale = true;
         }
 
-        // Remove the particles that we want to remove.
-        // for p in to_remove {
-        //     // Mutably retain the particles that we want to keep.
-        //     self.particles
-        //         .retain(|particle| particle.position != p.position);
-        // }
+        // Wrap, bounce or take out the particles that left the box. The
+        // external potentials don't repeat with a periodic box, so a wrapped
+        // particle feels a different pull from them.
+        let Crossings { wrapped, escaped } = self.boundary.apply(&mut self.particles);
+        let external = self.potentials.iter().any(|p| p.enabled);
+        if !escaped.is_empty() || (external && !wrapped.is_empty()) {
+            self.accelerations_stale = true;
+        }
+        if self.boundary.parks() {
+            self.parked.extend(escaped.iter().cloned());
+        }
+
+        // Merge, bounce, accrete or shatter every touching pair.
+        let (merges, impacts, captures) = collision::resolve(
+            &mut self.particles,
+            &self.collisions,
+            gravity.g,
+            gravity.period,
+            &mut self.rng,
+        );
+        if !merges.is_empty() || !impacts.is_empty() {
+            self.accelerations_stale = true;
+        }
+        let newborn = merges
+            .iter()
+            .map(|m| m.id)
+            .chain(impacts.iter().flat_map(|i| i.fragments.iter().cloned()));
+        for id in newborn {
+            if let Some(p) = self.particles.get_mut(id) {
+                p.born = self.time;
+            }
+        }
 
-        // Add the particles that we want to add.
-        for p in to_add {
-            self.add_particle(p);
+        StepReport {
+            merges,
+            impacts,
+            captures,
+            substeps,
+            escapes: escaped,
+            wrapped,
+            non_finite,
         }
     }
 
+    /// Measure the system's conserved quantities.
+    fn diagnostics(&self) -> Diagnostics {
+        Diagnostics::measure(
+            self.particles.as_slice(),
+            self.solver,
+            self.gravity(),
+            &self.potentials,
+            self.time,
+        )
+    }
+
     /// Draw the particle system.
     ///
     /// This method draws each particle in the system.
@@ -285,13 +689,36 @@ impl ParticleSystem {
     /// Arguments:
     ///
     /// * `draw` - the draw context
+    /// * `viewport` - the mapping from simulation space to the window
+    /// * `colors` - a color for each particle, in the order the store keeps
+    ///   them (see coloring.rs); particles without one use their own
+    /// * `positions` - where to draw each particle, in the same order (see
+    ///   clock.rs)
     ///
     /// Returns:
     ///
     /// * `()` - this method does not return a value
-    fn draw(&self, draw: &Draw) {
-        for p in self.particles.iter() {
-            p.draw(draw);
+    fn draw(&self, draw: &Draw, viewport: &Viewport, colors: &[Rgb], positions: &[Vector3]) {
+        self.boundary.draw(draw, viewport);
+        // Parked particles are drawn in gray, behind the live ones.
+        for p in self.parked.iter() {
+            if let Some(at) = viewport.project(p.position) {
+                p.draw(draw, &at, rgb(0.4, 0.4, 0.4));
+            }
+        }
+
+        // Project every particle, and draw the farthest first so nearer
+        // bodies cover them:
+        let mut projected: Vec<(usize, Projected)> = positions
+            .iter()
+            .enumerate()
+            .filter_map(|(i, position)| viewport.project(*position).map(|at| (i, at)))
+            .collect();
+        projected.sort_by(|(_, a), (_, b)| b.depth.total_cmp(&a.depth));
+        let particles = self.particles.as_slice();
+        for (i, at) in projected {
+            let p = &particles[i];
+            p.draw(draw, &at, colors.get(i).cloned().unwrap_or(p.color));
         }
     }
 }
@@ -299,45 +726,1015 @@ impl ParticleSystem {
 struct Model {
     _window: window::Id,
     particle_system: ParticleSystem,
+    camera: Camera,
+    /// The units requested on the command line, if any.
+    units: Option<UnitSystem>,
+    /// The seed that scenes are generated from.
+    seed: u64,
+    /// Whether to show the diagnostics readout.
+    hud: bool,
+    /// The latest diagnostics, if anything needs them.
+    diagnostics: Option<Diagnostics>,
+    /// The total energy when the scene was loaded.
+    initial_energy: f32,
+    /// Where diagnostics are being streamed, if anywhere.
+    telemetry: Option<Telemetry>,
+    /// The recent path of every particle.
+    trails: Trails,
+    /// Whether the trails are recorded and drawn.
+    show_trails: bool,
+    /// The new body being aimed with the mouse, if any.
+    slingshot: Slingshot,
+    /// What the particles are colored by, and their colors.
+    coloring: Coloring,
+    /// How the latest timestep was divided.
+    substeps: Substeps,
+    /// Snapshots of the system to rewind and scrub through.
+    history: History,
+    /// Whether the simulation is running, paused or rewinding.
+    playback: Playback,
+    /// Whether the timeline bar is being dragged.
+    scrubbing: bool,
+    /// How many updates to run each frame, and where to draw the particles
+    /// between them.
+    clock: Clock,
 }
 
-fn model(app: &App) -> Model {
-    let _window = app.new_window().view(view).build().unwrap();
+/// Read the value of a `--name value` command line option, if given.
+fn arg_value(name: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    let i = args.iter().position(|a| a == name)?;
+    args.get(i + 1).cloned()
+}
+
+/// Read the `--units length,mass,time` command line option, if given.
+fn units_from_args() -> Option<UnitSystem> {
+    let value = arg_value("--units")?;
+    match value.parse() {
+        Ok(units) => Some(units),
+        Err(e) => {
+            eprintln!("ignoring --units: {}", e);
+            None
+        }
+    }
+}
+
+/// Read the `--trail-length N` and `--trail-decay x` command line options,
+/// falling back to the defaults for any that aren't given.
+fn trail_settings_from_args() -> TrailSettings {
+    let mut settings = TrailSettings::default();
+    if let Some(length) = arg_value("--trail-length").and_then(|s| s.parse().ok()) {
+        settings.length = length;
+    }
+    if let Some(decay) = arg_value("--trail-decay").and_then(|s| s.parse::<f32>().ok()) {
+        settings.decay = decay.clamp(0.0, 1.0);
+    }
+    settings
+}
+
+/// Read the `--color-by quantity`, `--colormap name` and `--color-range
+/// low,high` command line options, falling back to the defaults (each
+/// particle's own color, viridis, and an automatic range) for any that aren't
+/// given.
+fn coloring_from_args() -> Coloring {
+    let mut coloring = Coloring::default();
+    if let Some(value) = arg_value("--color-by") {
+        match value.parse() {
+            Ok(quantity) => coloring.quantity = quantity,
+            Err(e) => eprintln!("ignoring --color-by: {}", e),
+        }
+    }
+    if let Some(value) = arg_value("--colormap") {
+        match value.parse() {
+            Ok(colormap) => coloring.colormap = colormap,
+            Err(e) => eprintln!("ignoring --colormap: {}", e),
+        }
+    }
+    if let Some(value) = arg_value("--color-range") {
+        let bounds: Vec<f32> = value
+            .split(',')
+            .filter_map(|s| s.trim().parse().ok())
+            .collect();
+        match bounds[..] {
+            [low, high] if low < high => coloring.range = Range::Fixed { low, high },
+            _ => eprintln!(
+                "ignoring --color-range: expected low,high but got {:?}",
+                value
+            ),
+        }
+    }
+    coloring
+}
+
+/// Read the `--yaw degrees`, `--pitch degrees` and `--perspective` command
+/// line options into a viewport, leaving anything not given as it is.
+fn view_from_args(viewport: &mut Viewport) {
+    let degrees = |name: &str| match arg_value(name).map(|s| s.parse::<f32>()) {
+        Some(Ok(angle)) => angle.to_radians(),
+        Some(Err(e)) => {
+            eprintln!("ignoring {}: {}", name, e);
+            0.0
+        }
+        None => 0.0,
+    };
+    viewport.orbit(degrees("--yaw"), degrees("--pitch"));
+    if std::env::args().any(|a| a == "--perspective") {
+        viewport.perspective = true;
+    }
+}
 
-    // A grid of particles.
-    let mut particles = Vec::new();
-    // Randomly scatter 20 particles:
+/// Read every `--potential kind:name=value,...` command line option (see
+/// potential.rs), in the order given.
+fn potentials_from_args() -> Vec<ExternalPotential> {
+    let args: Vec<String> = std::env::args().collect();
+    args.windows(2)
+        .filter(|pair| pair[0] == "--potential")
+        .filter_map(|pair| match pair[1].parse() {
+            Ok(potential) => Some(potential),
+            Err(e) => {
+                eprintln!("ignoring --potential {}: {}", pair[1], e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Read the `--substeps N`, `--time-scale x` and `--max-steps-per-frame N`
+/// command line options, falling back to the defaults for any that aren't
+/// given.
+fn clock_from_args() -> Clock {
+    let mut clock = match arg_value("--substeps").and_then(|s| s.parse().ok()) {
+        Some(substeps) => Clock::new(substeps),
+        None => Clock::default(),
+    };
+    if let Some(scale) = arg_value("--time-scale").and_then(|s| s.parse::<f32>().ok()) {
+        if scale > 0.0 {
+            clock.time_scale = scale;
+        }
+    }
+    if let Some(max_steps) = arg_value("--max-steps-per-frame").and_then(|s| s.parse().ok()) {
+        clock.max_steps = max_steps;
+    }
+    clock
+}
+
+/// Read the `--history-depth N` and `--history-stride K` command line
+/// options, falling back to the defaults for any that aren't given.
+fn history_settings_from_args() -> HistorySettings {
+    let mut settings = HistorySettings::default();
+    if let Some(depth) = arg_value("--history-depth").and_then(|s| s.parse().ok()) {
+        settings.depth = depth;
+    }
+    if let Some(stride) = arg_value("--history-stride").and_then(|s| s.parse().ok()) {
+        settings.stride = stride;
+    }
+    settings
+}
+
+/// Read the `--timestep kind:name=value,...` command line option (see
+/// timestep.rs), if given.
+fn timestep_from_args() -> Option<Timestep> {
+    let value = arg_value("--timestep")?;
+    match value.parse() {
+        Ok(timestep) => Some(timestep),
+        Err(e) => {
+            eprintln!("ignoring --timestep: {}", e);
+            None
+        }
+    }
+}
+
+/// Read the `--solver kind:name=value,...` command line option (see
+/// gravity.rs), if given.
+fn solver_from_args() -> Option<ForceSolver> {
+    let value = arg_value("--solver")?;
+    match value.parse() {
+        Ok(solver) => Some(solver),
+        Err(e) => {
+            eprintln!("ignoring --solver: {}", e);
+            None
+        }
+    }
+}
+
+/// Read the `--boundary kind:name=value,...` command line option (see
+/// boundary.rs), if given.
+fn boundary_from_args() -> Option<Boundary> {
+    let value = arg_value("--boundary")?;
+    match value.parse() {
+        Ok(boundary) => Some(boundary),
+        Err(e) => {
+            eprintln!("ignoring --boundary: {}", e);
+            None
+        }
+    }
+}
+
+/// The side of a cube centered on the origin that holds every particle of a
+/// system, with a little room to spare.
+fn enclosing_size(system: &ParticleSystem) -> f32 {
+    let extent = system
+        .particles
+        .iter()
+        .map(|p| {
+            let v = p.position;
+            v.x.abs().max(v.y.abs()).max(v.z.abs()) + p.radius
+        })
+        .fold(0.0, f32::max);
+    if extent > 0.0 {
+        2.2 * extent
+    } else {
+        1.0
+    }
+}
+
+/// Size rayon's global thread pool from the `--threads N` command line
+/// option. Without it, rayon uses one thread per core.
+fn threads_from_args() {
+    let threads = match arg_value("--threads").map(|s| s.parse::<usize>()) {
+        Some(Ok(threads)) => threads,
+        Some(Err(e)) => {
+            eprintln!("ignoring --threads: {}", e);
+            return;
+        }
+        None => return,
+    };
+    if let Err(e) = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build_global()
+    {
+        eprintln!("ignoring --threads: {}", e);
+    }
+}
+
+/// The scale that fits every particle of a system, around its center of mass,
+/// in a window or image.
+///
+/// Arguments:
+///
+/// * `system` - the system to fit
+/// * `width` - the width of the window, in pixels
+/// * `height` - the height of the window, in pixels
+///
+/// Returns:
+///
+/// * `f32` - pixels per length unit of the system
+fn fit_to_size(system: &ParticleSystem, width: f32, height: f32) -> f32 {
+    let center = system.diagnostics().center_of_mass;
+    let extent = system
+        .particles
+        .iter()
+        .map(|p| (p.position - center).magnitude() + p.radius)
+        .fold(0.0, f32::max);
+    let half_window = 0.5 * width.min(height);
+    if extent > 0.0 {
+        0.9 * half_window / extent
+    } else {
+        1.0
+    }
+}
+
+/// Show a new scene, converting it to the requested units and fitting the
+/// viewport to them.
+///
+/// Arguments:
+///
+/// * `model` - the model to show the scene in
+/// * `system` - the scene
+/// * `pixels_per_unit` - the scale to show the scene at, in the scene's own
+///   length units
+fn load_scene(model: &mut Model, mut system: ParticleSystem, pixels_per_unit: f32) {
+    // Keep looking from the same direction:
+    let mut viewport = Viewport {
+        center: vec3(0.0, 0.0, 0.0),
+        pixels_per_unit,
+        ..model.camera.viewport
+    };
+    if let Some(units) = model.units {
+        let (length, _, _) = system.units.factors_to(&units);
+        system.set_units(units);
+        viewport.pixels_per_unit /= length;
+    }
+    system.potentials.extend(potentials_from_args());
+    if let Some(timestep) = timestep_from_args() {
+        system.timestep = timestep;
+    }
+    if let Some(boundary) = boundary_from_args() {
+        system.boundary = boundary;
+    }
+    if let Some(solver) = solver_from_args() {
+        system.solver = solver;
+    }
+    println!("units: {}, G = {:e}", system.units, system.g());
+    println!("solver: {}", system.solver);
+    println!("timestep: {}", system.timestep);
+    println!("boundary: {}", system.boundary);
+    for (i, potential) in system.potentials.iter().enumerate() {
+        println!("potential F{}: {}", i + 1, potential);
+    }
+    let diagnostics = system.diagnostics();
+    model.initial_energy = diagnostics.energy();
+    model.diagnostics = Some(diagnostics);
+    model.history.reset(&system);
+    model.particle_system = system;
+    model.camera.viewport = viewport;
+    model.trails.clear();
+    model.clock.forget();
+}
+
+/// Show a snapshot from the history in place of the live system.
+fn show_snapshot(model: &mut Model, snapshot: ParticleSystem) {
+    model.particle_system = snapshot;
+    // The trails were left by the old state, so they'd point the wrong way.
+    model.trails.clear();
+    model.clock.forget();
+    if model.hud {
+        model.diagnostics = Some(model.particle_system.diagnostics());
+    }
+}
+
+/// Step back to the previous snapshot in the history, if there is one.
+///
+/// Returns:
+///
+/// * `bool` - whether there was one
+fn show_earlier(model: &mut Model) -> bool {
+    match model.history.earlier(&model.particle_system).cloned() {
+        Some(snapshot) => {
+            show_snapshot(model, snapshot);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Step forward one update: to the next snapshot in the history if one is
+/// being looked at, or else by simulating.
+fn step_forward(model: &mut Model) {
+    if !model.history.is_live() {
+        if let Some(snapshot) = model.history.later().cloned() {
+            show_snapshot(model, snapshot);
+            return;
+        }
+        model.history.fork();
+    }
+    advance(model);
+}
+
+/// Show the snapshot a fraction of the way along the history.
+fn scrub_to(model: &mut Model, fraction: f32) {
+    model.playback = Playback::Paused;
+    if let Some(snapshot) = model
+        .history
+        .scrub(fraction, &model.particle_system)
+        .cloned()
+    {
+        show_snapshot(model, snapshot);
+    }
+}
+
+/// Open the `--telemetry path` file, if one was asked for.
+fn telemetry_from_args() -> Option<Telemetry> {
+    let path = arg_value("--telemetry")?;
+    match Telemetry::create(&path) {
+        Ok(telemetry) => Some(telemetry),
+        Err(e) => {
+            eprintln!("couldn't open telemetry file {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Five bloated suns of different temperatures scattered at random within an
+/// AU of the origin, at rest.
+fn scattered_suns() -> ParticleSystem {
+    let mut system = ParticleSystem::new(UnitSystem::SOLAR);
+    // Randomly scatter 5 particles:
     for _ in 0..5 {
-        let x = random_range(-100.0, 100.0);
-        let y = random_range(-100.0, 100.0);
-        let particle = Particle::new(x, y);
-        particles.push(particle);
+        let x = random_range(-1.0, 1.0);
+        let y = random_range(-1.0, 1.0);
+        let mut sun = Particle::new(pt3(x, y, 0.0), vec3(0.0, 0.0, 0.0), 1.0, 0.04);
+        sun.material = Material::Star {
+            temperature: random_range(3000.0, 12000.0),
+        };
+        sun.color = sun.material.color();
+        system.add_particle(sun);
+    }
+    system.softening = 0.01;
+    system.collisions.allowed = AllowedOutcomes::ALL;
+    system
+}
+
+/// The Sun and the Earth on a circular orbit, which takes one year.
+fn sun_and_earth() -> ParticleSystem {
+    let mut system = ParticleSystem::new(UnitSystem::SOLAR);
+    let earth_mass = system.units.mass_from_kilograms(5.9722e24);
+
+    let sun_mass = 1.0;
+    let distance = 1.0;
+
+    // Circular speed v = sqrt(G * M / r), which is 2 * pi AU/yr at 1 AU.
+    let earth_speed = (system.g() * sun_mass / distance).sqrt();
+
+    // Their radii follow from their densities: 6.96e8 m and 6.38e6 m.
+    let sun = Particle::of_material(
+        pt3(0.0, 0.0, 0.0),
+        vec3(0.0, 0.0, 0.0),
+        sun_mass,
+        Material::default(),
+        &system.units,
+    );
+    let earth = Particle::of_material(
+        pt3(distance, 0.0, 0.0),
+        vec3(0.0, earth_speed, 0.0),
+        earth_mass,
+        Material::Rock,
+        &system.units,
+    );
+    system.add_particle(sun);
+    system.add_particle(earth);
+    system.collisions.allowed = AllowedOutcomes::ALL;
+    system
+}
+
+/// An exponential disk galaxy around a central mass, in a dark-matter halo
+/// that keeps its rotation curve flat.
+fn disk_galaxy(seed: u64) -> ParticleSystem {
+    let mut system = ParticleSystem::new(UnitSystem::GALACTIC);
+    system.softening = 50.0;
+    system.dt = 0.2;
+    // About 1e12 solar masses inside ten scale radii, like the Milky Way's.
+    system
+        .potentials
+        .push(ExternalPotential::new(Potential::Nfw {
+            mass: 6.7e11,
+            scale_radius: 20000.0,
+        }));
+    initial_conditions::exponential_disk(&mut system, &DiskParams::default(), seed);
+    system
+}
+
+/// A Plummer star cluster in virial equilibrium.
+fn star_cluster(seed: u64) -> ParticleSystem {
+    let mut system = ParticleSystem::new(UnitSystem::GALACTIC);
+    system.softening = 0.2;
+    system.dt = 0.01;
+    initial_conditions::plummer_sphere(&mut system, &PlummerParams::default(), seed);
+    system
+}
+
+/// A star with planets on Keplerian orbits.
+fn planetary_system(seed: u64) -> ParticleSystem {
+    let mut system = ParticleSystem::new(UnitSystem::SOLAR);
+    initial_conditions::kepler_system(&mut system, &KeplerParams::default(), seed);
+    system.collisions.allowed = AllowedOutcomes::ALL;
+    system.rng = Rng::new(seed);
+    system
+}
+
+/// Two disk galaxies on a collision course.
+fn galaxy_collision(seed: u64) -> ParticleSystem {
+    let mut system = ParticleSystem::new(UnitSystem::GALACTIC);
+    system.softening = 50.0;
+    system.dt = 0.2;
+    initial_conditions::galaxy_merger(&mut system, &MergerParams::default(), seed);
+    system
+}
+
+/// A hundred thousand massless tracer stars in a disk around a few dozen
+/// heavy clumps, in a dark-matter halo.
+fn tracer_disk(seed: u64) -> ParticleSystem {
+    let mut system = ParticleSystem::new(UnitSystem::GALACTIC);
+    system.softening = 50.0;
+    system.dt = 0.2;
+    system
+        .potentials
+        .push(ExternalPotential::new(Potential::Nfw {
+            mass: 6.7e11,
+            scale_radius: 20000.0,
+        }));
+    let params = DiskParams {
+        star_count: 40,
+        tracer_count: 100_000,
+        star_radius: 5.0,
+        ..DiskParams::default()
+    };
+    initial_conditions::exponential_disk(&mut system, &params, seed);
+    system
+}
+
+/// Build and show one of the numbered scenes.
+fn load_numbered_scene(model: &mut Model, number: u8) {
+    if let Some((system, pixels_per_unit)) = numbered_scene(number, model.seed) {
+        load_scene(model, system, pixels_per_unit);
     }
+}
+
+/// Build one of the numbered scenes.
+///
+/// Arguments:
+///
+/// * `number` - which scene, from 1 to 7
+/// * `seed` - the random seed for the scenes that use one
+///
+/// Returns:
+///
+/// * `Option<(ParticleSystem, f32)>` - the scene and a scale, in pixels per
+///   length unit, that fits it in the window; or None for an unknown number
+fn numbered_scene(number: u8, seed: u64) -> Option<(ParticleSystem, f32)> {
+    let scene = match number {
+        1 => (scattered_suns(), 200.0),
+        2 => (sun_and_earth(), 200.0),
+        3 => (disk_galaxy(seed), 0.03),
+        4 => (star_cluster(seed), 25.0),
+        5 => (planetary_system(seed), 80.0),
+        6 => (galaxy_collision(seed), 0.01),
+        7 => (tracer_disk(seed), 0.03),
+        _ => return None,
+    };
+    Some(scene)
+}
 
-    Model {
-        _window: _window,
-        particle_system: ParticleSystem {
-            particles: particles,
+fn model(app: &App) -> Model {
+    let _window = app
+        .new_window()
+        .view(view)
+        .key_pressed(key_pressed)
+        .mouse_pressed(mouse_pressed)
+        .mouse_released(mouse_released)
+        .mouse_moved(mouse_moved)
+        .mouse_wheel(mouse_wheel)
+        .build()
+        .unwrap();
+
+    let mut model = Model {
+        _window,
+        particle_system: ParticleSystem::new(UnitSystem::SOLAR),
+        camera: Camera::new(Viewport::new(200.0)),
+        units: units_from_args(),
+        seed: arg_value("--seed")
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1),
+        hud: false,
+        diagnostics: None,
+        initial_energy: 0.0,
+        telemetry: telemetry_from_args(),
+        trails: Trails::new(trail_settings_from_args()),
+        show_trails: true,
+        slingshot: Slingshot::default(),
+        coloring: coloring_from_args(),
+        substeps: Substeps::default(),
+        history: History::new(history_settings_from_args()),
+        playback: Playback::Playing,
+        scrubbing: false,
+        clock: clock_from_args(),
+    };
+    view_from_args(&mut model.camera.viewport);
+    match arg_value("--scene") {
+        Some(path) => match scene::load(Path::new(&path)) {
+            Ok(system) => {
+                let window = app.window_rect();
+                let pixels_per_unit = fit_to_size(&system, window.w(), window.h());
+                load_scene(&mut model, system, pixels_per_unit);
+            }
+            Err(e) => {
+                eprintln!("couldn't load scene {}: {}", path, e);
+                load_numbered_scene(&mut model, 1);
+            }
         },
+        None => load_numbered_scene(&mut model, 1),
+    }
+    model
+}
+
+fn update(app: &App, model: &mut Model, update: Update) {
+    match model.playback {
+        // Run as many updates as the time since the last frame paid for,
+        // remembering where the particles were before the last of them.
+        Playback::Playing => {
+            let steps = model.clock.tick(update.since_last);
+            for step in 0..steps {
+                if step + 1 == steps {
+                    model.clock.remember(&model.particle_system.particles);
+                }
+                advance(model);
+            }
+        }
+        Playback::Paused => model.clock.forget(),
+        Playback::Rewinding => {
+            model.clock.forget();
+            if !show_earlier(model) {
+                model.playback = Playback::Paused;
+            }
+        }
     }
+
+    model.camera.update(&model.particle_system.particles);
+    if model.slingshot.is_pulling() {
+        model
+            .slingshot
+            .update_preview(&model.particle_system, spawn_mass_factor(app));
+    }
+    model.coloring.update(&model.particle_system);
 }
 
-fn update(_app: &App, model: &mut Model, _update: Update) {
+/// Advance the live simulation by one update, and record what happened.
+fn advance(model: &mut Model) {
     // Update all the particles.
-    model.particle_system.update();
+    let report = model.particle_system.update();
+    for merge in report.merges.iter() {
+        println!(
+            "merged {} into {} of mass {:e} at {:?}",
+            merge
+                .parents
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            merge.id,
+            merge.mass,
+            merge.position
+        );
+    }
+    for impact in report.impacts.iter() {
+        println!(
+            "{:?}: {} and {} at {:.2} times their binding energy{}",
+            impact.outcome,
+            impact.bodies[0],
+            impact.bodies[1],
+            impact.energy_ratio,
+            if impact.fragments.is_empty() {
+                String::new()
+            } else {
+                format!(", into {} fragments", impact.fragments.len())
+            }
+        );
+    }
+    if !report.captures.is_empty() {
+        println!("{} tracers captured", report.captures.len());
+    }
+    let (tracers, bodies): (Vec<&Particle>, Vec<&Particle>) =
+        report.escapes.iter().partition(|p| p.is_tracer());
+    for p in bodies {
+        println!(
+            "{} of mass {:e} escaped at {:?} with velocity {:?}",
+            p.id, p.mass, p.position, p.velocity
+        );
+    }
+    if !tracers.is_empty() {
+        println!("{} tracers escaped", tracers.len());
+    }
+    // Don't draw a wrapped particle sweeping back across the box.
+    model.clock.jumped(&report.wrapped);
+    model.substeps = report.substeps;
+    for p in report.non_finite.iter() {
+        eprintln!(
+            "removed {}: non-finite state (position {:?}, velocity {:?}, mass {})",
+            p.id, p.position, p.velocity, p.mass
+        );
+    }
+    model.history.record(&model.particle_system);
+
+    if model.show_trails {
+        model.trails.follow_merges(&report.merges);
+        model.trails.cut(&report.wrapped);
+        model.trails.record(&model.particle_system.particles);
+    }
+
+    // Only pay for the diagnostics when someone is looking at them.
+    if model.hud || model.telemetry.is_some() {
+        let diagnostics = model.particle_system.diagnostics();
+        if let Some(telemetry) = model.telemetry.as_mut() {
+            if let Err(e) = telemetry
+                .record(&diagnostics)
+                .and_then(|_| telemetry.flush())
+            {
+                eprintln!("stopped writing telemetry: {}", e);
+                model.telemetry = None;
+            }
+        }
+        model.diagnostics = Some(diagnostics);
+    }
+}
+
+fn key_pressed(_app: &App, model: &mut Model, key: Key) {
+    let system = &mut model.particle_system;
+    match key {
+        // Cycle through the force solvers.
+        Key::B => {
+            system.solver = system.solver.next();
+            system.accelerations_stale = true;
+            println!("solver: {}", system.solver);
+        }
+        // Cycle through the integrators.
+        Key::I => {
+            system.integrator = system.integrator.next();
+            println!("integrator: {:?}", system.integrator);
+        }
+        // Cycle through the timestep schemes.
+        Key::A => {
+            system.timestep = system.timestep.next();
+            println!("timestep: {}", system.timestep);
+        }
+        // Cycle through the boundaries, with a box around the whole system.
+        Key::W => {
+            system.boundary = system.boundary.next(enclosing_size(system));
+            system.accelerations_stale = true;
+            println!("boundary: {}", system.boundary);
+        }
+        // Measure how much accuracy the current solver loses.
+        Key::E => {
+            let error =
+                gravity::solver_error(system.solver, system.particles.as_slice(), system.gravity());
+            println!(
+                "{}: mean relative error {:.2e}, max {:.2e}",
+                system.solver, error.mean, error.max
+            );
+        }
+        // Halve or double the softening length.
+        Key::LBracket | Key::RBracket => {
+            let factor = if key == Key::LBracket { 0.5 } else { 2.0 };
+            // Start from a hundredth of an AU if softening was off.
+            if system.softening == 0.0 {
+                system.softening = system.units.length_from_meters(1.495978707e9);
+            }
+            system.softening *= factor;
+            system.accelerations_stale = true;
+            println!(
+                "softening: {:e} {}",
+                system.softening,
+                system.units.length.symbol()
+            );
+        }
+        // Show or hide the diagnostics.
+        Key::H => model.hud = !model.hud,
+        // Save the scene, to `--save path` or galaxy.ron.
+        Key::S => {
+            let path = arg_value("--save").unwrap_or_else(|| "galaxy.ron".to_string());
+            match scene::save(system, Path::new(&path)) {
+                Ok(()) => println!("saved the scene to {}", path),
+                Err(e) => eprintln!("couldn't save the scene to {}: {}", path, e),
+            }
+        }
+        // Cycle through what the camera follows.
+        Key::F => {
+            model.camera.follow = model.camera.follow.next();
+            println!("follow: {:?}", model.camera.follow);
+        }
+        // Show or hide the grid and scale bar.
+        Key::G => model.camera.show_grid = !model.camera.show_grid,
+        // Show or hide the trails.
+        Key::T => {
+            model.show_trails = !model.show_trails;
+            model.trails.clear();
+        }
+        // Cycle through what the particles are colored by.
+        Key::C => {
+            model.coloring.quantity = model.coloring.quantity.next();
+            println!("coloring: {}", model.coloring);
+        }
+        // Cycle through the colormaps.
+        Key::M => {
+            model.coloring.colormap = model.coloring.colormap.next();
+            println!("coloring: {}", model.coloring);
+        }
+        // Fix the color range where it is, or go back to fitting it.
+        Key::R => {
+            model.coloring.toggle_range();
+            println!("color range: {:?}", model.coloring.range);
+        }
+        // Turn one of the external potentials on or off.
+        Key::F1 | Key::F2 | Key::F3 | Key::F4 | Key::F5 | Key::F6 => {
+            let i = match key {
+                Key::F1 => 0,
+                Key::F2 => 1,
+                Key::F3 => 2,
+                Key::F4 => 3,
+                Key::F5 => 4,
+                _ => 5,
+            };
+            if let Some(potential) = system.potentials.get_mut(i) {
+                potential.enabled = !potential.enabled;
+                system.accelerations_stale = true;
+                println!("potential F{}: {}", i + 1, potential);
+            }
+        }
+        // Pause, or resume from whatever is being shown, forking the
+        // timeline if it's from the history.
+        Key::Space => {
+            model.playback = match model.playback {
+                Playback::Playing | Playback::Rewinding => Playback::Paused,
+                Playback::Paused => {
+                    model.history.fork();
+                    Playback::Playing
+                }
+            };
+        }
+        // Step one update forwards or back.
+        Key::Period => {
+            model.playback = Playback::Paused;
+            step_forward(model);
+        }
+        Key::Comma => {
+            model.playback = Playback::Paused;
+            show_earlier(model);
+        }
+        // Run backwards through the history, or stop.
+        Key::Back => {
+            model.playback = match model.playback {
+                Playback::Rewinding => Playback::Paused,
+                _ => Playback::Rewinding,
+            };
+        }
+        // Speed up or slow down the simulation.
+        Key::Equals | Key::Minus => {
+            model.clock.change_speed(key == Key::Equals);
+            println!("time scale: {}", model.clock.time_scale);
+        }
+        // Jump to the start or the end of the history.
+        Key::Home => scrub_to(model, 0.0),
+        Key::End => scrub_to(model, 1.0),
+        // Orbit the camera around the middle of the window.
+        Key::Left => model.camera.viewport.orbit(-0.05, 0.0),
+        Key::Right => model.camera.viewport.orbit(0.05, 0.0),
+        Key::Up => model.camera.viewport.orbit(0.0, 0.05),
+        Key::Down => model.camera.viewport.orbit(0.0, -0.05),
+        // Switch between perspective and orthographic drawing.
+        Key::V => {
+            let viewport = &mut model.camera.viewport;
+            viewport.perspective = !viewport.perspective;
+            println!("perspective: {}", viewport.perspective);
+        }
+        // Go back to looking straight down on the x-y plane.
+        Key::O => {
+            let viewport = &mut model.camera.viewport;
+            viewport.yaw = 0.0;
+            viewport.pitch = 0.0;
+            viewport.perspective = false;
+        }
+        // Load one of the preset scenes.
+        Key::Key1 => load_numbered_scene(model, 1),
+        Key::Key2 => load_numbered_scene(model, 2),
+        Key::Key3 => load_numbered_scene(model, 3),
+        Key::Key4 => load_numbered_scene(model, 4),
+        Key::Key5 => load_numbered_scene(model, 5),
+        Key::Key6 => load_numbered_scene(model, 6),
+        Key::Key7 => load_numbered_scene(model, 7),
+        _ => {}
+    }
+}
+
+/// The factor on a launched body's mass from the modifier keys held down:
+/// shift for ten times heavier, alt for ten times lighter.
+fn spawn_mass_factor(app: &App) -> f32 {
+    if app.keys.mods.shift() {
+        10.0
+    } else if app.keys.mods.alt() {
+        0.1
+    } else {
+        1.0
+    }
+}
+
+fn mouse_pressed(app: &App, model: &mut Model, button: MouseButton) {
+    match button {
+        // Scrub with the timeline bar, when it's showing.
+        MouseButton::Left if model.playback != Playback::Playing => {
+            if let Some(fraction) = model.history.hit(app.window_rect(), app.mouse.position()) {
+                model.scrubbing = true;
+                scrub_to(model, fraction);
+            } else {
+                let at = model.camera.viewport.to_world(app.mouse.position());
+                model.slingshot.start(at);
+            }
+        }
+        MouseButton::Left => {
+            let at = model.camera.viewport.to_world(app.mouse.position());
+            model.slingshot.start(at);
+        }
+        // Orbit with the middle button, or the right one with shift held.
+        MouseButton::Middle => model.camera.start_orbit(app.mouse.position()),
+        MouseButton::Right if app.keys.mods.shift() => {
+            model.camera.start_orbit(app.mouse.position())
+        }
+        MouseButton::Right => model.camera.start_drag(app.mouse.position()),
+        _ => {}
+    }
+}
+
+fn mouse_released(app: &App, model: &mut Model, button: MouseButton) {
+    match button {
+        MouseButton::Left if model.scrubbing => model.scrubbing = false,
+        MouseButton::Left => {
+            // A body added to a snapshot starts a new timeline from it.
+            if model.slingshot.is_pulling() {
+                model.history.fork();
+            }
+            let mass_factor = spawn_mass_factor(app);
+            model
+                .slingshot
+                .release(&mut model.particle_system, mass_factor);
+        }
+        MouseButton::Right | MouseButton::Middle => model.camera.end_drag(),
+        _ => {}
+    }
+}
+
+fn mouse_moved(app: &App, model: &mut Model, position: Point2) {
+    if model.scrubbing {
+        if let Some(fraction) = model.history.hit(app.window_rect(), position) {
+            scrub_to(model, fraction);
+        }
+        return;
+    }
+    model.camera.drag_to(position);
+    model
+        .slingshot
+        .pull_to(model.camera.viewport.to_world(position));
+}
+
+fn mouse_wheel(app: &App, model: &mut Model, delta: MouseScrollDelta, _phase: TouchPhase) {
+    // While aiming, the wheel picks the new body's mass; otherwise it zooms.
+    if model.slingshot.is_pulling() {
+        model.slingshot.scroll(delta);
+    } else {
+        model.camera.scroll(app.mouse.position(), delta);
+    }
 }
 
 fn view(app: &App, model: &Model, frame: Frame) {
     let draw = app.draw();
     draw.background().color(BLACK);
 
-    // model.particle_system.draw(&draw);
-    model.particle_system.particles.iter().for_each(|p| {
-        draw.ellipse()
-            .xy(p.position)
-            .radius(p.radius)
-            .color(p.color);
-    });
+    model.camera.draw_grid(
+        &draw,
+        app.window_rect(),
+        model.particle_system.units.length.symbol(),
+    );
+
+    let positions = model.clock.positions(&model.particle_system.particles);
+    if model.show_trails {
+        model.trails.draw(
+            &draw,
+            &model.camera.viewport,
+            &model.particle_system.particles,
+            model.coloring.colors(),
+            &positions,
+        );
+    }
+    model.particle_system.draw(
+        &draw,
+        &model.camera.viewport,
+        model.coloring.colors(),
+        &positions,
+    );
+    model.slingshot.draw(&draw, &model.camera.viewport);
+
+    model.coloring.draw_legend(&draw, app.window_rect());
+    if model.playback != Playback::Playing {
+        model.history.draw(
+            &draw,
+            app.window_rect(),
+            model.playback,
+            &model.particle_system,
+        );
+    }
+
+    if model.hud {
+        if let Some(diagnostics) = model.diagnostics.as_ref() {
+            let system = &model.particle_system;
+            let mut lines = diagnostics.hud_lines(model.initial_energy);
+            lines.push(format!("dt       {:.4e}  {}", system.dt, system.timestep));
+            lines.push(format!(
+                "boundary {}  ({} parked)",
+                system.boundary,
+                system.parked.len()
+            ));
+            lines.push(format!(
+                "substeps {}  (shortest {:.4e})",
+                model.substeps.count, model.substeps.shortest
+            ));
+            lines.push(format!(
+                "speed    x{}  ({} updates per frame)",
+                model.clock.time_scale, model.clock.substeps
+            ));
+            draw_hud(&draw, app.window_rect(), &lines);
+        }
+    }
 
     draw.to_frame(app, &frame).unwrap();
 }
+
+/// Draw lines of text in the top-left corner of the window.
+fn draw_hud(draw: &Draw, window: Rect, lines: &[String]) {
+    let line_height = 14.0;
+    for (i, line) in lines.iter().enumerate() {
+        let y = window.top() - 10.0 - line_height * i as f32;
+        draw.text(line)
+            .x_y(window.left() + 160.0, y)
+            .w_h(300.0, line_height)
+            .left_justify()
+            .font_size(11)
+            .color(WHITE);
+    }
+}
@@ -0,0 +1,225 @@
+/*
+What the bodies in the galaxy simulation are made of.
+
+Every particle has a `Material`, which gives it a density and a color. A body's
+radius follows from its mass and density: a sphere of mass m and density rho
+has radius
+
+    r = (3 m / (4 pi rho))^(1/3)
+
+so a body eight times heavier is twice as wide. Rock is densest, then stars
+(the Sun averages about 1.4 g/cm^3), gas giants and ice. Rocks, ices and gases
+have a color each; stars are colored by their surface temperature, from red
+dwarfs through the yellow-white Sun to blue giants.
+
+When bodies merge, their volumes add up rather than their radii averaging, so
+two suns make a body about 1.26 times as wide as either. The merged body's
+density is then the mass-weighted harmonic mean of its parents' densities, so
+mixing rock into gas makes something in between. Seen from above a flat
+simulation, it can look better for bodies to grow by area instead (two suns
+make a body 1.41 times as wide); `RadiusScaling` picks between the two.
+
+The merged body's color is its parents' colors blended by mass fraction. It
+keeps the material of the heavier parent, except that two stars make a star
+with the mass-weighted mean of their temperatures.
+*/
+
+use nannou::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::lerp;
+use crate::units::UnitSystem;
+
+/// The surface temperature of the Sun, in kelvin.
+pub const SUN_TEMPERATURE: f32 = 5772.0;
+
+/// What a body is made of.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Material {
+    /// Rocky planets, moons and asteroids.
+    Rock,
+    /// Icy bodies such as comets.
+    Ice,
+    /// Gas giants.
+    Gas,
+    /// A star with a surface temperature, in kelvin.
+    Star { temperature: f32 },
+}
+
+/// Particles that don't say otherwise are stars like the Sun.
+impl Default for Material {
+    fn default() -> Self {
+        Material::Star {
+            temperature: SUN_TEMPERATURE,
+        }
+    }
+}
+
+impl Material {
+    /// The material's mean density, in kg/m^3.
+    pub fn density(&self) -> f64 {
+        match self {
+            Material::Rock => 5500.0,
+            Material::Ice => 1000.0,
+            Material::Gas => 1300.0,
+            Material::Star { .. } => 1410.0,
+        }
+    }
+
+    /// The color a body of this material is drawn in.
+    pub fn color(&self) -> Rgb {
+        match self {
+            Material::Rock => rgb(0.3, 0.5, 1.0),
+            Material::Ice => rgb(0.8, 0.95, 1.0),
+            Material::Gas => rgb(0.9, 0.7, 0.45),
+            Material::Star { temperature } => temperature_color(*temperature),
+        }
+    }
+
+    /// The radius of a sphere of this material.
+    ///
+    /// Arguments:
+    ///
+    /// * `mass` - the sphere's mass
+    /// * `units` - the units `mass` is in, and the radius is returned in
+    ///
+    /// Returns:
+    ///
+    /// * `f32` - the sphere's radius
+    pub fn radius(&self, mass: f32, units: &UnitSystem) -> f32 {
+        let kilograms = mass as f64 * units.mass.kilograms();
+        let volume = kilograms / self.density();
+        units.length_from_meters((3.0 * volume / (4.0 * std::f64::consts::PI)).cbrt())
+    }
+
+    /// The material of a body made by merging two others.
+    ///
+    /// Arguments:
+    ///
+    /// * `mass` - this body's mass
+    /// * `other` - the other body's material
+    /// * `other_mass` - the other body's mass
+    pub fn mix(&self, mass: f32, other: &Material, other_mass: f32) -> Material {
+        match (self, other) {
+            (Material::Star { temperature: a }, Material::Star { temperature: b }) => {
+                let total = mass + other_mass;
+                let t = if total > 0.0 { other_mass / total } else { 0.5 };
+                Material::Star {
+                    temperature: lerp(*a, *b, t),
+                }
+            }
+            _ if other_mass > mass => *other,
+            _ => *self,
+        }
+    }
+}
+
+/// How a body's radius follows its mass as it grows or breaks up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RadiusScaling {
+    /// Volume is conserved: the radius goes as the cube root of the mass.
+    #[default]
+    Volume,
+    /// Disk area is conserved: the radius goes as the square root of the mass.
+    Area,
+}
+
+impl RadiusScaling {
+    /// The power of the radius that is proportional to the mass.
+    fn dimension(&self) -> f32 {
+        match self {
+            RadiusScaling::Volume => 3.0,
+            RadiusScaling::Area => 2.0,
+        }
+    }
+
+    /// The radius of a body of the same density with a different mass.
+    ///
+    /// Arguments:
+    ///
+    /// * `radius` - the body's radius
+    /// * `ratio` - the new mass over the old one
+    pub fn scale(&self, radius: f32, ratio: f32) -> f32 {
+        radius * ratio.max(0.0).powf(1.0 / self.dimension())
+    }
+
+    /// The radius of the body made by squashing two bodies together.
+    pub fn combine(&self, a: f32, b: f32) -> f32 {
+        let d = self.dimension();
+        (a.powf(d) + b.powf(d)).powf(1.0 / d)
+    }
+}
+
+/// The color of a black body at a temperature, roughly.
+///
+/// This is a fit to the blackbody colors (after Tanner Helland), good to a few
+/// percent from 1000 K to 40000 K.
+///
+/// Arguments:
+///
+/// * `temperature` - the temperature in kelvin
+pub fn temperature_color(temperature: f32) -> Rgb {
+    let t = temperature.clamp(1000.0, 40000.0) / 100.0;
+    let red = if t <= 66.0 {
+        255.0
+    } else {
+        329.698_73 * (t - 60.0).powf(-0.133_204_76)
+    };
+    let green = if t <= 66.0 {
+        99.470_8 * t.ln() - 161.119_57
+    } else {
+        288.122_17 * (t - 60.0).powf(-0.075_514_85)
+    };
+    let blue = if t >= 66.0 {
+        255.0
+    } else if t <= 19.0 {
+        0.0
+    } else {
+        138.517_73 * (t - 10.0).ln() - 305.044_8
+    };
+    let channel = |c: f32| (c / 255.0).clamp(0.0, 1.0);
+    rgb(channel(red), channel(green), channel(blue))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Particle;
+
+    /// A body of rock of the given mass, in solar units.
+    fn rock(mass: f32) -> Particle {
+        Particle::of_material(
+            vec3(0.0, 0.0, 0.0),
+            vec3(0.0, 0.0, 0.0),
+            mass,
+            Material::Rock,
+            &UnitSystem::SOLAR,
+        )
+    }
+
+    #[test]
+    fn equal_bodies_merge_into_twice_the_volume_or_area() {
+        let body = rock(3e-6);
+        let r = body.radius;
+        let by_volume = body.merge(&body, RadiusScaling::Volume);
+        assert!((by_volume.radius / r - 2f32.cbrt()).abs() < 1e-5);
+        let by_area = body.merge(&body, RadiusScaling::Area);
+        assert!((by_area.radius / r - 2f32.sqrt()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn merged_volumes_keep_the_density() {
+        // Unequal bodies of the same material make a body of that material
+        // at its own density, just heavier.
+        let (small, large) = (rock(1e-7), rock(3e-6));
+        let merged = small.merge(&large, RadiusScaling::Volume);
+        assert_eq!(merged.material, Material::Rock);
+        assert!((merged.radius / rock(merged.mass).radius - 1.0).abs() < 1e-5);
+        assert!(
+            (RadiusScaling::Volume.scale(large.radius, merged.mass / large.mass) / merged.radius
+                - 1.0)
+                .abs()
+                < 1e-5
+        );
+    }
+}
@@ -0,0 +1,829 @@
+/*
+A particle-mesh (PM) gravity solver, with an optional P3M short-range
+correction.
+
+Rather than add up the pulls between bodies, the mesh solves for the
+potential on a grid:
+
+1. Every body's mass is spread over the eight grid cells nearest it, in
+   proportion to how much a cube one cell across centered on the body
+   overlaps each of them (cloud-in-cell, CIC).
+2. The potential is the convolution of that mass with the potential of a
+   point mass, -1 / r. A convolution is a product in Fourier space, so both
+   are taken to Fourier space with a fast Fourier transform, multiplied, and
+   brought back: O(M log M) for M cells, however many bodies there are.
+3. The acceleration at each cell is minus the gradient of the potential, by
+   four-point finite differences, and each body feels the acceleration of
+   the same eight cells, with the same weights, so the mass it gave out and
+   the pull it takes back match.
+
+The FFT is our own radix-2 transform, so the grid is a power of two cells
+across (`grid`, 64 by default). Memory grows with the cube of the grid, and
+an isolated mesh is padded to twice as wide, so the grid is capped at
+`MAX_GRID`: half a gigabyte of scratch space per force evaluation already.
+
+On its own, the mesh blurs gravity over a cell or two, so close pairs pull
+on each other far less than they should. That's fine for smooth, collisionless
+systems like galaxy disks, but not for anything that depends on close
+encounters. P3M (particle-particle particle-mesh) fixes that by splitting
+gravity in two. The mesh carries the long-range part, with the point-mass
+potential replaced by
+
+    -erf(s / 2 r_s) / s,    s = sqrt(r^2 + epsilon^2)
+
+which is smooth on the scale r_s of a cell or so, and the short-range
+remainder, erfc(s / 2 r_s) / s, is added up directly over the bodies within a
+few r_s, found with a grid of cells (a chain mesh) as wide as that cutoff.
+Both halves are measured in the softened distance s, so between them they add
+up to exactly the Plummer-softened pull however big the softening is. Beyond
+the cutoff the remainder is negligible, and within it the pull is exact.
+
+The mesh is fitted to a cube holding every particle, so a few far-flung
+bodies make its cells coarse for the rest; an absorbing boundary (see
+boundary.rs) keeps them from stretching it. Mass is only taken from the
+massive bodies, but the field is known everywhere on the mesh, so tracers
+feel it like any other particle.
+
+For an isolated system, the mass is put in one corner of a grid twice as wide
+and the rest left empty, so the convolution, which wraps around, never
+reaches from one side of the mass to the other. In a periodic box (see
+boundary.rs) the mesh covers the box exactly, and wraps around with it: each
+body feels the nearest image of every other, as in the other solvers.
+
+The transforms, the gradients and the interpolation all run in parallel with
+rayon, each result written to its own slot, and the mass is deposited in a
+fixed order, so runs still reproduce exactly for any thread count.
+*/
+
+use std::f64::consts::PI;
+use std::mem;
+use std::ops::{Add, Mul, Sub};
+
+use nannou::prelude::*;
+use rayon::prelude::*;
+
+use crate::boundary::minimum_image;
+
+/// The number of cells across the mesh, by default.
+pub const DEFAULT_GRID: u32 = 64;
+
+/// The fewest cells across the mesh: the mass has to sit far enough in from
+/// its edges for the finite differences.
+pub const MIN_GRID: u32 = 16;
+
+/// The most cells across the mesh; see `scratch_bytes` for what it costs.
+pub const MAX_GRID: u32 = 128;
+
+/// How many cells in from the edge of an isolated mesh the mass starts, so
+/// the gradient next to it never reaches past the edge.
+const MARGIN: f64 = 4.0;
+
+/// The scale r_s of the split between the long and short-range parts, in
+/// cells.
+const SPLIT: f64 = 1.25;
+
+/// How far the short-range sum reaches, in units of r_s.
+const CUTOFF: f64 = 4.5;
+
+/// The fewest cells transformed side by side that are worth splitting
+/// between threads.
+const PARALLEL_BLOCK: usize = 1024;
+
+/// The most chain mesh cells along each side.
+const MAX_CHAIN: usize = 128;
+
+/// A complex number, for the Fourier transforms.
+#[derive(Clone, Copy, Debug, Default)]
+struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Add for Complex {
+    type Output = Complex;
+    fn add(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re + other.re,
+            im: self.im + other.im,
+        }
+    }
+}
+
+impl Sub for Complex {
+    type Output = Complex;
+    fn sub(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re - other.re,
+            im: self.im - other.im,
+        }
+    }
+}
+
+impl Mul for Complex {
+    type Output = Complex;
+    fn mul(self, other: Complex) -> Complex {
+        Complex {
+            re: self.re * other.re - self.im * other.im,
+            im: self.re * other.im + self.im * other.re,
+        }
+    }
+}
+
+/// The potential of a unit point mass that the mesh convolves with.
+#[derive(Clone, Copy, Debug)]
+enum Kernel {
+    /// All of gravity, Plummer softened by `epsilon2`; with no softening the
+    /// point mass's own cell gets the potential at half a cell out.
+    Full { epsilon2: f64, cell: f64 },
+    /// Only the long-range part, smooth on the scale `split` (r_s), and
+    /// Plummer softened by `epsilon2` like the short-range part.
+    LongRange { split: f64, epsilon2: f64 },
+}
+
+impl Kernel {
+    /// The potential at a distance whose square is `r2`, per unit G and
+    /// mass.
+    fn potential(&self, r2: f64) -> f64 {
+        match *self {
+            Kernel::Full { epsilon2, cell } => {
+                let s2 = r2 + epsilon2;
+                if s2 > 0.0 {
+                    -1.0 / s2.sqrt()
+                } else {
+                    -2.0 / cell
+                }
+            }
+            Kernel::LongRange { split, epsilon2 } => {
+                let s = (r2 + epsilon2).sqrt();
+                if s > 0.0 {
+                    -(1.0 - erfc(s / (2.0 * split))) / s
+                } else {
+                    -1.0 / (split * PI.sqrt())
+                }
+            }
+        }
+    }
+}
+
+/// The massive bodies sorted into cubic cells at least as wide as the
+/// short-range cutoff, so every body within the cutoff of a point is in the
+/// point's cell or one next to it.
+struct ChainMesh {
+    /// The corner of the first cell.
+    origin: Vector3,
+    /// The side of a cell.
+    size: f32,
+    /// The number of cells along each side.
+    n: usize,
+    /// Whether the cells wrap around, in a periodic box.
+    periodic: bool,
+    /// The bodies in each cell, in the order they were given.
+    cells: Vec<Vec<usize>>,
+}
+
+impl ChainMesh {
+    /// Sort the massive bodies into cells.
+    ///
+    /// Arguments:
+    ///
+    /// * `positions` - the position of every body
+    /// * `masses` - the mass of every body; massless ones are left out
+    /// * `cutoff` - the least width of a cell
+    /// * `period` - the side of the periodic box, if there is one
+    fn new(positions: &[Vector3], masses: &[f32], cutoff: f32, period: Option<f32>) -> Self {
+        let (origin, extent) = match period {
+            Some(size) => (vec3(-0.5 * size, -0.5 * size, -0.5 * size), size),
+            None => {
+                let massive = positions.iter().zip(masses).filter(|(_, &m)| m != 0.0);
+                let (min, max) = bounds(massive.map(|(p, _)| *p));
+                let extent = (max.x - min.x).max(max.y - min.y).max(max.z - min.z);
+                (min, extent)
+            }
+        };
+        let n = ((extent / cutoff).floor() as usize).clamp(1, MAX_CHAIN);
+        let mut chain = ChainMesh {
+            origin,
+            size: (extent / n as f32).max(cutoff),
+            n,
+            periodic: period.is_some(),
+            cells: vec![Vec::new(); n * n * n],
+        };
+        for (i, p) in positions.iter().enumerate() {
+            if masses[i] != 0.0 {
+                let [x, y, z] = chain.cell(*p);
+                chain.cells[x + n * (y + n * z)].push(i);
+            }
+        }
+        chain
+    }
+
+    /// The cell a point falls into, or the nearest one if it's outside.
+    fn cell(&self, p: Vector3) -> [usize; 3] {
+        let n = self.n as isize;
+        let along = |x: f32, origin: f32| {
+            let c = ((x - origin) / self.size).floor() as isize;
+            if self.periodic {
+                c.rem_euclid(n) as usize
+            } else {
+                c.clamp(0, n - 1) as usize
+            }
+        };
+        [
+            along(p.x, self.origin.x),
+            along(p.y, self.origin.y),
+            along(p.z, self.origin.z),
+        ]
+    }
+
+    /// Every body in the cells around a point's cell.
+    fn near(&self, p: Vector3) -> impl Iterator<Item = usize> + '_ {
+        let n = self.n as isize;
+        let around = |c: usize| {
+            let mut cs: Vec<usize> = (-1..=1)
+                .map(|d| c as isize + d)
+                .filter_map(|c| {
+                    if self.periodic {
+                        Some(c.rem_euclid(n) as usize)
+                    } else {
+                        (0..n).contains(&c).then_some(c as usize)
+                    }
+                })
+                .collect();
+            // A box only one or two cells across would visit a cell twice.
+            cs.sort_unstable();
+            cs.dedup();
+            cs
+        };
+        let [x, y, z] = self.cell(p);
+        let (xs, ys, zs) = (around(x), around(y), around(z));
+        let n = self.n;
+        let mut cells = Vec::with_capacity(27);
+        for &z in zs.iter() {
+            for &y in ys.iter() {
+                for &x in xs.iter() {
+                    cells.push(x + n * (y + n * z));
+                }
+            }
+        }
+        cells
+            .into_iter()
+            .flat_map(move |c| self.cells[c].iter().copied())
+    }
+}
+
+/// The potential of a set of bodies on a grid, built once per force
+/// evaluation and then probed at every particle.
+pub struct Mesh {
+    /// The number of cells along each side of the grid that is kept.
+    n: usize,
+    /// The side of a cell.
+    cell: f64,
+    /// The corner of the first cell.
+    origin: [f64; 3],
+    /// Whether the grid wraps around, in a periodic box.
+    periodic: bool,
+    /// The potential at the middle of each cell, per unit G.
+    potential: Vec<f64>,
+    /// The gravitational field at the middle of each cell, per unit G.
+    field: Vec<[f32; 3]>,
+    /// The potential of a point mass that the mass was convolved with.
+    kernel: Kernel,
+    /// The position of every particle.
+    positions: Vec<Vector3>,
+    /// The mass of every particle, in the same order.
+    masses: Vec<f32>,
+    /// For P3M: the bodies sorted for the short-range sum, its cutoff and
+    /// the split scale r_s.
+    short_range: Option<(ChainMesh, f32, f32)>,
+    /// The Plummer softening length, for the short-range sum.
+    softening: f32,
+    /// The side of the periodic box, if there is one.
+    period: Option<f32>,
+}
+
+impl Mesh {
+    /// Deposit a set of bodies onto a grid and solve for their potential.
+    ///
+    /// Arguments:
+    ///
+    /// * `positions` - the position of every particle
+    /// * `masses` - the mass of each particle, in the same order; massless
+    ///   tracers are on the mesh but add nothing to it
+    /// * `grid` - how many cells across the particles (or the periodic box)
+    ///   the mesh is; rounded up to a power of two, from `MIN_GRID` to
+    ///   `MAX_GRID`
+    /// * `softening` - the Plummer softening length
+    /// * `period` - the side of the periodic box, if space wraps around
+    /// * `short_range` - whether to add up the short-range pulls directly
+    ///   (P3M), rather than leave everything to the mesh (PM)
+    pub fn new(
+        positions: Vec<Vector3>,
+        masses: Vec<f32>,
+        grid: u32,
+        softening: f32,
+        period: Option<f32>,
+        short_range: bool,
+    ) -> Self {
+        let grid = grid.clamp(MIN_GRID, MAX_GRID).next_power_of_two() as usize;
+        // An isolated mesh pads the particles' cells with as many empty ones.
+        let (n, cell, origin) = match period {
+            Some(size) => {
+                let half = -0.5 * size as f64;
+                (grid, size as f64 / grid as f64, [half, half, half])
+            }
+            None => {
+                let (min, max) = bounds(positions.iter().copied());
+                let extent = (max.x - min.x).max(max.y - min.y).max(max.z - min.z);
+                let cell = (extent as f64).max(1e-3) / (grid as f64 - 2.0 * MARGIN);
+                let center = (min + max) * 0.5;
+                let corner = |x: f32| x as f64 - 0.5 * grid as f64 * cell;
+                (
+                    2 * grid,
+                    cell,
+                    [corner(center.x), corner(center.y), corner(center.z)],
+                )
+            }
+        };
+        let epsilon2 = softening as f64 * softening as f64;
+        let kernel = if short_range {
+            Kernel::LongRange {
+                split: SPLIT * cell,
+                epsilon2,
+            }
+        } else {
+            Kernel::Full { epsilon2, cell }
+        };
+
+        let mut mesh = Mesh {
+            n,
+            cell,
+            origin,
+            periodic: period.is_some(),
+            potential: Vec::new(),
+            field: Vec::new(),
+            kernel,
+            positions,
+            masses,
+            short_range: None,
+            softening,
+            period,
+        };
+        mesh.solve(grid);
+        if short_range {
+            let split = (SPLIT * cell) as f32;
+            let cutoff = CUTOFF as f32 * split;
+            let chain = ChainMesh::new(&mesh.positions, &mesh.masses, cutoff, period);
+            mesh.short_range = Some((chain, cutoff, split));
+        }
+        mesh
+    }
+
+    /// Deposit the mass, convolve it with the kernel, and take the gradient
+    /// of the result.
+    ///
+    /// Arguments:
+    ///
+    /// * `span` - how many cells along each side the particles can be in:
+    ///   the whole of a periodic grid, or the first half of an isolated one.
+    ///   Only those cells are kept.
+    fn solve(&mut self, span: usize) {
+        let n = self.n;
+        // The kernel at every offset, wrapped around the grid: offsets past
+        // halfway are negative. The mass and the kernel are both real, so
+        // they share one transform, the kernel in the imaginary part.
+        let (cell, kernel) = (self.cell, self.kernel);
+        let offset = |i: usize| {
+            if i < n / 2 {
+                i as f64
+            } else {
+                i as f64 - n as f64
+            }
+        };
+        let mut both: Vec<Complex> = (0..n * n * n)
+            .into_par_iter()
+            .map(|i| {
+                let (x, y, z) = (offset(i % n), offset(i / n % n), offset(i / (n * n)));
+                let r2 = (x * x + y * y + z * z) * cell * cell;
+                Complex {
+                    re: 0.0,
+                    im: kernel.potential(r2),
+                }
+            })
+            .collect();
+        for (p, &m) in self.positions.iter().zip(self.masses.iter()) {
+            if m == 0.0 {
+                continue;
+            }
+            for (at, w) in self.corners(*p) {
+                both[self.index(at)].re += m as f64 * w;
+            }
+        }
+        fft_3d(&mut both, n, false);
+
+        // Pull the two transforms apart and multiply them. The mass's
+        // transform at -k is the conjugate of that at k, and the kernel's is
+        // real and the same at -k, so with Z the combined transform at k and
+        // W the conjugate of it at -k, the mass's is (Z + W) / 2 and the
+        // kernel's is (Z - W) / 2i.
+        let mirror = |i: usize| {
+            let (x, y, z) = (i % n, i / n % n, i / (n * n));
+            (n - x) % n + n * ((n - y) % n + n * ((n - z) % n))
+        };
+        let mut product: Vec<Complex> = (0..n * n * n)
+            .into_par_iter()
+            .map(|i| {
+                let (z, w) = (both[i], both[mirror(i)]);
+                let k = 0.5 * (z.im + w.im);
+                Complex {
+                    re: 0.5 * (z.re + w.re) * k,
+                    im: 0.5 * (z.im - w.im) * k,
+                }
+            })
+            .collect();
+        drop(both);
+        fft_3d(&mut product, n, true);
+        let scale = 1.0 / (n * n * n) as f64;
+        let potential: Vec<f64> = product.into_par_iter().map(|d| d.re * scale).collect();
+
+        // Minus the gradient at each cell, by four-point finite differences.
+        let index = |i: usize| [i % span, i / span % span, i / (span * span)].map(|c| c as isize);
+        let field = (0..span * span * span)
+            .into_par_iter()
+            .map(|i| {
+                let at = index(i);
+                let mut field = [0.0; 3];
+                for (axis, f) in field.iter_mut().enumerate() {
+                    let phi = |d: isize| {
+                        let mut c = at;
+                        c[axis] += d;
+                        potential[self.index(c)]
+                    };
+                    let slope = 8.0 * (phi(1) - phi(-1)) - (phi(2) - phi(-2));
+                    *f = (-slope / (12.0 * cell)) as f32;
+                }
+                field
+            })
+            .collect();
+        let kept = (0..span * span * span)
+            .into_par_iter()
+            .map(|i| potential[self.index(index(i))])
+            .collect();
+        self.field = field;
+        self.potential = kept;
+        self.n = span;
+    }
+
+    /// The eight cells a point's mass is spread over (and its field is
+    /// gathered from), and the share of it each gets.
+    fn corners(&self, p: Vector3) -> impl Iterator<Item = ([isize; 3], f64)> {
+        // Count cells from their middles: the point is `fraction` of a cell
+        // past the middle of cell `base`, along each axis.
+        let mut base = [0; 3];
+        let mut fraction = [0.0; 3];
+        for (axis, x) in [p.x, p.y, p.z].into_iter().enumerate() {
+            let u = (x as f64 - self.origin[axis]) / self.cell - 0.5;
+            base[axis] = u.floor() as isize;
+            fraction[axis] = u - u.floor();
+        }
+        (0..8).map(move |corner| {
+            let mut weight = 1.0;
+            let mut at = base;
+            for axis in 0..3 {
+                if corner >> axis & 1 == 1 {
+                    at[axis] += 1;
+                    weight *= fraction[axis];
+                } else {
+                    weight *= 1.0 - fraction[axis];
+                }
+            }
+            (at, weight)
+        })
+    }
+
+    /// The index of a cell, wrapped around a periodic grid or kept on an
+    /// isolated one.
+    fn index(&self, [x, y, z]: [isize; 3]) -> usize {
+        let n = self.n as isize;
+        let wrap = |c: isize| {
+            if self.periodic {
+                c.rem_euclid(n) as usize
+            } else {
+                c.clamp(0, n - 1) as usize
+            }
+        };
+        wrap(x) + self.n * (wrap(y) + self.n * wrap(z))
+    }
+
+    /// The gravitational acceleration felt by particle `i`, per unit G.
+    pub fn acceleration(&self, i: usize) -> Vector3 {
+        let p = self.positions[i];
+        let mut a = [0.0; 3];
+        for (at, weight) in self.corners(p) {
+            let field = self.field[self.index(at)];
+            for axis in 0..3 {
+                a[axis] += weight * field[axis] as f64;
+            }
+        }
+        let mut acceleration = vec3(a[0] as f32, a[1] as f32, a[2] as f32);
+
+        if let Some((chain, cutoff, split)) = self.short_range.as_ref() {
+            let epsilon2 = self.softening * self.softening;
+            for j in chain.near(p).filter(|&j| j != i) {
+                let r = minimum_image(self.positions[j] - p, self.period);
+                let r2 = r.magnitude2();
+                let s2 = r2 + epsilon2;
+                if r2 >= cutoff * cutoff || s2 == 0.0 {
+                    continue;
+                }
+                let u = s2.sqrt() as f64 / (2.0 * *split as f64);
+                let share = erfc(u) + 2.0 * u / PI.sqrt() * (-u * u).exp();
+                acceleration += r * (self.masses[j] * share as f32 / (s2 * s2.sqrt()));
+            }
+        }
+        acceleration
+    }
+
+    /// The gravitational potential at particle `i` due to every other body,
+    /// per unit G.
+    pub fn potential(&self, i: usize) -> f32 {
+        let p = self.positions[i];
+        let mut phi: f64 = self
+            .corners(p)
+            .map(|(at, w)| w * self.potential[self.index(at)])
+            .sum();
+
+        // Take out the particle's own mass, which the mesh holds too: its
+        // share in each cell, seen from each cell it's gathered from.
+        let m = self.masses[i] as f64;
+        if m != 0.0 {
+            for (a, wa) in self.corners(p) {
+                for (b, wb) in self.corners(p) {
+                    let d2: isize = (0..3).map(|axis| (a[axis] - b[axis]).pow(2)).sum();
+                    let r2 = d2 as f64 * self.cell * self.cell;
+                    phi -= m * wa * wb * self.kernel.potential(r2);
+                }
+            }
+        }
+        let mut potential = phi as f32;
+
+        if let Some((chain, cutoff, split)) = self.short_range.as_ref() {
+            let epsilon2 = self.softening * self.softening;
+            for j in chain.near(p).filter(|&j| j != i) {
+                let r2 = minimum_image(self.positions[j] - p, self.period).magnitude2();
+                let s2 = r2 + epsilon2;
+                if r2 >= cutoff * cutoff || s2 == 0.0 {
+                    continue;
+                }
+                let u = s2.sqrt() as f64 / (2.0 * *split as f64);
+                potential -= self.masses[j] * erfc(u) as f32 / s2.sqrt();
+            }
+        }
+        potential
+    }
+}
+
+/// The smallest and largest coordinates of a set of points.
+fn bounds(points: impl Iterator<Item = Vector3>) -> (Vector3, Vector3) {
+    let mut min = vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+    let mut max = vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+    for p in points {
+        min = vec3(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+        max = vec3(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+    }
+    if min.x > max.x {
+        return (vec3(0.0, 0.0, 0.0), vec3(0.0, 0.0, 0.0));
+    }
+    (min, max)
+}
+
+/// The complementary error function, erfc(x) = 1 - erf(x), to a relative
+/// accuracy of about 1e-7 (Numerical Recipes' erfcc).
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let poly = -1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807
+                            + t * (-1.13520398
+                                + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))));
+    let r = t * (-z * z + poly).exp();
+    if x >= 0.0 {
+        r
+    } else {
+        2.0 - r
+    }
+}
+
+/// The most memory an isolated mesh `grid` cells across takes while it
+/// solves: two complex arrays, the transform and the product, padded to
+/// twice as many cells across.
+pub fn scratch_bytes(grid: u32) -> usize {
+    let n = 2 * grid as usize;
+    2 * n * n * n * mem::size_of::<Complex>()
+}
+
+/// Fourier transform a cube of `n` cells along each side in place: forward,
+/// or back without dividing by the number of cells.
+///
+/// Each axis is transformed in turn, treating the cube as a row of `n`
+/// elements along that axis, each element a run of cells that lie
+/// contiguously in memory: single cells along x, rows along y, and planes
+/// along z. So the butterflies always sweep through memory in order.
+fn fft_3d(data: &mut [Complex], n: usize, inverse: bool) {
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let twiddles: Vec<Complex> = (0..n / 2)
+        .map(|k| {
+            let angle = sign * 2.0 * PI * k as f64 / n as f64;
+            Complex {
+                re: angle.cos(),
+                im: angle.sin(),
+            }
+        })
+        .collect();
+    data.par_chunks_mut(n)
+        .for_each(|row| fft(row, 1, &twiddles));
+    data.par_chunks_mut(n * n)
+        .for_each(|plane| fft(plane, n, &twiddles));
+    fft(data, n * n, &twiddles);
+}
+
+/// Fourier transform a row of elements in place, with the iterative radix-2
+/// Cooley-Tukey algorithm, where each element is a run of `block` cells
+/// transformed side by side. The number of elements is a power of two, and
+/// `twiddles` holds the first half of its roots of unity.
+fn fft(data: &mut [Complex], block: usize, twiddles: &[Complex]) {
+    let n = data.len() / block;
+    let bits = n.trailing_zeros();
+    for i in 0..n {
+        let j = i.reverse_bits() >> (usize::BITS - bits);
+        if i < j {
+            let (low, high) = data.split_at_mut(j * block);
+            low[i * block..(i + 1) * block].swap_with_slice(&mut high[..block]);
+        }
+    }
+    let mut width = 2;
+    while width <= n {
+        let stride = n / width;
+        for start in (0..n).step_by(width) {
+            for k in 0..width / 2 {
+                let w = twiddles[k * stride];
+                let (low, high) = data.split_at_mut((start + k + width / 2) * block);
+                let a = &mut low[(start + k) * block..(start + k + 1) * block];
+                let b = &mut high[..block];
+                let butterfly = |(a, b): (&mut Complex, &mut Complex)| {
+                    let t = *b * w;
+                    *b = *a - t;
+                    *a = *a + t;
+                };
+                // Whole planes are big enough to share out between threads.
+                if block >= PARALLEL_BLOCK {
+                    a.par_iter_mut().zip(b.par_iter_mut()).for_each(butterfly);
+                } else {
+                    a.iter_mut().zip(b.iter_mut()).for_each(butterfly);
+                }
+            }
+        }
+        width *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gravity::{solver_error, ForceSolver, Gravity};
+    use crate::rng::Rng;
+    use crate::store::ParticleStore;
+    use crate::Particle;
+
+    /// Unit gravity with a given softening.
+    fn gravity(softening: f32) -> Gravity {
+        Gravity {
+            g: 1.0,
+            softening,
+            period: None,
+        }
+    }
+
+    /// A random cloud of bodies in a unit cube, with a spread of masses.
+    fn cloud(count: usize) -> ParticleStore {
+        let mut rng = Rng::new(3);
+        let mut store = ParticleStore::new();
+        for _ in 0..count {
+            let position = vec3(rng.uniform(), rng.uniform(), rng.uniform());
+            let mass = rng.range(0.5, 2.0);
+            store.insert(Particle::new(position, vec3(0.0, 0.0, 0.0), mass, 0.001));
+        }
+        store
+    }
+
+    #[test]
+    fn fft_round_trips() {
+        let n = 16;
+        let mut rng = Rng::new(1);
+        let original: Vec<Complex> = (0..n * n * n)
+            .map(|_| Complex {
+                re: rng.range(-1.0, 1.0) as f64,
+                im: rng.range(-1.0, 1.0) as f64,
+            })
+            .collect();
+        let mut data = original.clone();
+        fft_3d(&mut data, n, false);
+        fft_3d(&mut data, n, true);
+        let scale = 1.0 / (n * n * n) as f64;
+        for (a, b) in data.iter().zip(original.iter()) {
+            assert!((a.re * scale - b.re).abs() < 1e-12);
+            assert!((a.im * scale - b.im).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn fft_matches_the_discrete_transform() {
+        let n = 4;
+        let mut rng = Rng::new(2);
+        let original: Vec<Complex> = (0..n * n * n)
+            .map(|_| Complex {
+                re: rng.range(-1.0, 1.0) as f64,
+                im: rng.range(-1.0, 1.0) as f64,
+            })
+            .collect();
+        let mut data = original.clone();
+        fft_3d(&mut data, n, false);
+        let coordinates = |i: usize| [i % n, i / n % n, i / (n * n)];
+        for (k, transformed) in data.iter().enumerate() {
+            let kc = coordinates(k);
+            let mut sum = Complex::default();
+            for (x, value) in original.iter().enumerate() {
+                let xc = coordinates(x);
+                let phase: usize = (0..3).map(|axis| kc[axis] * xc[axis]).sum();
+                let angle = -2.0 * PI * phase as f64 / n as f64;
+                sum = sum
+                    + *value
+                        * Complex {
+                            re: angle.cos(),
+                            im: angle.sin(),
+                        };
+            }
+            assert!((sum.re - transformed.re).abs() < 1e-9);
+            assert!((sum.im - transformed.im).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn a_single_body_pulls_like_a_point_mass() {
+        for short_range in [false, true] {
+            let positions = vec![vec3(0.0, 0.0, 0.0), vec3(3.0, 4.0, 0.0)];
+            let masses = vec![2.0, 0.0];
+            let mesh = Mesh::new(positions, masses, 32, 0.0, None, short_range);
+
+            // The tracer feels G m / r^2 towards the body.
+            let expected = vec3(-3.0, -4.0, 0.0) * (2.0 / 125.0);
+            let a = mesh.acceleration(1);
+            let error = (a - expected).magnitude() / expected.magnitude();
+            assert!(error < 0.01, "short range {}: {:?}", short_range, a);
+
+            // And the body doesn't pull on itself.
+            let own = mesh.acceleration(0).magnitude();
+            assert!(own < 1e-3 * expected.magnitude(), "{}", own);
+        }
+    }
+
+    #[test]
+    fn mesh_solvers_are_close_to_the_direct_sum() {
+        let store = cloud(400);
+        let particles = store.as_slice();
+        // The cells are about 1/24 across. PM blurs gravity over a couple of
+        // them, so it is only held to the direct sum when the softening
+        // blurs it as much; P3M adds the near field back, so it is held to
+        // it with little softening or a lot.
+        for (solver, softening, mean, max) in [
+            (ForceSolver::ParticleMesh { grid: 32 }, 0.1, 0.03, 0.25),
+            (ForceSolver::P3m { grid: 32 }, 0.01, 0.02, 0.1),
+            (ForceSolver::P3m { grid: 32 }, 0.1, 0.02, 0.1),
+        ] {
+            let error = solver_error(solver, particles, gravity(softening));
+            assert!(error.mean < mean, "{}: {:?}", solver, error);
+            assert!(error.max < max, "{}: {:?}", solver, error);
+        }
+    }
+
+    #[test]
+    fn particle_mesh_forces_conserve_momentum() {
+        let store = cloud(400);
+        let particles = store.as_slice();
+        let mut accelerations = Vec::new();
+        ForceSolver::ParticleMesh { grid: 32 }.accelerations_into(
+            particles,
+            gravity(0.01),
+            &mut accelerations,
+        );
+        let (mut net, mut scale) = (vec3(0.0, 0.0, 0.0), 0.0);
+        for (p, a) in particles.iter().zip(accelerations.iter()) {
+            net += *a * p.mass;
+            scale += (*a * p.mass).magnitude();
+        }
+        assert!(net.magnitude() < 1e-4 * scale, "{:?} of {}", net, scale);
+    }
+}
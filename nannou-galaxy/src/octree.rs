@@ -0,0 +1,471 @@
+/*
+A Barnes-Hut octree for approximating gravity between many bodies.
+
+Every body is inserted into a tree of cubic cells. Each cell remembers the
+total mass of the bodies beneath it and their center of mass. When we want the
+pull on a body, we walk the tree from the root: if a cell is small compared to
+its distance from the body,
+
+    s / d < theta
+
+where s is the cell's side length and d is the distance to its center of mass,
+we treat the whole cell as a single point mass. Otherwise we open the cell and
+look at its eight children. This brings the cost of a force evaluation down from
+O(N^2) to roughly O(N log N).
+
+theta = 0 opens every cell and reproduces the direct sum; larger values are
+faster and less accurate. 0.5 is the usual compromise.
+
+When every body lies in one plane, as in the flat scenes, the cells never
+split across it, and the octree does the work of a quadtree.
+
+The field can also be probed at any point that isn't one of the bodies, which
+is how massless tracers (see `Particle::is_tracer`) feel a tree built over the
+massive bodies alone, and how the slingshot previews the path of a body it
+hasn't launched yet (see spawn.rs).
+
+In a periodic box (see boundary.rs) every cell and body is seen at its nearest
+image: its center of mass is moved by whole periods to wherever it is closest
+to the point being probed, before the opening test and the pull. A cell that
+reaches past half a period from the point has bodies nearest in different
+images, so it is always opened. The tree itself is built over the positions
+as they are, wrapped into the box.
+*/
+
+use nannou::prelude::*;
+
+use crate::boundary::minimum_image;
+
+/// Cells deeper than this are never split. Bodies that land in the same cell
+/// at this depth (e.g. coincident bodies) are lumped together into one leaf.
+const MAX_DEPTH: usize = 32;
+
+/// Marks a leaf that does not hold a body.
+const EMPTY: usize = usize::MAX;
+
+/// A single cubic cell of the tree.
+struct Node {
+    /// The geometric center of the cell.
+    center: Vector3,
+    /// Half of the cell's side length.
+    half_size: f32,
+    /// Total mass of every body in the cell.
+    mass: f32,
+    /// Mass-weighted sum of positions; divide by `mass` for the center of
+    /// mass.
+    weighted_position: Vector3,
+    /// Index of the first of eight contiguous children, or `None` for a leaf.
+    children: Option<usize>,
+    /// The body stored in this leaf, or `EMPTY`.
+    body: usize,
+}
+
+impl Node {
+    fn new(center: Vector3, half_size: f32) -> Self {
+        Node {
+            center,
+            half_size,
+            mass: 0.0,
+            weighted_position: vec3(0.0, 0.0, 0.0),
+            children: None,
+            body: EMPTY,
+        }
+    }
+
+    /// Which of the eight children a point falls into.
+    fn octant(&self, point: Vector3) -> usize {
+        let east = (point.x >= self.center.x) as usize;
+        let north = (point.y >= self.center.y) as usize;
+        let up = (point.z >= self.center.z) as usize;
+        east + 2 * north + 4 * up
+    }
+
+    /// Whether a point lies inside this cell.
+    fn contains(&self, point: Vector3) -> bool {
+        (point.x - self.center.x).abs() <= self.half_size
+            && (point.y - self.center.y).abs() <= self.half_size
+            && (point.z - self.center.z).abs() <= self.half_size
+    }
+}
+
+/// An octree built over a snapshot of body positions and masses.
+pub struct Octree {
+    nodes: Vec<Node>,
+    positions: Vec<Vector3>,
+    masses: Vec<f32>,
+    /// The leaf each body ended up in.
+    leaves: Vec<usize>,
+    /// The period space repeats with, or None if it doesn't.
+    period: Option<f32>,
+}
+
+impl Octree {
+    /// Build a tree over a set of bodies.
+    ///
+    /// Arguments:
+    ///
+    /// * `positions` - the position of each body
+    /// * `masses` - the mass of each body, in the same order as `positions`
+    /// * `period` - the side of the periodic box the bodies are in, or None
+    ///   if space doesn't wrap around
+    pub fn new(positions: Vec<Vector3>, masses: Vec<f32>, period: Option<f32>) -> Self {
+        // Find a cube that holds every body.
+        let mut min = vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY);
+        let mut max = vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY);
+        for p in positions.iter() {
+            min.x = min.x.min(p.x);
+            min.y = min.y.min(p.y);
+            min.z = min.z.min(p.z);
+            max.x = max.x.max(p.x);
+            max.y = max.y.max(p.y);
+            max.z = max.z.max(p.z);
+        }
+        let (center, half_size) = if positions.is_empty() {
+            (vec3(0.0, 0.0, 0.0), 1.0)
+        } else {
+            let extent = (max.x - min.x).max(max.y - min.y).max(max.z - min.z);
+            // Pad a little so bodies on the boundary land strictly inside.
+            ((min + max) * 0.5, (extent * 0.5).max(1e-3) * 1.01)
+        };
+
+        let mut tree = Octree {
+            nodes: vec![Node::new(center, half_size)],
+            leaves: vec![0; positions.len()],
+            positions,
+            masses,
+            period,
+        };
+        for i in 0..tree.positions.len() {
+            tree.insert(i);
+        }
+        tree
+    }
+
+    /// Insert body `i`, splitting leaves as needed.
+    fn insert(&mut self, i: usize) {
+        let position = self.positions[i];
+        let mass = self.masses[i];
+        let mut node = 0;
+        let mut depth = 0;
+        loop {
+            self.nodes[node].mass += mass;
+            self.nodes[node].weighted_position += position * mass;
+
+            if let Some(first) = self.nodes[node].children {
+                node = first + self.nodes[node].octant(position);
+                depth += 1;
+                continue;
+            }
+
+            // An empty leaf takes the body.
+            if self.nodes[node].body == EMPTY {
+                self.nodes[node].body = i;
+                self.leaves[i] = node;
+                return;
+            }
+
+            // A full leaf at the bottom of the tree lumps bodies together.
+            if depth >= MAX_DEPTH {
+                self.leaves[i] = node;
+                return;
+            }
+
+            // A full leaf is split, and its previous body is pushed down.
+            let existing = self.nodes[node].body;
+            self.nodes[node].body = EMPTY;
+            let first = self.split(node);
+            let child = first + self.nodes[node].octant(self.positions[existing]);
+            let existing_mass = self.masses[existing];
+            self.nodes[child].mass += existing_mass;
+            self.nodes[child].weighted_position += self.positions[existing] * existing_mass;
+            self.nodes[child].body = existing;
+            self.leaves[existing] = child;
+
+            node = first + self.nodes[node].octant(position);
+            depth += 1;
+        }
+    }
+
+    /// Give a leaf eight empty children, returning the index of the first.
+    fn split(&mut self, node: usize) -> usize {
+        let center = self.nodes[node].center;
+        let half = self.nodes[node].half_size * 0.5;
+        let first = self.nodes.len();
+        for octant in 0..8 {
+            let dx = if octant & 1 == 1 { half } else { -half };
+            let dy = if octant & 2 == 2 { half } else { -half };
+            let dz = if octant & 4 == 4 { half } else { -half };
+            self.nodes.push(Node::new(center + vec3(dx, dy, dz), half));
+        }
+        self.nodes[node].children = Some(first);
+        first
+    }
+
+    /// Visit every point mass felt at a point: whole cells that pass the
+    /// opening test, and individual leaves otherwise, with the body at the
+    /// point (if it is one of the tree's) taken out. In a periodic box each
+    /// source is visited at its image nearest the point.
+    ///
+    /// Arguments:
+    ///
+    /// * `position` - the point
+    /// * `body` - the index of the body at the point, as passed to
+    ///   `Octree::new`, or None if it isn't one
+    /// * `theta` - the opening angle; 0 is exact, larger is faster
+    /// * `visit` - called with the position and mass of each source
+    fn walk<F: FnMut(Vector3, f32)>(
+        &self,
+        position: Vector3,
+        body: Option<usize>,
+        theta: f32,
+        mut visit: F,
+    ) {
+        let mut stack = vec![0];
+
+        while let Some(node) = stack.pop() {
+            let n = &self.nodes[node];
+            if n.mass == 0.0 {
+                continue;
+            }
+
+            match n.children {
+                Some(first) => {
+                    let com = self.nearest(position, n.weighted_position / n.mass);
+                    let d = (com - position).magnitude();
+                    let size = n.half_size * 2.0;
+                    // A cell holding the point is always opened, so a body
+                    // never pulls on itself through a lumped cell. So is
+                    // one whose bodies aren't all nearest in the same image.
+                    if !n.contains(position) && self.one_image(n, position) && size < theta * d {
+                        visit(com, n.mass);
+                    } else {
+                        stack.extend(first..first + 8);
+                    }
+                }
+                None => {
+                    let (mut mass, mut weighted) = (n.mass, n.weighted_position);
+                    if let Some(i) = body.filter(|&i| node == self.leaves[i]) {
+                        // Take this body's own contribution out of the leaf.
+                        mass -= self.masses[i];
+                        weighted -= position * self.masses[i];
+                    }
+                    if mass > 0.0 {
+                        visit(self.nearest(position, weighted / mass), mass);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether every point in a cell has its nearest image to `position` in
+    /// the same copy of the cell, so the cell can be lumped at one image of
+    /// its center of mass. Without a period there's only the one copy.
+    fn one_image(&self, node: &Node, position: Vector3) -> bool {
+        match self.period {
+            Some(period) => {
+                let d = minimum_image(node.center - position, self.period);
+                let reach = d.x.abs().max(d.y.abs()).max(d.z.abs()) + node.half_size;
+                reach < 0.5 * period
+            }
+            None => true,
+        }
+    }
+
+    /// The image of `source` nearest to `position`: `source` itself, unless
+    /// space wraps around.
+    fn nearest(&self, position: Vector3, source: Vector3) -> Vector3 {
+        match self.period {
+            Some(_) => position + minimum_image(source - position, self.period),
+            None => source,
+        }
+    }
+
+    /// The gravitational acceleration felt by body `i`, per unit G.
+    ///
+    /// Multiply by the gravitational constant to get a physical acceleration.
+    ///
+    /// Arguments:
+    ///
+    /// * `i` - the index of the body, as passed to `Octree::new`
+    /// * `theta` - the opening angle; 0 is exact, larger is faster
+    /// * `softening` - the Plummer softening length
+    pub fn acceleration(&self, i: usize, theta: f32, softening: f32) -> Vector3 {
+        self.field(self.positions[i], Some(i), theta, softening)
+    }
+
+    /// The gravitational acceleration at a point that isn't one of the
+    /// bodies, per unit G.
+    ///
+    /// Arguments:
+    ///
+    /// * `position` - the point
+    /// * `theta` - the opening angle; 0 is exact, larger is faster
+    /// * `softening` - the Plummer softening length
+    pub fn acceleration_at(&self, position: Vector3, theta: f32, softening: f32) -> Vector3 {
+        self.field(position, None, theta, softening)
+    }
+
+    /// The acceleration at a point, leaving out the body there if it is one.
+    fn field(&self, position: Vector3, body: Option<usize>, theta: f32, softening: f32) -> Vector3 {
+        let epsilon2 = softening * softening;
+        let mut acceleration = vec3(0.0, 0.0, 0.0);
+        self.walk(position, body, theta, |source, mass| {
+            acceleration += pull(position, source, mass, epsilon2);
+        });
+        acceleration
+    }
+
+    /// The gravitational potential at body `i` due to every other body, per
+    /// unit G.
+    ///
+    /// Arguments:
+    ///
+    /// * `i` - the index of the body, as passed to `Octree::new`
+    /// * `theta` - the opening angle; 0 is exact, larger is faster
+    /// * `softening` - the Plummer softening length
+    pub fn potential(&self, i: usize, theta: f32, softening: f32) -> f32 {
+        self.potential_of(self.positions[i], Some(i), theta, softening)
+    }
+
+    /// The gravitational potential at a point that isn't one of the bodies,
+    /// per unit G.
+    ///
+    /// Arguments:
+    ///
+    /// * `position` - the point
+    /// * `theta` - the opening angle; 0 is exact, larger is faster
+    /// * `softening` - the Plummer softening length
+    pub fn potential_at(&self, position: Vector3, theta: f32, softening: f32) -> f32 {
+        self.potential_of(position, None, theta, softening)
+    }
+
+    /// The potential at a point, leaving out the body there if it is one.
+    fn potential_of(
+        &self,
+        position: Vector3,
+        body: Option<usize>,
+        theta: f32,
+        softening: f32,
+    ) -> f32 {
+        let epsilon2 = softening * softening;
+        let mut potential = 0.0;
+        self.walk(position, body, theta, |source, mass| {
+            let r2 = (source - position).magnitude2() + epsilon2;
+            if r2 > 0.0 {
+                potential -= mass / r2.sqrt();
+            }
+        });
+        potential
+    }
+}
+
+/// The acceleration at `position` due to a point `mass` at `source`, per unit
+/// G, with Plummer softening `epsilon2` (the softening length squared).
+fn pull(position: Vector3, source: Vector3, mass: f32, epsilon2: f32) -> Vector3 {
+    let r = source - position;
+    let r2 = r.magnitude2() + epsilon2;
+    if r2 == 0.0 {
+        return vec3(0.0, 0.0, 0.0);
+    }
+    r * (mass / (r2 * r2.sqrt()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rng::Rng;
+
+    /// A random cloud of bodies in a cube, with a spread of masses.
+    fn cloud(count: usize, seed: u64) -> (Vec<Vector3>, Vec<f32>) {
+        let mut rng = Rng::new(seed);
+        let positions = (0..count)
+            .map(|_| {
+                vec3(
+                    rng.range(-1.0, 1.0),
+                    rng.range(-1.0, 1.0),
+                    rng.range(-1.0, 1.0),
+                )
+            })
+            .collect();
+        let masses = (0..count).map(|_| rng.range(0.5, 2.0)).collect();
+        (positions, masses)
+    }
+
+    /// The exact pull on body `i` from all the others.
+    fn direct(positions: &[Vector3], masses: &[f32], i: usize, softening: f32) -> Vector3 {
+        let epsilon2 = softening * softening;
+        (0..positions.len())
+            .filter(|&j| j != i)
+            .fold(vec3(0.0, 0.0, 0.0), |acc, j| {
+                acc + pull(positions[i], positions[j], masses[j], epsilon2)
+            })
+    }
+
+    /// The relative error of each body's acceleration from the tree.
+    fn errors(theta: f32) -> Vec<f32> {
+        let (positions, masses) = cloud(500, 7);
+        let tree = Octree::new(positions.clone(), masses.clone(), None);
+        (0..positions.len())
+            .map(|i| {
+                let exact = direct(&positions, &masses, i, 0.01);
+                (tree.acceleration(i, theta, 0.01) - exact).magnitude() / exact.magnitude()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn zero_theta_matches_the_direct_sum() {
+        let worst = errors(0.0).into_iter().fold(0.0, f32::max);
+        assert!(worst < 1e-5, "worst relative error {}", worst);
+    }
+
+    #[test]
+    fn half_theta_stays_close_to_the_direct_sum() {
+        let errors = errors(0.5);
+        let mean = errors.iter().sum::<f32>() / errors.len() as f32;
+        let worst = errors.into_iter().fold(0.0, f32::max);
+        assert!(mean < 0.01, "mean relative error {}", mean);
+        assert!(worst < 0.05, "worst relative error {}", worst);
+    }
+
+    #[test]
+    fn a_body_never_pulls_on_itself() {
+        let tree = Octree::new(vec![vec3(1.0, 2.0, 3.0)], vec![5.0], None);
+        assert_eq!(tree.acceleration(0, 0.5, 0.0), vec3(0.0, 0.0, 0.0));
+        assert_eq!(tree.potential(0, 0.5, 0.0), 0.0);
+
+        let tree = Octree::new(
+            vec![vec3(0.0, 0.0, 0.0), vec3(2.0, 0.0, 0.0)],
+            vec![1.0, 3.0],
+            None,
+        );
+        let a = tree.acceleration(0, 0.5, 0.0);
+        assert!((a - vec3(0.75, 0.0, 0.0)).magnitude() < 1e-6, "{:?}", a);
+        let b = tree.acceleration(1, 0.5, 0.0);
+        assert!((b - vec3(-0.25, 0.0, 0.0)).magnitude() < 1e-6, "{:?}", b);
+    }
+
+    #[test]
+    fn coincident_bodies_are_lumped_into_one_leaf() {
+        let positions = vec![
+            vec3(0.5, 0.5, 0.5),
+            vec3(0.5, 0.5, 0.5),
+            vec3(0.5, 0.5, 0.5),
+            vec3(-1.5, 0.5, 0.5),
+        ];
+        let masses = vec![1.0, 2.0, 3.0, 4.0];
+        let tree = Octree::new(positions, masses, None);
+        assert_eq!(tree.leaves[0], tree.leaves[1]);
+        assert_eq!(tree.leaves[1], tree.leaves[2]);
+        assert!(tree.nodes.len() <= 1 + 8 * MAX_DEPTH);
+
+        // Each of the stack feels only the far body, not itself or the
+        // others on top of it, and the far body feels all of them.
+        for i in 0..3 {
+            let a = tree.acceleration(i, 0.5, 0.0);
+            assert!((a - vec3(-1.0, 0.0, 0.0)).magnitude() < 1e-5, "{:?}", a);
+        }
+        let a = tree.acceleration(3, 0.5, 0.0);
+        assert!((a - vec3(1.5, 0.0, 0.0)).magnitude() < 1e-5, "{:?}", a);
+    }
+}
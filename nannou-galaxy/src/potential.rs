@@ -0,0 +1,638 @@
+/*
+Analytic external potentials for the galaxy simulation.
+
+A disk of stars on its own is not a galaxy: most of a real galaxy's mass is
+in a dark-matter halo that nobody would simulate star by star, and it is the
+halo that keeps the rotation curve flat far out. So as well as the pairwise
+forces, a `ParticleSystem` can feel any number of fixed, analytic external
+potentials, each with a center, some parameters and an on/off switch:
+
+* `Nfw`: the Navarro-Frenk-White dark-matter halo, with density
+  rho = rho0 / ((r / rs) (1 + r / rs)^2). Its `mass` is 4 pi rho0 rs^3, and
+  the mass inside r is mass * (ln(1 + x) - x / (1 + x)) with x = r / rs.
+* `Isothermal`: a cored isothermal halo, phi = v^2 / 2 * ln(r^2 + rc^2), whose
+  circular speed rises through the core and then stays at v.
+* `Plummer`: a Plummer sphere, phi = -G M / sqrt(r^2 + b^2), for a bulge.
+* `PointMass`: a fixed point mass such as a central black hole, softened like
+  the pairwise forces.
+* `Uniform`: a uniform field, the same acceleration everywhere.
+* `Bar`: a rotating bar, the softened needle of Long & Murali (1992): a rod
+  of mass M from -a to a along the bar's axis, smeared by a Plummer softening
+  b in the plane and a thickness c out of it, turning about the z axis at a
+  fixed pattern speed.
+
+The potentials are fixed in space (a halo doesn't follow a galaxy around),
+and only the bar changes with time, so the energy measured by diagnostics.rs
+includes each particle's energy in them and is still conserved, except with a
+bar, which does work on the stars.
+
+Every parameter is in the units of the owning system. Pass e.g.
+`--potential nfw:mass=6.7e11,scale_radius=20000` (or just `--potential nfw`
+for the defaults, which suit galactic units) to add one to any scene, in the
+units it runs in; the option can be repeated.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+
+use nannou::prelude::*;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::gravity::Gravity;
+use crate::Particle;
+
+/// The shape and strength of an external potential.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Potential {
+    /// A Navarro-Frenk-White halo with characteristic mass 4 pi rho0 rs^3.
+    Nfw { mass: f32, scale_radius: f32 },
+    /// A cored isothermal halo with asymptotic circular speed `speed`.
+    Isothermal { speed: f32, core_radius: f32 },
+    /// A Plummer sphere of total mass `mass` and scale radius `radius`.
+    Plummer { mass: f32, radius: f32 },
+    /// A fixed point mass.
+    PointMass { mass: f32 },
+    /// The same acceleration everywhere.
+    Uniform { acceleration: [f32; 3] },
+    /// A Long & Murali bar of total mass `mass` and half-length
+    /// `half_length`, with in-plane softening `softening` and vertical
+    /// thickness `thickness`. It lies along `angle` (radians from the x axis)
+    /// at time 0, and turns at `pattern_speed` radians per time unit.
+    Bar {
+        mass: f32,
+        half_length: f32,
+        softening: f32,
+        thickness: f32,
+        pattern_speed: f32,
+        angle: f32,
+    },
+}
+
+/// An external potential placed in a system.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ExternalPotential {
+    pub potential: Potential,
+    /// Where the potential is centered. A uniform field ignores it, except
+    /// as the point where its potential energy is zero.
+    pub center: [f32; 3],
+    /// Whether the potential is felt at all.
+    pub enabled: bool,
+}
+
+impl ExternalPotential {
+    /// Place a potential at the origin, switched on.
+    pub fn new(potential: Potential) -> Self {
+        ExternalPotential {
+            potential,
+            center: [0.0; 3],
+            enabled: true,
+        }
+    }
+
+    /// The potential's center.
+    fn center(&self) -> Vector3 {
+        vec3(self.center[0], self.center[1], self.center[2])
+    }
+
+    /// The acceleration the potential gives a particle.
+    ///
+    /// Arguments:
+    ///
+    /// * `position` - where the particle is
+    /// * `time` - the simulation time, which turns the bar
+    /// * `gravity` - the gravitational constant, and the softening used by a
+    ///   point mass
+    pub fn acceleration(&self, position: Vector3, time: f64, gravity: Gravity) -> Vector3 {
+        let d = position - self.center();
+        let r2 = d.magnitude2();
+        let g = gravity.g;
+        match self.potential {
+            Potential::Nfw { mass, scale_radius } => {
+                let r = r2.sqrt();
+                if r == 0.0 {
+                    return vec3(0.0, 0.0, 0.0);
+                }
+                -d * (g * nfw_enclosed(mass, scale_radius, r) / (r2 * r))
+            }
+            Potential::Isothermal { speed, core_radius } => {
+                -d * (speed * speed / (r2 + core_radius * core_radius))
+            }
+            Potential::Plummer { mass, radius } => {
+                -d * (g * mass / (r2 + radius * radius).powf(1.5))
+            }
+            Potential::PointMass { mass } => {
+                let softened = r2 + gravity.softening * gravity.softening;
+                if softened == 0.0 {
+                    return vec3(0.0, 0.0, 0.0);
+                }
+                -d * (g * mass / softened.powf(1.5))
+            }
+            Potential::Uniform { acceleration } => {
+                vec3(acceleration[0], acceleration[1], acceleration[2])
+            }
+            Potential::Bar { angle, .. } => {
+                let turned = angle + self.pattern_angle(time);
+                let a = bar_acceleration(&self.potential, turn(d, -turned), g);
+                turn(a, turned)
+            }
+        }
+    }
+
+    /// The potential energy per unit mass of a particle.
+    ///
+    /// Arguments:
+    ///
+    /// * `position` - where the particle is
+    /// * `time` - the simulation time, which turns the bar
+    /// * `gravity` - the gravitational constant, and the softening used by a
+    ///   point mass
+    pub fn energy(&self, position: Vector3, time: f64, gravity: Gravity) -> f32 {
+        let d = position - self.center();
+        let r2 = d.magnitude2();
+        let g = gravity.g;
+        match self.potential {
+            Potential::Nfw { mass, scale_radius } => {
+                let r = r2.sqrt() as f64;
+                let x = r / scale_radius as f64;
+                // ln(1 + x) / r tends to 1 / rs at the center.
+                let ln_over_r = if x < 1e-4 {
+                    (1.0 - 0.5 * x) / scale_radius as f64
+                } else {
+                    x.ln_1p() / r
+                };
+                -(g as f64 * mass as f64 * ln_over_r) as f32
+            }
+            Potential::Isothermal { speed, core_radius } => {
+                0.5 * speed * speed * (r2 + core_radius * core_radius).ln()
+            }
+            Potential::Plummer { mass, radius } => -g * mass / (r2 + radius * radius).sqrt(),
+            Potential::PointMass { mass } => {
+                let softened = r2 + gravity.softening * gravity.softening;
+                if softened == 0.0 {
+                    return 0.0;
+                }
+                -g * mass / softened.sqrt()
+            }
+            Potential::Uniform { acceleration } => {
+                -(d.x * acceleration[0] + d.y * acceleration[1] + d.z * acceleration[2])
+            }
+            Potential::Bar {
+                mass,
+                half_length,
+                angle,
+                ..
+            } => {
+                let d = turn(d, -(angle + self.pattern_angle(time)));
+                let (t_plus, t_minus, rho2) = bar_distances(&self.potential, d);
+                // Minus the integral of G dm / distance along the rod, which
+                // is a sum of asinh(w / rho) = ln((w + T) / rho). For negative
+                // w, w + T = rho^2 / (T - w) doesn't lose precision.
+                let sum = |w: f32, t: f32| if w >= 0.0 { w + t } else { rho2 / (t - w) };
+                let a = half_length;
+                let logs = sum(a + d.x, t_plus).ln() + sum(a - d.x, t_minus).ln() - rho2.ln();
+                -g * mass / (2.0 * a) * logs
+            }
+        }
+    }
+
+    /// Set one named parameter, as given on the command line.
+    fn set(&mut self, name: &str, value: f32) -> Result<(), String> {
+        let slot = match (&mut self.potential, name) {
+            (_, "x") => &mut self.center[0],
+            (_, "y") => &mut self.center[1],
+            (_, "z") => &mut self.center[2],
+            (Potential::Nfw { mass, .. }, "mass")
+            | (Potential::Plummer { mass, .. }, "mass")
+            | (Potential::PointMass { mass }, "mass")
+            | (Potential::Bar { mass, .. }, "mass") => mass,
+            (Potential::Nfw { scale_radius, .. }, "scale_radius") => scale_radius,
+            (Potential::Isothermal { speed, .. }, "speed") => speed,
+            (Potential::Isothermal { core_radius, .. }, "core_radius") => core_radius,
+            (Potential::Plummer { radius, .. }, "radius") => radius,
+            (Potential::Uniform { acceleration }, "ax") => &mut acceleration[0],
+            (Potential::Uniform { acceleration }, "ay") => &mut acceleration[1],
+            (Potential::Uniform { acceleration }, "az") => &mut acceleration[2],
+            (Potential::Bar { half_length, .. }, "half_length") => half_length,
+            (Potential::Bar { softening, .. }, "softening") => softening,
+            (Potential::Bar { thickness, .. }, "thickness") => thickness,
+            (Potential::Bar { pattern_speed, .. }, "pattern_speed") => pattern_speed,
+            (Potential::Bar { angle, .. }, "angle") => angle,
+            _ => return Err(format!("this potential has no parameter {:?}", name)),
+        };
+        *slot = value;
+        Ok(())
+    }
+
+    /// How far the bar has turned since time 0; zero for everything else.
+    fn pattern_angle(&self, time: f64) -> f32 {
+        match self.potential {
+            Potential::Bar { pattern_speed, .. } => {
+                (pattern_speed as f64 * time % TAU as f64) as f32
+            }
+            _ => 0.0,
+        }
+    }
+
+    /// Convert the potential to other units.
+    ///
+    /// Arguments:
+    ///
+    /// * `length`, `mass`, `time` - how many new units make up one old one
+    pub fn convert(&mut self, length: f32, mass: f32, time: f32) {
+        for c in self.center.iter_mut() {
+            *c *= length;
+        }
+        self.potential = match self.potential {
+            Potential::Nfw {
+                mass: m,
+                scale_radius,
+            } => Potential::Nfw {
+                mass: m * mass,
+                scale_radius: scale_radius * length,
+            },
+            Potential::Isothermal { speed, core_radius } => Potential::Isothermal {
+                speed: speed * length / time,
+                core_radius: core_radius * length,
+            },
+            Potential::Plummer { mass: m, radius } => Potential::Plummer {
+                mass: m * mass,
+                radius: radius * length,
+            },
+            Potential::PointMass { mass: m } => Potential::PointMass { mass: m * mass },
+            Potential::Uniform { acceleration } => Potential::Uniform {
+                acceleration: acceleration.map(|a| a * length / (time * time)),
+            },
+            Potential::Bar {
+                mass: m,
+                half_length,
+                softening,
+                thickness,
+                pattern_speed,
+                angle,
+            } => Potential::Bar {
+                mass: m * mass,
+                half_length: half_length * length,
+                softening: softening * length,
+                thickness: thickness * length,
+                pattern_speed: pattern_speed / time,
+                angle,
+            },
+        };
+    }
+}
+
+/// Shows the potential's parameters, e.g. "nfw (mass 6.7e11, scale_radius
+/// 2e4), on".
+impl fmt::Display for ExternalPotential {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parameters = match self.potential {
+            Potential::Nfw { mass, scale_radius } => {
+                format!("nfw (mass {:e}, scale_radius {:e})", mass, scale_radius)
+            }
+            Potential::Isothermal { speed, core_radius } => format!(
+                "isothermal (speed {:e}, core_radius {:e})",
+                speed, core_radius
+            ),
+            Potential::Plummer { mass, radius } => {
+                format!("plummer (mass {:e}, radius {:e})", mass, radius)
+            }
+            Potential::PointMass { mass } => format!("point (mass {:e})", mass),
+            Potential::Uniform {
+                acceleration: [x, y, z],
+            } => {
+                format!("uniform (x {:e}, y {:e}, z {:e})", x, y, z)
+            }
+            Potential::Bar {
+                mass,
+                half_length,
+                pattern_speed,
+                ..
+            } => format!(
+                "bar (mass {:e}, half_length {:e}, pattern_speed {:e})",
+                mass, half_length, pattern_speed
+            ),
+        };
+        let [x, y, z] = self.center;
+        if [x, y, z] != [0.0; 3] {
+            write!(f, "{} at ({}, {}, {})", parameters, x, y, z)?;
+        } else {
+            write!(f, "{}", parameters)?;
+        }
+        write!(f, ", {}", if self.enabled { "on" } else { "off" })
+    }
+}
+
+/// Parses a potential from its kind, optionally followed by a colon and
+/// comma-separated `name=value` parameters, e.g.
+/// `plummer:mass=1e10,radius=500,x=1000`. Anything not given takes the
+/// defaults below, which are in galactic units (parsecs, solar masses and
+/// megayears). `x`, `y` and `z` place the center.
+impl FromStr for ExternalPotential {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, parameters) = match s.split_once(':') {
+            Some((kind, parameters)) => (kind, parameters),
+            None => (s, ""),
+        };
+        let mut potential = ExternalPotential::new(match kind.trim() {
+            "nfw" => Potential::Nfw {
+                mass: 6.7e11,
+                scale_radius: 20000.0,
+            },
+            "isothermal" => Potential::Isothermal {
+                speed: 200.0,
+                core_radius: 1000.0,
+            },
+            "plummer" | "bulge" => Potential::Plummer {
+                mass: 1e10,
+                radius: 500.0,
+            },
+            "point" => Potential::PointMass { mass: 4e6 },
+            "uniform" => Potential::Uniform {
+                acceleration: [0.0; 3],
+            },
+            "bar" => Potential::Bar {
+                mass: 1e10,
+                half_length: 4000.0,
+                softening: 500.0,
+                thickness: 300.0,
+                pattern_speed: 0.04,
+                angle: 0.0,
+            },
+            other => {
+                return Err(format!(
+                    "unknown potential {:?}; expected one of nfw, isothermal, plummer, point, \
+                     uniform or bar",
+                    other
+                ))
+            }
+        });
+        for parameter in parameters.split(',').filter(|p| !p.trim().is_empty()) {
+            let (name, value) = parameter
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=value, got {:?}", parameter))?;
+            let value: f32 = value
+                .trim()
+                .parse()
+                .map_err(|e| format!("bad value for {}: {}", name.trim(), e))?;
+            potential.set(name.trim(), value)?;
+        }
+        Ok(potential)
+    }
+}
+
+/// Add the acceleration from every enabled potential to each particle's.
+///
+/// Arguments:
+///
+/// * `potentials` - the external potentials
+/// * `particles` - the particles, in the same order as `accelerations`
+/// * `gravity` - the gravitational constant and softening
+/// * `time` - the simulation time the particles' positions are at
+/// * `accelerations` - the accelerations to add to
+pub fn add_accelerations(
+    potentials: &[ExternalPotential],
+    particles: &[Particle],
+    gravity: Gravity,
+    time: f64,
+    accelerations: &mut [Vector3],
+) {
+    if !potentials.iter().any(|p| p.enabled) {
+        return;
+    }
+    accelerations
+        .par_iter_mut()
+        .zip(particles.par_iter())
+        .for_each(|(a, p)| *a += field_at(potentials, p.position, time, gravity));
+}
+
+/// Add the acceleration from every enabled potential to some of the
+/// particles' accelerations, as computed by `ForceSolver::accelerations_of`.
+///
+/// Arguments:
+///
+/// * `potentials` - the external potentials
+/// * `particles` - all the particles
+/// * `targets` - the indices of the particles, in the same order as
+///   `accelerations`
+/// * `gravity` - the gravitational constant and softening
+/// * `time` - the simulation time the particles' positions are at
+/// * `accelerations` - the accelerations to add to
+pub fn add_accelerations_of(
+    potentials: &[ExternalPotential],
+    particles: &[Particle],
+    targets: &[usize],
+    gravity: Gravity,
+    time: f64,
+    accelerations: &mut [Vector3],
+) {
+    if !potentials.iter().any(|p| p.enabled) {
+        return;
+    }
+    accelerations
+        .par_iter_mut()
+        .zip(targets.par_iter())
+        .for_each(|(a, &i)| *a += field_at(potentials, particles[i].position, time, gravity));
+}
+
+/// The total acceleration from every enabled potential at a point.
+pub fn field_at(
+    potentials: &[ExternalPotential],
+    position: Vector3,
+    time: f64,
+    gravity: Gravity,
+) -> Vector3 {
+    potentials
+        .iter()
+        .filter(|p| p.enabled)
+        .fold(vec3(0.0, 0.0, 0.0), |sum, p| {
+            sum + p.acceleration(position, time, gravity)
+        })
+}
+
+/// The total potential energy per unit mass from every enabled potential at a
+/// point.
+pub fn energy_at(
+    potentials: &[ExternalPotential],
+    position: Vector3,
+    time: f64,
+    gravity: Gravity,
+) -> f32 {
+    potentials
+        .iter()
+        .filter(|p| p.enabled)
+        .map(|p| p.energy(position, time, gravity))
+        .sum()
+}
+
+/// The NFW mass inside radius r, mass * (ln(1 + x) - x / (1 + x)), in f64
+/// and with a series near the center where the two terms nearly cancel.
+fn nfw_enclosed(mass: f32, scale_radius: f32, r: f32) -> f32 {
+    let x = r as f64 / scale_radius as f64;
+    let shape = if x < 1e-3 {
+        x * x * (0.5 - 2.0 * x / 3.0)
+    } else {
+        x.ln_1p() - x / (1.0 + x)
+    };
+    (mass as f64 * shape) as f32
+}
+
+/// Rotate a vector by `angle` radians about the z axis.
+fn turn(v: Vector3, angle: f32) -> Vector3 {
+    let (sin, cos) = angle.sin_cos();
+    vec3(v.x * cos - v.y * sin, v.x * sin + v.y * cos, v.z)
+}
+
+/// The distances T+ and T- from a point to the two ends of a bar, softened,
+/// and the squared softened distance rho^2 from the point to the bar's axis.
+/// The point is in the bar's frame, with the bar along x.
+fn bar_distances(bar: &Potential, d: Vector3) -> (f32, f32, f32) {
+    match *bar {
+        Potential::Bar {
+            half_length: a,
+            softening,
+            thickness,
+            ..
+        } => {
+            let u = softening + (thickness * thickness + d.z * d.z).sqrt();
+            let rho2 = d.y * d.y + u * u;
+            let t_plus = ((a + d.x) * (a + d.x) + rho2).sqrt();
+            let t_minus = ((a - d.x) * (a - d.x) + rho2).sqrt();
+            (t_plus, t_minus, rho2)
+        }
+        _ => (0.0, 0.0, 0.0),
+    }
+}
+
+/// The acceleration from a bar, in the bar's frame.
+///
+/// The potential is phi = -G M / (2 a) * (asinh((a + x) / rho) + asinh((a -
+/// x) / rho)), so along the bar a_x = G M / (2 a) * (1 / T+ - 1 / T-), and
+/// towards it the pull is G M / (2 a) * ((a + x) / T+ + (a - x) / T-) / rho,
+/// shared between y and z by how rho depends on them.
+fn bar_acceleration(bar: &Potential, d: Vector3, g: f32) -> Vector3 {
+    match *bar {
+        Potential::Bar {
+            mass,
+            half_length: a,
+            softening,
+            thickness,
+            ..
+        } => {
+            let (t_plus, t_minus, rho2) = bar_distances(bar, d);
+            let k = g * mass / (2.0 * a);
+            let along = k * (1.0 / t_plus - 1.0 / t_minus);
+            let towards = k * ((a + d.x) / t_plus + (a - d.x) / t_minus) / rho2;
+            let vertical = (thickness * thickness + d.z * d.z).sqrt();
+            let u = softening + vertical;
+            let dz = if vertical > 0.0 {
+                u * d.z / vertical
+            } else {
+                0.0
+            };
+            vec3(along, -towards * d.y, -towards * dz)
+        }
+        _ => vec3(0.0, 0.0, 0.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gravity() -> Gravity {
+        Gravity {
+            g: 1.5,
+            softening: 0.2,
+            period: None,
+        }
+    }
+
+    /// One of each kind of potential, off center, in units of about 1.
+    fn potentials() -> Vec<ExternalPotential> {
+        [
+            Potential::Nfw {
+                mass: 3.0,
+                scale_radius: 2.0,
+            },
+            Potential::Isothermal {
+                speed: 0.7,
+                core_radius: 0.5,
+            },
+            Potential::Plummer {
+                mass: 2.0,
+                radius: 0.8,
+            },
+            Potential::PointMass { mass: 1.2 },
+            Potential::Uniform {
+                acceleration: [0.3, -0.1, 0.2],
+            },
+            Potential::Bar {
+                mass: 2.0,
+                half_length: 1.5,
+                softening: 0.3,
+                thickness: 0.2,
+                pattern_speed: 0.4,
+                angle: 0.3,
+            },
+        ]
+        .into_iter()
+        .map(|potential| ExternalPotential {
+            center: [0.2, -0.1, 0.05],
+            ..ExternalPotential::new(potential)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn fields_are_minus_the_gradient_of_the_potential() {
+        let points = [
+            vec3(1.0, 0.5, -0.3),
+            vec3(-2.0, 0.7, 0.4),
+            vec3(0.3, -3.0, 1.2),
+            vec3(4.0, 2.5, -0.6),
+        ];
+        let (time, h) = (2.5, 1e-2);
+        for potential in potentials() {
+            for &point in points.iter() {
+                // Central differences along each axis.
+                let energy = |d: Vector3| potential.energy(point + d, time, gravity());
+                let slope = |d: Vector3| (energy(d) - energy(-d)) / (2.0 * h);
+                let gradient = vec3(
+                    slope(vec3(h, 0.0, 0.0)),
+                    slope(vec3(0.0, h, 0.0)),
+                    slope(vec3(0.0, 0.0, h)),
+                );
+                let field = potential.acceleration(point, time, gravity());
+                let error = (field + gradient).magnitude();
+                assert!(
+                    error < 1e-3 + 1e-2 * field.magnitude(),
+                    "{} at {:?}: field {:?}, gradient {:?}",
+                    potential,
+                    point,
+                    field,
+                    gradient
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn isothermal_halos_have_flat_rotation_curves() {
+        let (speed, core_radius) = (200.0, 1000.0);
+        let halo = ExternalPotential::new(Potential::Isothermal { speed, core_radius });
+        let circular = |r: f32| {
+            let pull = halo.acceleration(vec3(r, 0.0, 0.0), 0.0, gravity());
+            (r * pull.magnitude()).sqrt()
+        };
+        // Rising through the core...
+        assert!(circular(0.1 * core_radius) < 0.2 * speed);
+        // ...then flat at `speed` however far out.
+        for r in [10.0, 30.0, 100.0, 1000.0] {
+            let v = circular(r * core_radius);
+            assert!((v / speed - 1.0).abs() < 0.01, "{} at {}", v, r);
+        }
+    }
+}
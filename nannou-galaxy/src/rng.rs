@@ -0,0 +1,52 @@
+/*
+A small seeded random number generator.
+
+nannou's `random_range` draws from a thread-local generator that can't be
+seeded, so scenes built with it are different every run. Anything that needs to
+be reproducible from a seed (initial conditions, fragmentation, ...) uses this
+instead. It is SplitMix64, which is tiny, fast, and plenty good for sampling
+positions and velocities.
+*/
+
+use std::f32::consts::TAU;
+
+/// A seeded pseudo-random number generator.
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Create a generator. The same seed always gives the same sequence.
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    /// The next raw 64 random bits.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// A uniform sample from [0, 1).
+    pub fn uniform(&mut self) -> f32 {
+        // The top 24 bits fill an f32 mantissa exactly.
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// A uniform sample from [low, high).
+    pub fn range(&mut self, low: f32, high: f32) -> f32 {
+        low + (high - low) * self.uniform()
+    }
+
+    /// A sample from the standard normal distribution (Box-Muller).
+    pub fn normal(&mut self) -> f32 {
+        // 1 - uniform() is in (0, 1], so the log is finite.
+        let u1 = 1.0 - self.uniform();
+        let u2 = self.uniform();
+        (-2.0 * u1.ln()).sqrt() * (TAU * u2).cos()
+    }
+}
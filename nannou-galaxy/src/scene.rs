@@ -0,0 +1,433 @@
+/*
+Scene files for the galaxy simulation.
+
+A scene is everything needed to carry on a simulation later: every particle's
+position, velocity, mass, radius, color and material, plus the units, timestep
+and how it is divided, softening, force solver, integrator, collision model,
+external potentials, boundary and the time elapsed so far. Particle ids aren't
+saved; particles get fresh ones when the scene is loaded. Neither are particles
+parked outside an absorbing boundary, which are no longer part of the
+simulation.
+
+Scenes can be written in two formats, chosen by the file's extension:
+
+* `.ron`: RON (Rusty Object Notation), which is human-readable, so scenes can
+  be inspected, diffed and edited by hand;
+* `.bin`: a compact binary snapshot (bincode), for saving big systems quickly.
+  It starts with the bytes "NGSC" and the format version, so other files are
+  rejected before we try to decode them.
+
+Every scene records the version of the format it was written in. Files from a
+newer version than this build understands are refused rather than misread.
+RON scenes from older versions still load, with defaults for anything they
+don't mention (and z = 0 for the flat positions and velocities of versions 1
+to 3), but binary snapshots only load into the version that wrote
+them, as their layout changes with every version.
+*/
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use nannou::prelude::*;
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+
+use crate::boundary::Boundary;
+use crate::collision::CollisionModel;
+use crate::gravity::ForceSolver;
+use crate::integrator::Integrator;
+use crate::material::Material;
+use crate::potential::ExternalPotential;
+use crate::timestep::Timestep;
+use crate::units::UnitSystem;
+use crate::{Particle, ParticleSystem};
+
+/// The version of the scene format written by this build.
+pub const SCENE_VERSION: u32 = 9;
+
+/// The first bytes of a binary scene file.
+const MAGIC: &[u8; 4] = b"NGSC";
+
+/// Why a scene couldn't be saved or loaded.
+#[derive(Debug)]
+pub enum SceneError {
+    /// The file couldn't be read or written.
+    Io(io::Error),
+    /// The file isn't a scene, or is damaged.
+    Format(String),
+    /// The file was written by a version of the format this build can't read.
+    Version(u32),
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneError::Io(e) => write!(f, "{}", e),
+            SceneError::Format(e) => write!(f, "not a valid scene: {}", e),
+            SceneError::Version(v) => write!(
+                f,
+                "scene format version {} isn't readable by this build (version {})",
+                v, SCENE_VERSION
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for SceneError {
+    fn from(e: io::Error) -> Self {
+        SceneError::Io(e)
+    }
+}
+
+/// One particle, as saved in a scene.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ParticleRecord {
+    #[serde(deserialize_with = "flat_or_spatial")]
+    position: [f32; 3],
+    #[serde(deserialize_with = "flat_or_spatial")]
+    velocity: [f32; 3],
+    mass: f32,
+    radius: f32,
+    color: [f32; 3],
+    /// Missing from version 1 and 2 scenes, which didn't have materials.
+    #[serde(default)]
+    material: Material,
+}
+
+/// The saved state of a `ParticleSystem`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Scene {
+    version: u32,
+    units: UnitSystem,
+    dt: f32,
+    /// Missing from scenes saved before adaptive timesteps existed, which
+    /// always took fixed steps.
+    #[serde(default)]
+    timestep: Timestep,
+    time: f64,
+    softening: f32,
+    solver: ForceSolver,
+    integrator: Integrator,
+    /// Missing from version 1 scenes, which only ever merged.
+    #[serde(default)]
+    collisions: CollisionModel,
+    /// Missing from scenes saved before external potentials existed.
+    #[serde(default)]
+    potentials: Vec<ExternalPotential>,
+    /// Missing from scenes saved before boundaries existed, which were all
+    /// unbounded.
+    #[serde(default)]
+    boundary: Boundary,
+    particles: Vec<ParticleRecord>,
+}
+
+/// Just the version of a scene, read before the rest of it so that a newer
+/// file is reported as such rather than as a parse error.
+#[derive(Deserialize)]
+struct SceneVersion {
+    version: u32,
+}
+
+impl Scene {
+    /// Take a copy of a system's state.
+    fn capture(system: &ParticleSystem) -> Self {
+        Scene {
+            version: SCENE_VERSION,
+            units: system.units,
+            dt: system.dt,
+            timestep: system.timestep,
+            time: system.time,
+            softening: system.softening,
+            solver: system.solver,
+            integrator: system.integrator,
+            collisions: system.collisions,
+            potentials: system.potentials.clone(),
+            boundary: system.boundary,
+            particles: system
+                .particles
+                .iter()
+                .map(|p| ParticleRecord {
+                    position: [p.position.x, p.position.y, p.position.z],
+                    velocity: [p.velocity.x, p.velocity.y, p.velocity.z],
+                    mass: p.mass,
+                    radius: p.radius,
+                    color: [p.color.red, p.color.green, p.color.blue],
+                    material: p.material,
+                })
+                .collect(),
+        }
+    }
+
+    /// Build a system with the saved state.
+    fn restore(self) -> ParticleSystem {
+        let mut system = ParticleSystem::new(self.units);
+        system.dt = self.dt;
+        system.timestep = self.timestep;
+        system.time = self.time;
+        system.softening = self.softening;
+        system.solver = self.solver;
+        system.integrator = self.integrator;
+        system.collisions = self.collisions;
+        system.potentials = self.potentials;
+        system.boundary = self.boundary;
+        for record in self.particles {
+            let mut particle = Particle::new(
+                vec3(record.position[0], record.position[1], record.position[2]),
+                vec3(record.velocity[0], record.velocity[1], record.velocity[2]),
+                record.mass,
+                record.radius,
+            );
+            let [red, green, blue] = record.color;
+            particle.color = rgb(red, green, blue);
+            particle.material = record.material;
+            system.add_particle(particle);
+        }
+        system
+    }
+}
+
+/// Read a vector written with two components (by versions 1 to 3, which
+/// were flat) or three.
+fn flat_or_spatial<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[f32; 3], D::Error> {
+    struct Components;
+
+    impl<'de> Visitor<'de> for Components {
+        type Value = [f32; 3];
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a vector of two or three numbers")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<[f32; 3], A::Error> {
+            let mut v = [0.0; 3];
+            for (i, c) in v.iter_mut().enumerate() {
+                match seq.next_element()? {
+                    Some(value) => *c = value,
+                    None if i == 2 => break,
+                    None => return Err(de::Error::invalid_length(i, &self)),
+                }
+            }
+            Ok(v)
+        }
+    }
+
+    deserializer.deserialize_tuple(3, Components)
+}
+
+/// Whether a path names a binary scene, rather than a RON one.
+fn is_binary(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == "bin")
+}
+
+/// Save a system to a scene file, in the format given by its extension.
+///
+/// Arguments:
+///
+/// * `system` - the system to save
+/// * `path` - where to save it; `.bin` for binary, anything else for RON
+pub fn save(system: &ParticleSystem, path: &Path) -> Result<(), SceneError> {
+    let scene = Scene::capture(system);
+    if is_binary(path) {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&SCENE_VERSION.to_le_bytes());
+        let body = bincode::serialize(&scene).map_err(|e| SceneError::Format(e.to_string()))?;
+        bytes.extend_from_slice(&body);
+        fs::write(path, bytes)?;
+    } else {
+        let text = ron::ser::to_string_pretty(&scene, ron::ser::PrettyConfig::default())
+            .map_err(|e| SceneError::Format(e.to_string()))?;
+        fs::write(path, text)?;
+    }
+    Ok(())
+}
+
+/// Load a system from a scene file, in the format given by its extension.
+///
+/// Arguments:
+///
+/// * `path` - the scene file; `.bin` for binary, anything else for RON
+///
+/// Returns:
+///
+/// * `Result<ParticleSystem, SceneError>` - the saved system, ready to run
+pub fn load(path: &Path) -> Result<ParticleSystem, SceneError> {
+    let scene: Scene = if is_binary(path) {
+        let bytes = fs::read(path)?;
+        if bytes.len() < 8 || &bytes[..4] != MAGIC {
+            return Err(SceneError::Format("missing the binary scene header".into()));
+        }
+        let version = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+        if version != SCENE_VERSION {
+            return Err(SceneError::Version(version));
+        }
+        bincode::deserialize(&bytes[8..]).map_err(|e| SceneError::Format(e.to_string()))?
+    } else {
+        let text = fs::read_to_string(path)?;
+        let header: SceneVersion =
+            ron::from_str(&text).map_err(|e| SceneError::Format(e.to_string()))?;
+        if header.version > SCENE_VERSION {
+            return Err(SceneError::Version(header.version));
+        }
+        ron::from_str(&text).map_err(|e| SceneError::Format(e.to_string()))?
+    };
+    Ok(scene.restore())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::collision::AllowedOutcomes;
+    use crate::potential::Potential;
+
+    /// A scratch file in the temporary directory, deleted when dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let file = format!("nannou-galaxy-{}-{}", std::process::id(), name);
+            Scratch(std::env::temp_dir().join(file))
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// A small system with nothing left at its default.
+    fn system() -> ParticleSystem {
+        let mut system = ParticleSystem::new(UnitSystem::SOLAR);
+        system.dt = 0.25;
+        system.timestep = Timestep::Block {
+            eta: 0.02,
+            max_level: 6,
+        };
+        system.time = 12.5;
+        system.softening = 0.01;
+        system.solver = ForceSolver::P3m { grid: 32 };
+        system.integrator = Integrator::Rk4;
+        system.collisions = CollisionModel {
+            allowed: AllowedOutcomes::ALL,
+            restitution: 0.8,
+            ..CollisionModel::default()
+        };
+        system
+            .potentials
+            .push(ExternalPotential::new(Potential::PointMass { mass: 2.0 }));
+        system.boundary = Boundary::Reflective {
+            size: 40.0,
+            restitution: 0.9,
+        };
+        for (i, material) in [Material::Rock, Material::Ice, Material::Gas]
+            .into_iter()
+            .enumerate()
+        {
+            let x = i as f32;
+            let mut p = Particle::new(vec3(x, -x, 0.5 * x), vec3(0.1, x, -2.0), 1.0 + x, 0.2);
+            p.color = rgb(0.1 * x, 0.5, 1.0);
+            p.material = material;
+            system.add_particle(p);
+        }
+        system
+    }
+
+    fn assert_same(a: &ParticleSystem, b: &ParticleSystem) {
+        assert_eq!(a.units, b.units);
+        assert_eq!(a.dt, b.dt);
+        assert_eq!(a.timestep, b.timestep);
+        assert_eq!(a.time, b.time);
+        assert_eq!(a.softening, b.softening);
+        assert_eq!(a.solver, b.solver);
+        assert_eq!(a.integrator, b.integrator);
+        assert_eq!(a.collisions, b.collisions);
+        assert_eq!(a.potentials, b.potentials);
+        assert_eq!(a.boundary, b.boundary);
+        assert_eq!(a.particles.as_slice().len(), b.particles.as_slice().len());
+        for (p, q) in a.particles.iter().zip(b.particles.iter()) {
+            assert_eq!(p.position, q.position);
+            assert_eq!(p.velocity, q.velocity);
+            assert_eq!(p.mass, q.mass);
+            assert_eq!(p.radius, q.radius);
+            assert_eq!(p.color, q.color);
+            assert_eq!(p.material, q.material);
+        }
+    }
+
+    #[test]
+    fn ron_scenes_round_trip() {
+        let file = Scratch::new("round-trip.ron");
+        let original = system();
+        save(&original, &file.0).unwrap();
+        assert_same(&original, &load(&file.0).unwrap());
+    }
+
+    #[test]
+    fn binary_scenes_round_trip() {
+        let file = Scratch::new("round-trip.bin");
+        let original = system();
+        save(&original, &file.0).unwrap();
+        assert_same(&original, &load(&file.0).unwrap());
+    }
+
+    #[test]
+    fn flat_version_1_scenes_load_in_the_plane() {
+        let file = Scratch::new("version-1.ron");
+        let text = format!(
+            "(version: 1, units: {}, dt: 0.5, time: 2.0, softening: 0.1, solver: {}, \
+             integrator: Leapfrog, particles: [(position: (1.0, 2.0), \
+             velocity: (3.0, 4.0), mass: 5.0, radius: 6.0, color: (1.0, 0.5, 0.25))])",
+            ron::to_string(&UnitSystem::SOLAR).unwrap(),
+            ron::to_string(&ForceSolver::default()).unwrap(),
+        );
+        fs::write(&file.0, text).unwrap();
+        let system = load(&file.0).unwrap();
+        let p = &system.particles.as_slice()[0];
+        assert_eq!(p.position, vec3(1.0, 2.0, 0.0));
+        assert_eq!(p.velocity, vec3(3.0, 4.0, 0.0));
+        assert_eq!(p.material, Material::default());
+        assert_eq!(system.collisions, CollisionModel::default());
+        assert_eq!(system.boundary, Boundary::Unbounded);
+    }
+
+    #[test]
+    fn binary_scenes_from_other_versions_are_refused() {
+        let file = Scratch::new("other.bin");
+        for version in [SCENE_VERSION - 1, SCENE_VERSION + 1] {
+            save(&system(), &file.0).unwrap();
+            let mut bytes = fs::read(&file.0).unwrap();
+            bytes[4..8].copy_from_slice(&version.to_le_bytes());
+            fs::write(&file.0, bytes).unwrap();
+            match load(&file.0) {
+                Err(SceneError::Version(v)) => assert_eq!(v, version),
+                other => panic!("expected a version error, got {:?}", other.err()),
+            }
+        }
+    }
+
+    #[test]
+    fn newer_ron_scenes_are_refused() {
+        let file = Scratch::new("newer.ron");
+        save(&system(), &file.0).unwrap();
+        let text = fs::read_to_string(&file.0).unwrap();
+        let current = format!("version: {},", SCENE_VERSION);
+        assert!(text.contains(&current));
+        let newer = format!("version: {},", SCENE_VERSION + 1);
+        fs::write(&file.0, text.replacen(&current, &newer, 1)).unwrap();
+        match load(&file.0) {
+            Err(SceneError::Version(v)) => assert_eq!(v, SCENE_VERSION + 1),
+            other => panic!("expected a version error, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn other_files_are_not_taken_for_binary_scenes() {
+        let file = Scratch::new("not-a-scene.bin");
+        fs::write(&file.0, b"PNG\0 and then some").unwrap();
+        assert!(matches!(load(&file.0), Err(SceneError::Format(_))));
+    }
+}
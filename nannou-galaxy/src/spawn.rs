@@ -0,0 +1,279 @@
+/*
+Slingshot spawning for the galaxy simulation.
+
+Press the left mouse button where a new body should appear, pull back, and let
+go (in a turned view, the body goes in the plane through the middle of the
+window, facing the camera): the body is launched in the opposite direction to
+the pull, like a slingshot, and the further you pull the faster it goes. A pull of length d
+launches it at d / (LAUNCH_STEPS * dt), i.e. fast enough to cover the pull in
+LAUNCH_STEPS timesteps, so the feel is the same whatever the units and scene.
+
+The new body's mass starts at a thousandth of the system's total mass. Scroll
+the wheel while pulling to double or halve it per notch, and hold shift at the
+moment of release to make it ten times heavier, or alt to make it ten times
+lighter. It is made of the same material as the heaviest body in the system,
+and takes that material's color; its radius is scaled from that body's as if
+they had the same density.
+
+While pulling, the path the body would follow is previewed by stepping it
+through the gravitational field of the current particles, held still, and of
+any external potentials (see potential.rs). The rest of the system keeps
+moving in the real simulation, of course, so the preview is a guide for aiming
+rather than a prophecy, but over a short look-ahead it's a good one.
+
+The preview samples the particles' field from a Barnes-Hut tree, built once
+per preview, rather than summing over every body at each of its steps, and it
+is only worked out again when the pull, the system or its settings (softening,
+solver, potentials, boundary) have changed since the last frame, so dragging
+stays smooth over thousands of stars.
+*/
+
+use nannou::prelude::*;
+
+use crate::boundary::Boundary;
+use crate::gravity::{self, ForceSolver, Gravity};
+use crate::material::Material;
+use crate::potential::{self, ExternalPotential};
+use crate::store::ParticleStore;
+use crate::viewport::Viewport;
+use crate::{Particle, ParticleSystem};
+
+/// How many timesteps a launched body takes to cover the length of the pull.
+const LAUNCH_STEPS: f32 = 100.0;
+
+/// How many timesteps ahead the trajectory preview looks.
+const PREVIEW_STEPS: usize = 400;
+
+/// The Barnes-Hut opening angle of the field the preview samples.
+const PREVIEW_THETA: f32 = 0.5;
+
+/// The state of a slingshot pull in progress.
+#[derive(Clone, Debug, Default)]
+pub struct Slingshot {
+    /// Where the pull started, in simulation coordinates: the new body's
+    /// position.
+    anchor: Option<Vector3>,
+    /// Where the cursor is now, in simulation coordinates.
+    cursor: Vector3,
+    /// The mass doubles for each notch scrolled up, and halves for each notch
+    /// scrolled down.
+    notches: f32,
+    /// The predicted path of the new body.
+    preview: Vec<Vector3>,
+    /// What the preview was worked out for.
+    previewed: Option<PreviewKey>,
+}
+
+/// Everything a trajectory preview depends on, so it's only worked out again
+/// when one of them changes.
+#[derive(Clone, Debug, PartialEq)]
+struct PreviewKey {
+    /// The launch position.
+    position: Vector3,
+    /// The launch velocity.
+    velocity: Vector3,
+    /// The simulation time.
+    time: f64,
+    /// The number of particles.
+    particles: usize,
+    /// The gravitational constant, softening and period.
+    gravity: Gravity,
+    /// The force solver.
+    solver: ForceSolver,
+    /// The external potentials, including which are switched on.
+    potentials: Vec<ExternalPotential>,
+    /// The boundary.
+    boundary: Boundary,
+}
+
+impl Slingshot {
+    /// Whether a pull is in progress.
+    pub fn is_pulling(&self) -> bool {
+        self.anchor.is_some()
+    }
+
+    /// Start a pull at a point in the simulation.
+    pub fn start(&mut self, at: Vector3) {
+        self.anchor = Some(at);
+        self.cursor = at;
+        self.preview.clear();
+        self.previewed = None;
+    }
+
+    /// Follow the cursor, in simulation coordinates.
+    pub fn pull_to(&mut self, cursor: Vector3) {
+        self.cursor = cursor;
+    }
+
+    /// Make the new body heavier (positive) or lighter (negative) by a scroll
+    /// of the mouse wheel.
+    pub fn scroll(&mut self, delta: MouseScrollDelta) {
+        self.notches += match delta {
+            MouseScrollDelta::LineDelta(_, y) => y,
+            MouseScrollDelta::PixelDelta(position) => position.y as f32 / 50.0,
+        };
+    }
+
+    /// The body that would be launched if the pull ended now.
+    ///
+    /// Arguments:
+    ///
+    /// * `system` - the system the body would join
+    /// * `mass_factor` - an extra factor on the mass, e.g. from modifier keys
+    fn body(&self, system: &ParticleSystem, mass_factor: f32) -> Option<Particle> {
+        let anchor = self.anchor?;
+        let velocity = (anchor - self.cursor) / (LAUNCH_STEPS * system.dt);
+
+        let particles = &system.particles;
+        let total_mass: f32 = particles.iter().map(|p| p.mass).sum();
+        let mass = if total_mass > 0.0 {
+            1e-3 * total_mass * 2f32.powf(self.notches) * mass_factor
+        } else {
+            1.0
+        };
+        let scaling = system.collisions.radius_scaling;
+        Some(match heaviest(particles) {
+            Some(p) if p.mass > 0.0 => {
+                let mut body = Particle::new(
+                    anchor,
+                    velocity,
+                    mass,
+                    scaling.scale(p.radius, mass / p.mass),
+                );
+                body.color = p.material.color();
+                body.material = p.material;
+                body
+            }
+            _ => Particle::of_material(anchor, velocity, mass, Material::default(), &system.units),
+        })
+    }
+
+    /// Recompute the trajectory preview for the current pull, unless neither
+    /// it, the system nor any of its settings has changed since the last one.
+    pub fn update_preview(&mut self, system: &ParticleSystem, mass_factor: f32) {
+        let body = match self.body(system, mass_factor) {
+            Some(body) => body,
+            None => {
+                self.preview.clear();
+                self.previewed = None;
+                return;
+            }
+        };
+        let gravity = system.gravity();
+        let key = PreviewKey {
+            position: body.position,
+            velocity: body.velocity,
+            time: system.time,
+            particles: system.particles.as_slice().len(),
+            gravity,
+            solver: system.solver,
+            potentials: system.potentials.clone(),
+            boundary: system.boundary,
+        };
+        if self.previewed.as_ref() == Some(&key) {
+            return;
+        }
+        self.preview = trajectory(
+            &gravity::Field::new(system.particles.as_slice(), gravity, PREVIEW_THETA),
+            gravity,
+            &system.potentials,
+            system.time,
+            body.position,
+            body.velocity,
+            system.dt,
+        );
+        self.previewed = Some(key);
+    }
+
+    /// End the pull, launching the new body into the system.
+    ///
+    /// Arguments:
+    ///
+    /// * `system` - the system to add the body to
+    /// * `mass_factor` - an extra factor on the mass, e.g. from modifier keys
+    pub fn release(&mut self, system: &mut ParticleSystem, mass_factor: f32) {
+        if let Some(body) = self.body(system, mass_factor) {
+            system.add_particle(body);
+        }
+        self.anchor = None;
+        self.preview.clear();
+        self.previewed = None;
+    }
+
+    /// Draw the pull and the predicted path, if a pull is in progress.
+    pub fn draw(&self, draw: &Draw, viewport: &Viewport) {
+        let anchor = self.anchor.and_then(|a| viewport.to_screen(a));
+        let (anchor, cursor) = match (anchor, viewport.to_screen(self.cursor)) {
+            (Some(anchor), Some(cursor)) => (anchor, cursor),
+            _ => return,
+        };
+        draw.line()
+            .start(anchor)
+            .end(cursor)
+            .weight(1.5)
+            .color(rgba(1.0, 1.0, 1.0, 0.6));
+        draw.ellipse()
+            .xy(anchor)
+            .radius(4.0)
+            .color(rgba(0.6, 0.9, 1.0, 0.8));
+
+        // The predicted path, fading out as it gets less certain, and cut
+        // short where it goes behind the camera.
+        let count = self.preview.len().max(1) as f32;
+        draw.polyline().weight(1.0).points_colored(
+            self.preview
+                .iter()
+                .map_while(|&p| viewport.to_screen(p))
+                .enumerate()
+                .map(|(i, p)| (p, rgba(0.6, 0.9, 1.0, 0.8 * (1.0 - i as f32 / count)))),
+        );
+    }
+}
+
+/// The heaviest particle, if there are any.
+fn heaviest(particles: &ParticleStore) -> Option<&Particle> {
+    particles.iter().max_by(|a, b| a.mass.total_cmp(&b.mass))
+}
+
+/// Step a test body through the field of some fixed particles with leapfrog.
+///
+/// Arguments:
+///
+/// * `field` - the field of the particles, which don't move
+/// * `gravity` - the gravitational constant and softening
+/// * `potentials` - the external potentials adding to the field
+/// * `time` - the simulation time the body starts at
+/// * `position` - where the test body starts
+/// * `velocity` - how fast it starts moving
+/// * `dt` - the timestep
+///
+/// Returns:
+///
+/// * `Vec<Vector3>` - the body's positions, starting with `position`
+fn trajectory(
+    field: &gravity::Field,
+    gravity: Gravity,
+    potentials: &[ExternalPotential],
+    time: f64,
+    mut position: Vector3,
+    mut velocity: Vector3,
+    dt: f32,
+) -> Vec<Vector3> {
+    let mut path = Vec::with_capacity(PREVIEW_STEPS + 1);
+    path.push(position);
+    let field = |position: Vector3, time: f64| {
+        field.at(position) + potential::field_at(potentials, position, time, gravity)
+    };
+    let mut acceleration = field(position, time);
+    for step in 1..=PREVIEW_STEPS {
+        velocity += acceleration * (0.5 * dt);
+        position += velocity * dt;
+        acceleration = field(position, time + (step as f32 * dt) as f64);
+        velocity += acceleration * (0.5 * dt);
+        if !(position.x.is_finite() && position.y.is_finite() && position.z.is_finite()) {
+            break;
+        }
+        path.push(position);
+    }
+    path
+}
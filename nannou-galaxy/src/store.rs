@@ -0,0 +1,357 @@
+/*
+Particle storage for the galaxy simulation.
+
+Every particle that joins a system gets a `ParticleId`. An id is a slot index
+plus a generation: when a particle leaves, its slot is reused by a later
+particle, but with the next generation, so an old id never finds the new
+occupant. Ids are therefore never ambiguous, even when two bodies sit on the
+same spot or one is merged away and another takes its place.
+
+The particles themselves are kept packed together in a `Vec`, in no particular
+order, because the force solvers want a plain slice. Each slot records where
+its particle is in that `Vec`, and a removal swaps the last particle into the
+hole and updates that particle's slot. Adding, removing and looking up a
+particle by id are all O(1).
+
+When particles merge, the store records which body each of them became, so
+that anything holding on to an old id (a camera following a body, a trail) can
+find where its mass ended up. Every so often that record is tidied: chains of
+merges are collapsed so each old id points straight at the body that holds its
+mass now, and ids whose mass has left the system for good (culled, escaped)
+are forgotten, as nothing can be found through them anyway. So the record
+grows with the ids that still lead somewhere, not with every merge and capture
+of the run.
+*/
+
+use std::collections::HashMap;
+use std::fmt;
+use std::mem;
+
+use crate::Particle;
+
+/// The fewest merge records kept before they are tidied.
+const MIN_LINEAGE: usize = 64;
+
+/// A stable handle to a particle in a `ParticleStore`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ParticleId {
+    index: u32,
+    generation: u32,
+}
+
+impl ParticleId {
+    /// The id of a particle that hasn't joined a system yet.
+    pub const UNASSIGNED: ParticleId = ParticleId {
+        index: u32::MAX,
+        generation: 0,
+    };
+}
+
+impl fmt::Display for ParticleId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}v{}", self.index, self.generation)
+    }
+}
+
+/// One entry in the slot table.
+#[derive(Clone, Debug)]
+struct Slot {
+    /// The generation of the slot's current (or most recent) occupant.
+    generation: u32,
+    /// Where the occupant is in the packed particle list, if the slot is in use.
+    dense: Option<usize>,
+}
+
+/// A generational slot map of particles.
+#[derive(Clone, Default)]
+pub struct ParticleStore {
+    /// The particles, packed together.
+    particles: Vec<Particle>,
+    /// One slot per id index ever handed out.
+    slots: Vec<Slot>,
+    /// The indices of the slots that are free to reuse.
+    free: Vec<u32>,
+    /// For each particle that was merged away, the body it was merged into.
+    merged_into: HashMap<ParticleId, ParticleId>,
+    /// How many merge records were left after they were last tidied.
+    lineage_kept: usize,
+}
+
+impl ParticleStore {
+    /// Create an empty store.
+    pub fn new() -> Self {
+        ParticleStore::default()
+    }
+
+    /// Add a particle, giving it a fresh id.
+    ///
+    /// Returns:
+    ///
+    /// * `ParticleId` - the id of the new particle, also stored on it
+    pub fn insert(&mut self, mut particle: Particle) -> ParticleId {
+        let dense = self.particles.len();
+        let id = match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.generation += 1;
+                slot.dense = Some(dense);
+                ParticleId {
+                    index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    dense: Some(dense),
+                });
+                ParticleId {
+                    index: self.slots.len() as u32 - 1,
+                    generation: 0,
+                }
+            }
+        };
+        particle.id = id;
+        self.particles.push(particle);
+        id
+    }
+
+    /// Where the particle with this id is in the packed list, if it's here.
+    fn dense_index(&self, id: ParticleId) -> Option<usize> {
+        let slot = self.slots.get(id.index as usize)?;
+        if slot.generation == id.generation {
+            slot.dense
+        } else {
+            None
+        }
+    }
+
+    /// Look up a particle by id.
+    pub fn get(&self, id: ParticleId) -> Option<&Particle> {
+        self.dense_index(id).map(|i| &self.particles[i])
+    }
+
+    /// Look up a particle by id, to change it.
+    pub fn get_mut(&mut self, id: ParticleId) -> Option<&mut Particle> {
+        self.dense_index(id).map(move |i| &mut self.particles[i])
+    }
+
+    /// Look up two different particles by id, to change them together.
+    ///
+    /// Returns:
+    ///
+    /// * `Option<(&mut Particle, &mut Particle)>` - the particles, in the
+    ///   order asked for, or None if either is missing or they're the same
+    pub fn get_pair_mut(
+        &mut self,
+        a: ParticleId,
+        b: ParticleId,
+    ) -> Option<(&mut Particle, &mut Particle)> {
+        let (i, j) = (self.dense_index(a)?, self.dense_index(b)?);
+        if i < j {
+            let (low, high) = self.particles.split_at_mut(j);
+            Some((&mut low[i], &mut high[0]))
+        } else if j < i {
+            let (low, high) = self.particles.split_at_mut(i);
+            Some((&mut high[0], &mut low[j]))
+        } else {
+            None
+        }
+    }
+
+    /// Remove a particle by id.
+    ///
+    /// Returns:
+    ///
+    /// * `Option<Particle>` - the removed particle, or None if no particle
+    ///   has this id
+    pub fn remove(&mut self, id: ParticleId) -> Option<Particle> {
+        let dense = self.dense_index(id)?;
+        self.slots[id.index as usize].dense = None;
+        self.free.push(id.index);
+        let removed = self.particles.swap_remove(dense);
+        if let Some(moved) = self.particles.get(dense) {
+            self.slots[moved.id.index as usize].dense = Some(dense);
+        }
+        Some(removed)
+    }
+
+    /// Remove every particle for which `keep` returns false.
+    ///
+    /// Returns:
+    ///
+    /// * `Vec<Particle>` - the removed particles
+    pub fn remove_where<F>(&mut self, mut keep: F) -> Vec<Particle>
+    where
+        F: FnMut(&Particle) -> bool,
+    {
+        let doomed: Vec<ParticleId> = self
+            .particles
+            .iter()
+            .filter(|p| !keep(p))
+            .map(|p| p.id)
+            .collect();
+        doomed
+            .into_iter()
+            .filter_map(|id| self.remove(id))
+            .collect()
+    }
+
+    /// Record that the `parents` were merged into the body `child`.
+    ///
+    /// The parents must already have been removed. Once the records have
+    /// doubled since they were last tidied, they are tidied again, which
+    /// keeps the cost per merge constant on average.
+    pub fn record_merge(&mut self, parents: &[ParticleId], child: ParticleId) {
+        for &parent in parents {
+            self.merged_into.insert(parent, child);
+        }
+        if self.merged_into.len() > 2 * self.lineage_kept.max(MIN_LINEAGE) {
+            self.prune_lineage();
+        }
+    }
+
+    /// Point every merge record straight at the body that holds its mass
+    /// now, and forget the ones that don't lead to any body.
+    fn prune_lineage(&mut self) {
+        let resolved: HashMap<ParticleId, ParticleId> = self
+            .merged_into
+            .keys()
+            .filter_map(|&id| Some((id, self.resolve(id)?)))
+            .collect();
+        self.merged_into = resolved;
+        self.lineage_kept = self.merged_into.len();
+    }
+
+    /// Follow an id through any merges to the body that holds its mass now.
+    ///
+    /// Returns:
+    ///
+    /// * `Option<ParticleId>` - the id itself if the particle is still here,
+    ///   the body it was (eventually) merged into if that is here, or None if
+    ///   it is gone for another reason
+    pub fn resolve(&self, mut id: ParticleId) -> Option<ParticleId> {
+        loop {
+            if self.dense_index(id).is_some() {
+                return Some(id);
+            }
+            id = *self.merged_into.get(&id)?;
+        }
+    }
+
+    /// Roughly how many bytes a copy of the store takes on the heap: the
+    /// particles, the slot table, the free list and the merge records.
+    pub fn heap_size(&self) -> usize {
+        // A hash map keeps a control byte beside each bucket.
+        let lineage =
+            self.merged_into.capacity() * (mem::size_of::<(ParticleId, ParticleId)>() + 1);
+        mem::size_of_val(self.particles.as_slice())
+            + mem::size_of_val(self.slots.as_slice())
+            + mem::size_of_val(self.free.as_slice())
+            + lineage
+    }
+
+    /// All the particles, packed together in no particular order.
+    pub fn as_slice(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    /// All the particles, for changing their state in place.
+    ///
+    /// The ids must be left alone.
+    pub fn as_mut_slice(&mut self) -> &mut [Particle] {
+        &mut self.particles
+    }
+
+    /// Iterate over the particles.
+    pub fn iter(&self) -> std::slice::Iter<'_, Particle> {
+        self.particles.iter()
+    }
+
+    /// Iterate mutably over the particles. The ids must be left alone.
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, Particle> {
+        self.particles.iter_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nannou::prelude::*;
+
+    fn body(mass: f32) -> Particle {
+        Particle::new(vec3(mass, 0.0, 0.0), vec3(0.0, 0.0, 0.0), mass, 1.0)
+    }
+
+    /// Add a tracer and have `into` capture it straight away.
+    fn capture_into(store: &mut ParticleStore, into: ParticleId) -> ParticleId {
+        let tracer = store.insert(body(0.0));
+        store.remove(tracer);
+        store.record_merge(&[tracer], into);
+        tracer
+    }
+
+    #[test]
+    fn reused_slots_get_a_new_generation() {
+        let mut store = ParticleStore::new();
+        let a = store.insert(body(1.0));
+        assert_eq!(store.remove(a).map(|p| p.mass), Some(1.0));
+        let b = store.insert(body(2.0));
+        assert_eq!(a.index, b.index);
+        assert_ne!(a.generation, b.generation);
+        assert!(store.get(a).is_none());
+        assert!(store.remove(a).is_none());
+        assert_eq!(store.get(b).map(|p| p.mass), Some(2.0));
+    }
+
+    #[test]
+    fn removal_keeps_the_others_findable() {
+        let mut store = ParticleStore::new();
+        let ids: Vec<ParticleId> = (1..=4).map(|m| store.insert(body(m as f32))).collect();
+        store.remove(ids[1]);
+        for (&id, mass) in ids.iter().zip([1.0, 2.0, 3.0, 4.0]) {
+            if id != ids[1] {
+                assert_eq!(store.get(id).map(|p| (p.id, p.mass)), Some((id, mass)));
+            }
+        }
+        assert_eq!(store.as_slice().len(), 3);
+    }
+
+    #[test]
+    fn resolve_follows_a_chain_of_merges() {
+        let mut store = ParticleStore::new();
+        let (a, b, d) = (
+            store.insert(body(1.0)),
+            store.insert(body(2.0)),
+            store.insert(body(4.0)),
+        );
+        store.remove(a);
+        store.remove(b);
+        let c = store.insert(body(3.0));
+        store.record_merge(&[a, b], c);
+        store.remove(c);
+        store.remove(d);
+        let e = store.insert(body(7.0));
+        store.record_merge(&[c, d], e);
+
+        for id in [a, b, c, d, e] {
+            assert_eq!(store.resolve(id), Some(e));
+        }
+        store.remove(e);
+        assert_eq!(store.resolve(a), None);
+    }
+
+    #[test]
+    fn lineage_of_departed_bodies_is_forgotten() {
+        let mut store = ParticleStore::new();
+        let first = store.insert(body(1.0));
+        let early: Vec<ParticleId> = (0..200).map(|_| capture_into(&mut store, first)).collect();
+        store.remove(first);
+        let second = store.insert(body(1.0));
+        let late: Vec<ParticleId> = (0..200).map(|_| capture_into(&mut store, second)).collect();
+
+        assert!(early.iter().all(|&id| store.resolve(id).is_none()));
+        assert!(late.iter().all(|&id| store.resolve(id) == Some(second)));
+        assert!(store.merged_into.len() < 400, "{}", store.merged_into.len());
+    }
+}
@@ -0,0 +1,533 @@
+/*
+Timestep control for the galaxy simulation.
+
+With one fixed step for everything, a close encounter either crawls (the step
+is short enough for the closest pass, and wasted on everything else) or
+explodes (it isn't, and the pair is flung apart with energy from nowhere).
+Each update of a `ParticleSystem` still advances it by its timestep `dt`, but a
+`Timestep` chooses how it gets there:
+
+* `Fixed` takes the whole of `dt` in one step of the system's integrator.
+* `Adaptive` takes as many steps of the integrator as it needs, each as long as
+  the most demanding particle allows, but never longer than `dt` nor shorter
+  than `dt / 2^max_level`.
+* `Block` gives each particle a step of its own, `dt / 2^level`. Forces are
+  only computed on a particle when its own step ends, so a tight binary costs
+  its two bodies many force evaluations while the thousands of stars around it
+  keep taking long steps.
+
+The step a particle asks for is
+
+    dt_i = eta * max(|v| / |a|, sqrt(L / |a|))
+
+The first term is the time it takes the acceleration to turn the velocity
+through about a radian: a fixed fraction of an orbit, which shrinks during a
+close pass as the speed and the pull climb. A particle at rest has no such
+time, so it falls back on the second, the time to fall from rest across a
+length L: the softening length, or the particle's radius if gravity isn't
+softened. Velocities are measured relative to the center of mass, so a
+system drifting across the screen steps the same as one at rest. A smaller
+eta is more accurate and slower.
+
+Block steps are kick-drift-kick leapfrog (see integrator.rs), whichever
+integrator the system has chosen. Time is counted in ticks of the finest
+possible step, `dt / 2^max_level`, and the update jumps from one moment when
+some particle's step ends to the next. Everybody drifts to that moment, so the
+forces are always computed from positions at one time; the particles whose
+step ends get new forces, their closing half kick, a new level and the opening
+half kick of their next step. A particle can move to a finer level whenever its
+step ends, but to a coarser one only where the longer step would line up with
+the others. Every level's step divides `dt`, so every particle's last step
+ends together at the end of the update: the state that is drawn, saved and
+measured between updates is always synchronized, just as with a fixed step.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+
+use nannou::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::integrator::{Integrator, StepBuffers};
+use crate::Particle;
+
+/// The accuracy parameter that the adaptive schemes start with.
+const ETA: f32 = 0.05;
+
+/// The deepest level the adaptive schemes start with: steps are never shorter
+/// than `dt / 2^MAX_LEVEL`.
+const MAX_LEVEL: u32 = 12;
+
+/// The deepest level allowed at all, so that a tick count fits in a `u64`
+/// and a tick is still a sensible `f32`.
+const DEEPEST: u32 = 30;
+
+/// How each update of the system divides its timestep.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Timestep {
+    /// The whole timestep in one step.
+    #[default]
+    Fixed,
+    /// Steps for every particle at once, as long as the most demanding one
+    /// allows, down to `dt / 2^max_level`.
+    Adaptive { eta: f32, max_level: u32 },
+    /// A step of `dt / 2^level` for each particle, down to
+    /// `dt / 2^max_level`.
+    Block { eta: f32, max_level: u32 },
+}
+
+impl Timestep {
+    /// The scheme after this one, for cycling through them with a key.
+    pub fn next(&self) -> Self {
+        match self {
+            Timestep::Fixed => Timestep::Adaptive {
+                eta: ETA,
+                max_level: MAX_LEVEL,
+            },
+            Timestep::Adaptive { eta, max_level } => Timestep::Block {
+                eta: *eta,
+                max_level: *max_level,
+            },
+            Timestep::Block { .. } => Timestep::Fixed,
+        }
+    }
+
+    /// Advance the particles by a whole timestep.
+    ///
+    /// Arguments:
+    ///
+    /// * `integrator` - the integrator for fixed and adaptive steps
+    /// * `particles` - the particles to advance, whose `acceleration` is up
+    ///   to date with their positions
+    /// * `dt` - the length of the timestep
+    /// * `softening` - the Plummer softening length, which the step criterion
+    ///   measures fall times across
+    /// * `buffers` - scratch space for the integrator
+    /// * `accelerations` - writes the acceleration on every particle, at the
+    ///   given time since the start of the timestep, into the given buffer
+    /// * `accelerations_of` - writes the acceleration on each of the given
+    ///   particles, at the given time since the start of the timestep, into
+    ///   the given buffer
+    ///
+    /// Returns:
+    ///
+    /// * `Substeps` - how the timestep was divided
+    #[allow(clippy::too_many_arguments)]
+    pub fn advance<F, G>(
+        &self,
+        integrator: Integrator,
+        particles: &mut [Particle],
+        dt: f32,
+        softening: f32,
+        buffers: &mut StepBuffers,
+        accelerations: F,
+        accelerations_of: G,
+    ) -> Substeps
+    where
+        F: Fn(&[Particle], f32, &mut Vec<Vector3>),
+        G: Fn(&[Particle], &[usize], f32, &mut Vec<Vector3>),
+    {
+        match *self {
+            Timestep::Fixed => {
+                integrator.step(particles, dt, buffers, accelerations);
+                Substeps {
+                    count: 1,
+                    shortest: dt,
+                }
+            }
+            Timestep::Adaptive { eta, max_level } => adaptive_steps(
+                integrator,
+                particles,
+                dt,
+                Criterion::new(particles, eta, softening),
+                max_level.min(DEEPEST),
+                buffers,
+                &accelerations,
+            ),
+            Timestep::Block { eta, max_level } => block_steps(
+                particles,
+                dt,
+                Criterion::new(particles, eta, softening),
+                max_level.min(DEEPEST),
+                &mut buffers.blocks,
+                &accelerations_of,
+            ),
+        }
+    }
+}
+
+/// Write a timestep scheme in the form `FromStr` reads.
+impl fmt::Display for Timestep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Timestep::Fixed => write!(f, "fixed"),
+            Timestep::Adaptive { eta, max_level } => {
+                write!(f, "adaptive:eta={},max_level={}", eta, max_level)
+            }
+            Timestep::Block { eta, max_level } => {
+                write!(f, "block:eta={},max_level={}", eta, max_level)
+            }
+        }
+    }
+}
+
+/// Read a timestep scheme written as `kind` or `kind:name=value,...`, e.g.
+/// `block:eta=0.02,max_level=16`. Anything not given keeps its default.
+impl FromStr for Timestep {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (kind, parameters) = match s.split_once(':') {
+            Some((kind, parameters)) => (kind, parameters),
+            None => (s, ""),
+        };
+        let (mut eta, mut max_level) = (ETA, MAX_LEVEL);
+        for parameter in parameters.split(',').filter(|p| !p.trim().is_empty()) {
+            let (name, value) = parameter
+                .split_once('=')
+                .ok_or_else(|| format!("expected name=value, got {:?}", parameter))?;
+            match name.trim() {
+                "eta" => {
+                    eta = value
+                        .trim()
+                        .parse()
+                        .map_err(|e| format!("bad value for eta: {}", e))?;
+                    if eta.is_nan() || eta <= 0.0 {
+                        return Err(format!("eta must be positive, got {}", eta));
+                    }
+                }
+                "max_level" => {
+                    max_level = value
+                        .trim()
+                        .parse()
+                        .map_err(|e| format!("bad value for max_level: {}", e))?;
+                    if max_level > DEEPEST {
+                        return Err(format!("max_level can be at most {}", DEEPEST));
+                    }
+                }
+                other => return Err(format!("unknown parameter {:?}", other)),
+            }
+        }
+        match kind.trim().to_lowercase().as_str() {
+            "fixed" if parameters.trim().is_empty() => Ok(Timestep::Fixed),
+            "fixed" => Err("a fixed timestep has no parameters".to_string()),
+            "adaptive" => Ok(Timestep::Adaptive { eta, max_level }),
+            "block" => Ok(Timestep::Block { eta, max_level }),
+            other => Err(format!(
+                "unknown timestep {:?} (expected fixed, adaptive or block)",
+                other
+            )),
+        }
+    }
+}
+
+/// How one timestep was divided.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Substeps {
+    /// How many times forces were computed, on some or all of the particles.
+    pub count: u32,
+    /// The shortest step any particle took.
+    pub shortest: f32,
+}
+
+/// Scratch space for block timesteps, kept from step to step in
+/// `StepBuffers` so that stepping doesn't allocate.
+#[derive(Default)]
+pub struct BlockBuffers {
+    /// Each particle's level.
+    levels: Vec<u32>,
+    /// The tick each particle's step ends at.
+    ends: Vec<u64>,
+    /// The indices of the particles whose step ends now.
+    active: Vec<usize>,
+    /// Where the force solver writes their accelerations.
+    accelerations: Vec<Vector3>,
+}
+
+/// The step criterion described at the top of this file.
+struct Criterion {
+    eta: f32,
+    softening: f32,
+    /// The velocity of the center of mass, which speeds are measured from.
+    frame: Vector3,
+}
+
+impl Criterion {
+    fn new(particles: &[Particle], eta: f32, softening: f32) -> Self {
+        let mass: f32 = particles.iter().map(|p| p.mass).sum();
+        let frame = if mass > 0.0 {
+            particles
+                .iter()
+                .fold(vec3(0.0, 0.0, 0.0), |sum, p| sum + p.velocity * p.mass)
+                / mass
+        } else {
+            vec3(0.0, 0.0, 0.0)
+        };
+        Criterion {
+            eta,
+            softening,
+            frame,
+        }
+    }
+
+    /// The step a particle asks for; infinite if nothing pulls on it.
+    fn step(&self, p: &Particle) -> f32 {
+        let a = p.acceleration.magnitude();
+        if a == 0.0 {
+            return f32::INFINITY;
+        }
+        let length = if self.softening > 0.0 {
+            self.softening
+        } else {
+            p.radius
+        };
+        let turn = (p.velocity - self.frame).magnitude() / a;
+        let fall = (length / a).sqrt();
+        self.eta * turn.max(fall)
+    }
+
+    /// The level whose step, `dt / 2^level`, is the longest no longer than
+    /// the one a particle asks for, down to `max_level`.
+    fn level(&self, p: &Particle, dt: f32, max_level: u32) -> u32 {
+        let wanted = self.step(p);
+        // Also sends a NaN to level 0; the particle is removed after the step.
+        if wanted.is_nan() || wanted >= dt {
+            return 0;
+        }
+        ((dt / wanted).log2().ceil() as u32).min(max_level)
+    }
+}
+
+/// Advance by `dt` in global steps of the integrator, each chosen by the
+/// criterion, the last cut short to land exactly at the end.
+fn adaptive_steps<F>(
+    integrator: Integrator,
+    particles: &mut [Particle],
+    dt: f32,
+    criterion: Criterion,
+    max_level: u32,
+    buffers: &mut StepBuffers,
+    accelerations: &F,
+) -> Substeps
+where
+    F: Fn(&[Particle], f32, &mut Vec<Vector3>),
+{
+    let shortest_allowed = dt / 2f32.powi(max_level as i32);
+    let mut substeps = Substeps {
+        count: 0,
+        shortest: dt,
+    };
+    let mut done = 0.0;
+    loop {
+        let wanted = particles
+            .iter()
+            .map(|p| criterion.step(p))
+            .fold(dt, f32::min)
+            .max(shortest_allowed);
+        let left = dt - done;
+        // Take the rest in one go rather than leave a sliver of a step.
+        let last = wanted >= left || left - wanted < 0.5 * shortest_allowed;
+        let h = if last { left } else { wanted };
+        let start = done;
+        integrator.step(particles, h, buffers, |particles, since, out| {
+            accelerations(particles, start + since, out)
+        });
+        substeps.count += 1;
+        substeps.shortest = substeps.shortest.min(h);
+        done += h;
+        if last {
+            return substeps;
+        }
+    }
+}
+
+/// Advance by `dt` with a power-of-two step for each particle, as described
+/// at the top of this file.
+fn block_steps<G>(
+    particles: &mut [Particle],
+    dt: f32,
+    criterion: Criterion,
+    max_level: u32,
+    buffers: &mut BlockBuffers,
+    accelerations_of: &G,
+) -> Substeps
+where
+    G: Fn(&[Particle], &[usize], f32, &mut Vec<Vector3>),
+{
+    let ticks: u64 = 1 << max_level;
+    let tick = dt / ticks as f32;
+    // The number of ticks in a step of each level.
+    let span = |level: u32| ticks >> level;
+
+    let BlockBuffers {
+        levels,
+        ends,
+        active,
+        accelerations,
+    } = buffers;
+    levels.clear();
+    levels.extend(particles.iter().map(|p| criterion.level(p, dt, max_level)));
+    ends.clear();
+    ends.extend(levels.iter().map(|&level| span(level)));
+    let mut substeps = Substeps {
+        count: 0,
+        shortest: dt,
+    };
+    for (p, &level) in particles.iter_mut().zip(levels.iter()) {
+        p.velocity += p.acceleration * (0.5 * tick * span(level) as f32);
+    }
+
+    let mut now = 0;
+    while now < ticks {
+        let next = ends.iter().copied().min().unwrap_or(ticks);
+        let drift = tick * (next - now) as f32;
+        for p in particles.iter_mut() {
+            p.position += p.velocity * drift;
+        }
+        now = next;
+
+        active.clear();
+        active.extend((0..particles.len()).filter(|&i| ends[i] == now));
+        accelerations_of(particles, active, tick * now as f32, accelerations);
+        substeps.count += 1;
+
+        for (&i, &a) in active.iter().zip(accelerations.iter()) {
+            let p = &mut particles[i];
+            let step = tick * span(levels[i]) as f32;
+            substeps.shortest = substeps.shortest.min(step);
+            p.acceleration = a;
+            p.velocity += a * (0.5 * step);
+            if now == ticks {
+                continue;
+            }
+            // Go finer straight away, but only as coarse as lines up.
+            let wanted = criterion.level(p, dt, max_level);
+            let level = (wanted..=levels[i])
+                .find(|&level| now % span(level) == 0)
+                .unwrap_or(wanted);
+            levels[i] = level;
+            ends[i] = now + span(level);
+            p.velocity += a * (0.5 * tick * span(level) as f32);
+        }
+    }
+    substeps
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// The pull of a unit point mass at the origin.
+    fn kepler(position: Vector3) -> Vector3 {
+        -position / position.magnitude().powi(3)
+    }
+
+    /// Bodies on circular orbits about a unit point mass, from a tight one
+    /// that wants short steps to wide ones that don't.
+    fn orbits() -> Vec<Particle> {
+        [0.05, 0.5, 5.0, 50.0]
+            .into_iter()
+            .map(|r: f32| {
+                let mut p =
+                    Particle::new(vec3(r, 0.0, 0.0), vec3(0.0, r.powf(-0.5), 0.0), 0.0, 0.01);
+                p.acceleration = kepler(p.position);
+                p
+            })
+            .collect()
+    }
+
+    /// Take one update with a scheme in the field of a unit point mass.
+    ///
+    /// Returns:
+    ///
+    /// * `(Vec<Particle>, Vec<(f32, usize)>)` - the particles afterwards,
+    ///   and the time and number of particles of every force evaluation
+    fn advance(
+        timestep: Timestep,
+        buffers: &mut StepBuffers,
+    ) -> (Vec<Particle>, Vec<(f32, usize)>) {
+        let mut particles = orbits();
+        let evaluations = RefCell::new(Vec::new());
+        timestep.advance(
+            Integrator::Leapfrog,
+            &mut particles,
+            0.1,
+            0.0,
+            buffers,
+            |particles, since, out| {
+                evaluations.borrow_mut().push((since, particles.len()));
+                out.clear();
+                out.extend(particles.iter().map(|p| kepler(p.position)));
+            },
+            |particles, targets, since, out| {
+                evaluations.borrow_mut().push((since, targets.len()));
+                out.clear();
+                out.extend(targets.iter().map(|&i| kepler(particles[i].position)));
+            },
+        );
+        (particles, evaluations.into_inner())
+    }
+
+    #[test]
+    fn block_steps_end_together() {
+        let block = Timestep::Block {
+            eta: 0.05,
+            max_level: 12,
+        };
+        let mut buffers = StepBuffers::default();
+        for _ in 0..2 {
+            // The second time round reuses the buffers.
+            let (particles, evaluations) = advance(block, &mut buffers);
+
+            // The tight orbit took many steps while the wide ones didn't.
+            assert!(evaluations.len() > 2);
+            assert!(evaluations
+                .iter()
+                .any(|&(_, count)| count < particles.len()));
+
+            // But every particle finished at the end of the update, with its
+            // acceleration at its final position.
+            assert_eq!(evaluations.last(), Some(&(0.1, particles.len())));
+            for p in particles.iter() {
+                assert_eq!(p.acceleration, kepler(p.position));
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_steps_are_one_step_of_the_integrator() {
+        let (particles, evaluations) = advance(Timestep::Fixed, &mut StepBuffers::default());
+        assert_eq!(evaluations, vec![(0.1, particles.len())]);
+
+        let mut expected = orbits();
+        Integrator::Leapfrog.step(
+            &mut expected,
+            0.1,
+            &mut StepBuffers::default(),
+            |particles, _, out| {
+                out.clear();
+                out.extend(particles.iter().map(|p| kepler(p.position)));
+            },
+        );
+        for (p, q) in particles.iter().zip(expected.iter()) {
+            assert_eq!((p.position, p.velocity), (q.position, q.velocity));
+        }
+    }
+
+    #[test]
+    fn coarse_block_steps_are_a_fixed_leapfrog_step() {
+        // Steps far longer than the update put everyone on level 0.
+        let coarse = Timestep::Block {
+            eta: 1e6,
+            max_level: 12,
+        };
+        let (particles, evaluations) = advance(coarse, &mut StepBuffers::default());
+        let (fixed, _) = advance(Timestep::Fixed, &mut StepBuffers::default());
+        assert_eq!(evaluations, vec![(0.1, particles.len())]);
+        for (p, q) in particles.iter().zip(fixed.iter()) {
+            assert_eq!((p.position, p.velocity), (q.position, q.velocity));
+        }
+    }
+}
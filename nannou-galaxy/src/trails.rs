@@ -0,0 +1,195 @@
+/*
+Orbit trails for the galaxy simulation.
+
+Each particle leaves a trail of the positions it has passed through, so you can
+see where it has been and how it is moving. A trail is a fixed-size ring
+buffer: once it is full, each new point overwrites the oldest, so memory stays
+bounded however long the simulation runs.
+
+Trails are drawn as polylines that fade and narrow towards their tail. How
+many points a trail keeps, and how quickly it fades, are set by
+`TrailSettings` (and the `--trail-length` and `--trail-decay` command line
+options).
+
+Tracer particles (see `Particle::is_tracer`) leave no trail; there can be a
+great many of them and they would swamp the picture. When particles merge, the
+merged body carries on the trail of its heaviest parent, so a trail isn't cut
+short every time a planet sweeps up a bit of dust. A particle that wraps
+around a periodic boundary (see boundary.rs) starts a new trail, rather than
+drawing one straight back across the box.
+
+Trails are stored in simulation coordinates, so panning and zooming the view
+moves them along with the particles.
+*/
+
+use std::collections::HashMap;
+
+use nannou::prelude::*;
+
+use crate::collision::MergeEvent;
+use crate::store::{ParticleId, ParticleStore};
+use crate::viewport::Viewport;
+
+/// How long trails are and how they fade.
+#[derive(Clone, Copy, Debug)]
+pub struct TrailSettings {
+    /// How many past positions each trail keeps; 0 turns trails off.
+    pub length: usize,
+    /// How much of its opacity a trail keeps from one point to the next older
+    /// one, from 0 (only the newest segment shows) to 1 (no fading).
+    pub decay: f32,
+}
+
+impl Default for TrailSettings {
+    fn default() -> Self {
+        TrailSettings {
+            length: 64,
+            decay: 0.95,
+        }
+    }
+}
+
+/// A fixed-size ring buffer of positions.
+#[derive(Clone, Debug)]
+struct Ring {
+    points: Vec<Vector3>,
+    /// The index of the oldest point, once the buffer is full.
+    start: usize,
+}
+
+impl Ring {
+    fn new(capacity: usize) -> Self {
+        Ring {
+            points: Vec::with_capacity(capacity),
+            start: 0,
+        }
+    }
+
+    /// Add a point, overwriting the oldest if the buffer is full.
+    fn push(&mut self, point: Vector3, capacity: usize) {
+        if self.points.len() < capacity {
+            self.points.push(point);
+        } else {
+            self.points[self.start] = point;
+            self.start = (self.start + 1) % self.points.len();
+        }
+    }
+
+    /// The points from newest to oldest.
+    fn newest_first(&self) -> impl Iterator<Item = &Vector3> {
+        let (older, newer) = self.points.split_at(self.start);
+        older.iter().rev().chain(newer.iter().rev())
+    }
+}
+
+/// The trails of every particle in a system.
+#[derive(Clone, Debug, Default)]
+pub struct Trails {
+    pub settings: TrailSettings,
+    trails: HashMap<ParticleId, Ring>,
+}
+
+impl Trails {
+    /// Create an empty set of trails.
+    pub fn new(settings: TrailSettings) -> Self {
+        Trails {
+            settings,
+            trails: HashMap::new(),
+        }
+    }
+
+    /// Forget every trail, e.g. when a new scene is loaded.
+    pub fn clear(&mut self) {
+        self.trails.clear();
+    }
+
+    /// Hand each merge's heaviest parent's trail on to the merged body.
+    pub fn follow_merges(&mut self, merges: &[MergeEvent]) {
+        for merge in merges {
+            if let Some(trail) = self.trails.remove(&merge.heaviest) {
+                self.trails.insert(merge.id, trail);
+            }
+        }
+    }
+
+    /// Forget the trails of particles that jumped, e.g. by wrapping around a
+    /// periodic boundary, so they start again from where they are now.
+    pub fn cut(&mut self, ids: &[ParticleId]) {
+        for id in ids {
+            self.trails.remove(id);
+        }
+    }
+
+    /// Add every particle's current position to its trail, and drop the
+    /// trails of particles that are gone.
+    pub fn record(&mut self, particles: &ParticleStore) {
+        let length = self.settings.length;
+        if length == 0 {
+            self.trails.clear();
+            return;
+        }
+        self.trails.retain(|&id, _| particles.get(id).is_some());
+        for p in particles.iter().filter(|p| !p.is_tracer()) {
+            self.trails
+                .entry(p.id)
+                .or_insert_with(|| Ring::new(length))
+                .push(p.position, length);
+        }
+    }
+
+    /// Draw every trail, fading and narrowing from head to tail.
+    ///
+    /// Arguments:
+    ///
+    /// * `draw` - the draw context
+    /// * `viewport` - the mapping from the simulation to the window
+    /// * `particles` - the particles, for their positions and sizes
+    /// * `colors` - a color for each particle, in the order the store keeps
+    ///   them; particles without one use their own
+    /// * `positions` - where each particle is being drawn, in the same order,
+    ///   which is where its trail starts
+    pub fn draw(
+        &self,
+        draw: &Draw,
+        viewport: &Viewport,
+        particles: &ParticleStore,
+        colors: &[Rgb],
+        positions: &[Vector3],
+    ) {
+        let length = self.settings.length as f32;
+        for (i, p) in particles.iter().enumerate() {
+            let color = colors.get(i).cloned().unwrap_or(p.color);
+            let trail = match self.trails.get(&p.id) {
+                Some(trail) => trail,
+                None => continue,
+            };
+            let position = positions.get(i).cloned().unwrap_or(p.position);
+            let mut head = match viewport.project(position) {
+                Some(head) => head,
+                None => continue,
+            };
+            // A particle drawn short of where it is hasn't reached the
+            // trail's newest point yet.
+            let skip = if position == p.position { 0 } else { 1 };
+            let width = head.radius(p.radius).min(4.0);
+            let color = head.shade(color);
+            let mut alpha = 0.8;
+            for (age, point) in trail.newest_first().enumerate().skip(skip) {
+                // Stop where the trail goes behind the camera.
+                let tail = match viewport.project(*point) {
+                    Some(tail) => tail,
+                    None => break,
+                };
+                // Taper linearly to nothing at the end of a full trail.
+                let taper = 1.0 - age as f32 / length;
+                draw.line()
+                    .start(head.position)
+                    .end(tail.position)
+                    .weight(width * taper)
+                    .color(rgba(color.red, color.green, color.blue, alpha));
+                alpha *= self.settings.decay;
+                head = tail;
+            }
+        }
+    }
+}
@@ -0,0 +1,305 @@
+/*
+Physical units for the galaxy simulation.
+
+The simulation stores positions, velocities, masses and times as plain f32s.
+A `UnitSystem` says what those numbers mean: e.g. positions in AU, masses in
+solar masses and times in years. From that we derive the gravitational
+constant in the same units,
+
+    G = G_SI * (kg per mass unit) * (s per time unit)^2 / (m per length unit)^3
+
+so that the physics comes out right without ever handling huge SI numbers in
+f32. In AU, solar masses and years, G = 4 * pi^2, and a body at 1 AU from a
+one-solar-mass star orbits at 2 * pi AU per year: one orbit per year.
+
+Conversions are done in f64 and only the final factors are cast to f32.
+
+A unit system can be written as "length,mass,time", e.g. "au,msun,yr" or
+"pc,msun,myr", and parsed from a string.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// The gravitational constant in SI units (m^3 kg^-1 s^-2).
+pub const G_SI: f64 = 6.67430e-11;
+
+/// A unit of length.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LengthUnit {
+    Meter,
+    /// The astronomical unit, the mean Earth-Sun distance.
+    Au,
+    Parsec,
+    Kiloparsec,
+    /// A nominal screen pixel of 1/100 AU, so sketches laid out in pixels
+    /// still get physically sensible gravity.
+    Pixel,
+}
+
+impl LengthUnit {
+    /// How many meters are in one of this unit.
+    pub fn meters(&self) -> f64 {
+        match self {
+            LengthUnit::Meter => 1.0,
+            LengthUnit::Au => 1.495978707e11,
+            LengthUnit::Parsec => 3.085_677_581e16,
+            LengthUnit::Kiloparsec => 3.085_677_581e19,
+            LengthUnit::Pixel => 1.495978707e9,
+        }
+    }
+
+    /// The short name of the unit, for labels.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            LengthUnit::Meter => "m",
+            LengthUnit::Au => "AU",
+            LengthUnit::Parsec => "pc",
+            LengthUnit::Kiloparsec => "kpc",
+            LengthUnit::Pixel => "px",
+        }
+    }
+}
+
+/// A unit of mass.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MassUnit {
+    Kilogram,
+    EarthMass,
+    SolarMass,
+}
+
+impl MassUnit {
+    /// How many kilograms are in one of this unit.
+    pub fn kilograms(&self) -> f64 {
+        match self {
+            MassUnit::Kilogram => 1.0,
+            MassUnit::EarthMass => 5.9722e24,
+            MassUnit::SolarMass => 1.98892e30,
+        }
+    }
+
+    /// The short name of the unit, for labels.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            MassUnit::Kilogram => "kg",
+            MassUnit::EarthMass => "M_earth",
+            MassUnit::SolarMass => "M_sun",
+        }
+    }
+}
+
+/// A unit of time.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TimeUnit {
+    Second,
+    Day,
+    /// The Julian year of 365.25 days.
+    Year,
+    Megayear,
+}
+
+impl TimeUnit {
+    /// How many seconds are in one of this unit.
+    pub fn seconds(&self) -> f64 {
+        match self {
+            TimeUnit::Second => 1.0,
+            TimeUnit::Day => 86400.0,
+            TimeUnit::Year => 3.15576e7,
+            TimeUnit::Megayear => 3.15576e13,
+        }
+    }
+
+    /// The short name of the unit, for labels.
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            TimeUnit::Second => "s",
+            TimeUnit::Day => "d",
+            TimeUnit::Year => "yr",
+            TimeUnit::Megayear => "Myr",
+        }
+    }
+}
+
+/// The units that a `ParticleSystem`'s numbers are measured in.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UnitSystem {
+    pub length: LengthUnit,
+    pub mass: MassUnit,
+    pub time: TimeUnit,
+}
+
+impl Default for UnitSystem {
+    fn default() -> Self {
+        UnitSystem::SOLAR
+    }
+}
+
+impl UnitSystem {
+    /// AU, solar masses and years: planetary systems.
+    pub const SOLAR: UnitSystem = UnitSystem {
+        length: LengthUnit::Au,
+        mass: MassUnit::SolarMass,
+        time: TimeUnit::Year,
+    };
+
+    /// Parsecs, solar masses and megayears: star clusters and galaxies.
+    pub const GALACTIC: UnitSystem = UnitSystem {
+        length: LengthUnit::Parsec,
+        mass: MassUnit::SolarMass,
+        time: TimeUnit::Megayear,
+    };
+
+    /// The gravitational constant expressed in these units.
+    pub fn gravitational_constant(&self) -> f32 {
+        let length = self.length.meters();
+        let time = self.time.seconds();
+        (G_SI * self.mass.kilograms() * time * time / (length * length * length)) as f32
+    }
+
+    /// Convert a length in meters to these units.
+    pub fn length_from_meters(&self, meters: f64) -> f32 {
+        (meters / self.length.meters()) as f32
+    }
+
+    /// Convert a mass in kilograms to these units.
+    pub fn mass_from_kilograms(&self, kilograms: f64) -> f32 {
+        (kilograms / self.mass.kilograms()) as f32
+    }
+
+    /// Convert a time in seconds to these units.
+    pub fn time_from_seconds(&self, seconds: f64) -> f32 {
+        (seconds / self.time.seconds()) as f32
+    }
+
+    /// The factors that convert lengths, masses and times measured in these
+    /// units into `other` units.
+    ///
+    /// Returns:
+    ///
+    /// * `(f32, f32, f32)` - the length, mass and time factors
+    pub fn factors_to(&self, other: &UnitSystem) -> (f32, f32, f32) {
+        (
+            (self.length.meters() / other.length.meters()) as f32,
+            (self.mass.kilograms() / other.mass.kilograms()) as f32,
+            (self.time.seconds() / other.time.seconds()) as f32,
+        )
+    }
+}
+
+impl fmt::Display for UnitSystem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {}, {}",
+            self.length.symbol(),
+            self.mass.symbol(),
+            self.time.symbol()
+        )
+    }
+}
+
+impl FromStr for UnitSystem {
+    type Err = String;
+
+    /// Parse a unit system written as "length,mass,time", e.g. "au,msun,yr".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<String> = s.split(',').map(|p| p.trim().to_lowercase()).collect();
+        if parts.len() != 3 {
+            return Err(format!("expected length,mass,time but got {:?}", s));
+        }
+        let length = match parts[0].as_str() {
+            "m" => LengthUnit::Meter,
+            "au" => LengthUnit::Au,
+            "pc" => LengthUnit::Parsec,
+            "kpc" => LengthUnit::Kiloparsec,
+            "px" => LengthUnit::Pixel,
+            other => return Err(format!("unknown length unit {:?}", other)),
+        };
+        let mass = match parts[1].as_str() {
+            "kg" => MassUnit::Kilogram,
+            "mearth" => MassUnit::EarthMass,
+            "msun" => MassUnit::SolarMass,
+            other => return Err(format!("unknown mass unit {:?}", other)),
+        };
+        let time = match parts[2].as_str() {
+            "s" => TimeUnit::Second,
+            "d" => TimeUnit::Day,
+            "yr" => TimeUnit::Year,
+            "myr" => TimeUnit::Megayear,
+            other => return Err(format!("unknown time unit {:?}", other)),
+        };
+        Ok(UnitSystem { length, mass, time })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nannou::prelude::*;
+
+    use super::*;
+    use crate::integrator::{Integrator, StepBuffers};
+    use crate::Particle;
+
+    #[test]
+    fn g_is_four_pi_squared_in_solar_units() {
+        // Not exactly: the year here is the Julian year rather than the
+        // sidereal one, and G_SI and the solar mass are only known to a few
+        // parts in 10^4 apart.
+        let g = UnitSystem::SOLAR.gravitational_constant();
+        assert!((g / (4.0 * PI * PI) - 1.0).abs() < 1e-3, "G = {}", g);
+    }
+
+    #[test]
+    fn the_earth_goes_round_the_sun_in_a_year() {
+        let units = UnitSystem::SOLAR;
+        let g = units.gravitational_constant();
+        let sun = 1.0;
+        let earth = units.mass_from_kilograms(MassUnit::EarthMass.kilograms());
+
+        // Both bodies on circular orbits about their center of mass, which
+        // stays still at the origin.
+        let speed = (g * (sun + earth)).sqrt();
+        let mut particles = vec![
+            Particle::new(
+                vec3(-earth / (sun + earth), 0.0, 0.0),
+                vec3(0.0, -speed * earth / (sun + earth), 0.0),
+                sun,
+                0.005,
+            ),
+            Particle::new(
+                vec3(sun / (sun + earth), 0.0, 0.0),
+                vec3(0.0, speed * sun / (sun + earth), 0.0),
+                earth,
+                4e-5,
+            ),
+        ];
+        let accelerations = |particles: &[Particle], _: f32, out: &mut Vec<Vector3>| {
+            let r = particles[1].position - particles[0].position;
+            let pull = r * (g / r.magnitude().powi(3));
+            out.clear();
+            out.extend([pull * particles[1].mass, -pull * particles[0].mass]);
+        };
+        let mut buffers = StepBuffers::default();
+        buffers.refresh(&mut particles, accelerations);
+        let start = particles[1].position;
+
+        let steps = 1000;
+        let dt = units.time_from_seconds(TimeUnit::Year.seconds()) / steps as f32;
+        for step in 1..=steps {
+            Integrator::Leapfrog.step(&mut particles, dt, &mut buffers, accelerations);
+            if step == steps / 2 {
+                // Half a year on, it's on the far side of the Sun.
+                let across = (particles[1].position - start).magnitude();
+                assert!((across - 2.0).abs() < 1e-2, "{} AU across", across);
+            }
+        }
+
+        // Back where it started, give or take the few parts in 10^4 that G
+        // is off 4 pi^2 by and the leapfrog's phase error.
+        let miss = (particles[1].position - start).magnitude();
+        assert!(miss < 1e-2, "missed by {} AU", miss);
+    }
+}
@@ -0,0 +1,196 @@
+/*
+The mapping from simulation space to the window.
+
+The simulation runs in physical units (see units.rs), which have nothing to do
+with pixels: a planetary system is a few AU across, a galaxy tens of
+kiloparsecs. A `Viewport` places a point of the simulation at the middle of the
+window and scales it by a number of pixels per length unit.
+
+The simulation is 3D, so the viewport also has an orientation: a yaw about the
+simulation's z axis, then a pitch that tilts the view away from looking
+straight down it. With both at zero the x-y plane is seen face on, x to the
+right and y up, which is all the flat scenes need.
+
+The projection is either orthographic, where every depth is drawn at the same
+scale, or perspective. In perspective, the camera sits `FOCAL_LENGTH /
+pixels_per_unit` length units in front of the point in the middle of the
+window, so that point is still drawn at `pixels_per_unit`, nearer things
+bigger and farther things smaller, and zooming moves the camera in and out.
+Points behind the camera aren't drawn at all.
+*/
+
+use nannou::prelude::*;
+
+/// Bodies are never drawn smaller than this many pixels across, so that
+/// physically tiny bodies (like planets at solar-system scale) stay visible.
+const MIN_SCREEN_RADIUS: f32 = 1.5;
+
+/// The distance from the eye to the picture in perspective, in pixels. Longer
+/// is a narrower field of view and a flatter picture.
+const FOCAL_LENGTH: f32 = 1200.0;
+
+/// Points closer to the eye than this fraction of the distance to the middle
+/// of the window are treated as behind the camera.
+const NEAR_PLANE: f32 = 0.02;
+
+/// Far-off bodies are dimmed in perspective, but never below this brightness.
+const MIN_BRIGHTNESS: f32 = 0.2;
+
+/// A transform between simulation coordinates and window coordinates.
+#[derive(Clone, Copy, Debug)]
+pub struct Viewport {
+    /// The point of the simulation shown at the center of the window.
+    pub center: Vector3,
+    /// How many pixels one simulation length unit spans, at the depth of
+    /// `center`.
+    pub pixels_per_unit: f32,
+    /// How far the view is turned about the z axis, in radians.
+    pub yaw: f32,
+    /// How far the view is tilted from looking down the z axis, in radians,
+    /// from 0 (face on) through PI / 2 (edge on) to PI (from below).
+    pub pitch: f32,
+    /// Whether to draw in perspective, rather than orthographically.
+    pub perspective: bool,
+}
+
+/// Where a point of the simulation lands in the window.
+#[derive(Clone, Copy, Debug)]
+pub struct Projected {
+    /// The point in window coordinates.
+    pub position: Vector2,
+    /// How many pixels a length unit spans at the point's depth.
+    pub pixels_per_unit: f32,
+    /// How far the point is in front of the camera; larger is farther away.
+    pub depth: f32,
+    /// How brightly to draw things at this depth, from `MIN_BRIGHTNESS` to 1.
+    pub brightness: f32,
+}
+
+impl Projected {
+    /// Convert a radius in simulation units at this point to a drawn radius
+    /// in pixels.
+    pub fn radius(&self, radius: f32) -> f32 {
+        (radius * self.pixels_per_unit).max(MIN_SCREEN_RADIUS)
+    }
+
+    /// Dim a color by the brightness at this depth.
+    pub fn shade(&self, color: Rgb) -> Rgb {
+        rgb(
+            color.red * self.brightness,
+            color.green * self.brightness,
+            color.blue * self.brightness,
+        )
+    }
+}
+
+impl Viewport {
+    /// Create a viewport centered on the origin, looking face on at the x-y
+    /// plane, orthographically.
+    ///
+    /// Arguments:
+    ///
+    /// * `pixels_per_unit` - how many pixels one simulation length unit spans
+    pub fn new(pixels_per_unit: f32) -> Self {
+        Viewport {
+            center: vec3(0.0, 0.0, 0.0),
+            pixels_per_unit,
+            yaw: 0.0,
+            pitch: 0.0,
+            perspective: false,
+        }
+    }
+
+    /// Turn the view, keeping the pitch between face on from above and from
+    /// below.
+    ///
+    /// Arguments:
+    ///
+    /// * `yaw` - how far to turn about the z axis, in radians
+    /// * `pitch` - how far to tilt, in radians
+    pub fn orbit(&mut self, yaw: f32, pitch: f32) {
+        self.yaw = (self.yaw + yaw) % TAU;
+        self.pitch = (self.pitch + pitch).clamp(0.0, PI);
+    }
+
+    /// Rotate an offset from simulation axes to the camera's: x to the right,
+    /// y up, and z out of the window towards the viewer.
+    fn rotate(self, v: Vector3) -> Vector3 {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        let x = v.x * cos_yaw + v.y * sin_yaw;
+        let y = -v.x * sin_yaw + v.y * cos_yaw;
+        vec3(
+            x,
+            y * cos_pitch + v.z * sin_pitch,
+            -y * sin_pitch + v.z * cos_pitch,
+        )
+    }
+
+    /// Rotate an offset from the camera's axes back to the simulation's.
+    fn unrotate(self, v: Vector3) -> Vector3 {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        let y = v.y * cos_pitch - v.z * sin_pitch;
+        let z = v.y * sin_pitch + v.z * cos_pitch;
+        vec3(v.x * cos_yaw - y * sin_yaw, v.x * sin_yaw + y * cos_yaw, z)
+    }
+
+    /// Where a point of the simulation is relative to `center`, along the
+    /// camera's axes (see `rotate`).
+    pub fn to_camera(self, world: Vector3) -> Vector3 {
+        self.rotate(world - self.center)
+    }
+
+    /// Project a point of the simulation into the window.
+    ///
+    /// Returns:
+    ///
+    /// * `Option<Projected>` - where it lands, or None if it's behind the
+    ///   camera
+    pub fn project(self, world: Vector3) -> Option<Projected> {
+        let camera = self.to_camera(world);
+        if !self.perspective {
+            return Some(Projected {
+                position: vec2(camera.x, camera.y) * self.pixels_per_unit,
+                pixels_per_unit: self.pixels_per_unit,
+                depth: -camera.z,
+                brightness: 1.0,
+            });
+        }
+        let distance = FOCAL_LENGTH / self.pixels_per_unit;
+        let depth = distance - camera.z;
+        if depth < NEAR_PLANE * distance {
+            return None;
+        }
+        let scale = FOCAL_LENGTH / depth;
+        Some(Projected {
+            position: vec2(camera.x, camera.y) * scale,
+            pixels_per_unit: scale,
+            depth,
+            brightness: (scale / self.pixels_per_unit).clamp(MIN_BRIGHTNESS, 1.0),
+        })
+    }
+
+    /// Convert a point in the simulation to a point in the window, if it's in
+    /// front of the camera.
+    pub fn to_screen(self, world: Vector3) -> Option<Vector2> {
+        self.project(world).map(|p| p.position)
+    }
+
+    /// Convert a point in the window to the point of the simulation under it
+    /// at the depth of `center`.
+    pub fn to_world(self, screen: Vector2) -> Vector3 {
+        let offset = screen / self.pixels_per_unit;
+        self.center + self.unrotate(vec3(offset.x, offset.y, 0.0))
+    }
+
+    /// Move the view across the window, keeping its depth.
+    ///
+    /// Arguments:
+    ///
+    /// * `screen` - how far to move the picture, in pixels
+    pub fn pan(&mut self, screen: Vector2) {
+        let offset = screen / self.pixels_per_unit;
+        self.center -= self.unrotate(vec3(offset.x, offset.y, 0.0));
+    }
+}